@@ -1,9 +1,37 @@
 "use strict";
 const fs = require("fs");
 
+// read_intは標準入力を空白区切りで読む。入力が尽きたか整数でなければ0
+let inputTokens;
+function makeReadInt() {
+  let pos = 0;
+  return () => {
+    if (inputTokens === undefined) {
+      inputTokens = fs
+        .readFileSync(0, { encoding: "utf8" })
+        .split(/\s+/)
+        .filter((token) => token !== "");
+    }
+    if (pos >= inputTokens.length) {
+      return 0;
+    }
+    const token = inputTokens[pos++];
+    const x = Number(token);
+    return /^[+-]?\d+$/.test(token) && x >= -(2 ** 31) && x < 2 ** 31 ? x : 0;
+  };
+}
+
+class Trap extends Error {}
+
+// interpreter_global_typeの戻り値
+const GLOBAL_TYPES = ["i32", "i64", "f64"];
+
 class Runner {
   constructor() {
     this.dumpWasm = process.argv.includes("--dump-wasm");
+    // --fuel Nで実行できる量を制限する
+    const fuelIdx = process.argv.indexOf("--fuel");
+    this.fuel = fuelIdx !== -1 ? BigInt(process.argv[fuelIdx + 1]) : null;
 
     const wasmPath = "target/wasm32-unknown-unknown/debug/wjit.wasm";
     const wasmBin = fs.readFileSync(wasmPath);
@@ -13,6 +41,42 @@ class Runner {
         println: (x) => {
           console.log(x);
         },
+        println_i64: (x) => {
+          console.log(x.toString());
+        },
+        println_f64: (x) => {
+          console.log(x);
+        },
+        print_str: (ptr, len) => {
+          process.stdout.write(Buffer.from(this.ptrToBuffer(ptr, len)));
+        },
+        println_str: (ptr, len) => {
+          console.log(Buffer.from(this.ptrToBuffer(ptr, len)).toString());
+        },
+        exit: (code) => {
+          process.exit(code);
+        },
+        read_int: makeReadInt(),
+        load_file: (pathPtr, pathLen, lenPtr) => {
+          const path = Buffer.from(
+            this.ptrToBuffer(pathPtr, pathLen)
+          ).toString();
+          let buf;
+          try {
+            buf = fs.readFileSync(path);
+          } catch (e) {
+            return 0;
+          }
+          const ptr = this.wasmInstance.exports.alloc(buf.length);
+          const memoryView = new DataView(
+            this.wasmInstance.exports.memory.buffer
+          );
+          for (let i = 0; i < buf.length; i++) {
+            memoryView.setUint8(ptr + i, buf[i]);
+          }
+          memoryView.setInt32(lenPtr, buf.length, true);
+          return ptr;
+        },
       },
     });
   }
@@ -29,17 +93,45 @@ class Runner {
     return ptr;
   }
 
+  // 関数モジュールの文字列は先頭8バイトに長さを持つ
+  skeletonString(skeletonInstance, ptr) {
+    const buf = skeletonInstance.exports._memory.buffer;
+    const len = new DataView(buf).getInt32(ptr, true);
+    return Buffer.from(buf, ptr + 8, len).toString();
+  }
+
   ptrToBuffer(ptr, len) {
     const buf = this.wasmInstance.exports.memory.buffer;
     return buf.slice(ptr, ptr + len);
   }
 
-  makeIrModule(code) {
-    return this.wasmInstance.exports.make_ir_module(this.stringToPtr(code));
+  lastError() {
+    const lenPtr = this.wasmInstance.exports.alloc(4);
+    const ptr = this.wasmInstance.exports.last_error(lenPtr);
+    const memoryView = new DataView(this.wasmInstance.exports.memory.buffer);
+    const len = memoryView.getInt32(lenPtr, true);
+    return Buffer.from(this.ptrToBuffer(ptr, len)).toString();
+  }
+
+  makeIrModule(path) {
+    const irModule = this.wasmInstance.exports.make_ir_module(
+      this.stringToPtr(path)
+    );
+    if (irModule === 0) {
+      throw new Error(this.lastError());
+    }
+    return irModule;
   }
 
   makeInterpreter(IrModule) {
-    return this.wasmInstance.exports.make_interpreter(IrModule);
+    const interpreter = this.wasmInstance.exports.make_interpreter(IrModule);
+    if (interpreter === 0) {
+      throw new Error(this.lastError());
+    }
+    if (this.fuel !== null) {
+      this.wasmInstance.exports.interpreter_set_fuel(interpreter, this.fuel);
+    }
+    return interpreter;
   }
 
   interpreterCall(interpreter, funcIdx, args) {
@@ -49,12 +141,57 @@ class Runner {
     for (let i = 0; i < args.length; i++) {
       memoryView.setInt32(argsPtr + i * 4, args[i], true);
     }
-    return this.wasmInstance.exports.interpreter_call_func(
+    const trappedPtr = this.wasmInstance.exports.alloc(4);
+    const result = this.wasmInstance.exports.interpreter_call_func(
       interpreter,
       funcIdx,
       args.length,
-      argsPtr
+      argsPtr,
+      trappedPtr
+    );
+    const trapped = new DataView(
+      this.wasmInstance.exports.memory.buffer
+    ).getInt32(trappedPtr, true);
+    if (trapped !== 0) {
+      throw new Trap(this.lastError());
+    }
+    return result;
+  }
+
+  // スタックトレースにある関数モジュールのフレームをソース上の位置に戻す
+  jitTrapMessage(compiler, skeletonInstance, error) {
+    // コンパイルしたコードは_trapに理由を書き込んでからunreachableでtrapする
+    const code =
+      error instanceof RangeError
+        ? this.wasmInstance.exports.stack_overflow_trap_code()
+        : skeletonInstance.exports._trap.value;
+    const message =
+      this.wasmInstance.exports.trap_message(code) !== 0
+        ? this.lastError()
+        : error.message;
+    const frames = [];
+    for (const line of error.stack.split("\n")) {
+      const match = line.match(
+        /wasm:\/\/wasm\/wjit_func_(\d+)-[0-9a-f]+:wasm-function\[\d+\]:0x([0-9a-f]+)/
+      );
+      if (match) {
+        frames.push(Number(match[1]), parseInt(match[2], 16));
+      }
+    }
+    if (frames.length === 0) {
+      return message;
+    }
+    const framesPtr = this.wasmInstance.exports.alloc(frames.length * 4);
+    const memoryView = new DataView(this.wasmInstance.exports.memory.buffer);
+    for (let i = 0; i < frames.length; i++) {
+      memoryView.setUint32(framesPtr + i * 4, frames[i], true);
+    }
+    this.wasmInstance.exports.compiler_backtrace(
+      compiler,
+      frames.length / 2,
+      framesPtr
     );
+    return `${message}\n${this.lastError()}`;
   }
 
   makeCompiler(irModule) {
@@ -77,6 +214,7 @@ class Runner {
   }
 
   makeSkeltonInstance(compiler, skeletonModule) {
+    const readInt = makeReadInt();
     const skeletonInstance = new WebAssembly.Instance(skeletonModule, {
       env: {
         compile_func: (idx) => {
@@ -96,30 +234,122 @@ class Runner {
             fs.writeFileSync(`dump_wasm/${idx}.wasm`, Buffer.from(funcBin));
           }
           const funcModule = new WebAssembly.Module(funcBin);
+          const globals = Object.fromEntries(
+            Object.entries(skeletonInstance.exports).filter(([name]) =>
+              name.startsWith("_global_")
+            )
+          );
           new WebAssembly.Instance(funcModule, {
             env: {
+              ...globals,
               _table: skeletonInstance.exports._table,
+              _memory: skeletonInstance.exports._memory,
+              _alloc: skeletonInstance.exports._alloc,
+              _fuel: skeletonInstance.exports._fuel,
+              _trap: skeletonInstance.exports._trap,
               println: (x) => {
                 console.log(x);
                 return 0;
               },
+              println_i64: (x) => {
+                console.log(x.toString());
+                return 0;
+              },
+              println_f64: (x) => {
+                console.log(x);
+                return 0;
+              },
+              print_str: (ptr) => {
+                process.stdout.write(
+                  this.skeletonString(skeletonInstance, ptr)
+                );
+                return 0;
+              },
+              println_str: (ptr) => {
+                console.log(this.skeletonString(skeletonInstance, ptr));
+                return 0;
+              },
+              exit: (code) => {
+                process.exit(code);
+              },
+              read_int: readInt,
             },
           });
           return 0;
         },
       },
     });
+    if (this.fuel !== null) {
+      skeletonInstance.exports._fuel.value = this.fuel;
+    }
     return skeletonInstance;
   }
+
+  // スケルトンモジュールはグローバル変数をIRと同じ順番でexportしている
+  skeletonGlobals(skeletonInstance) {
+    return Object.entries(skeletonInstance.exports)
+      .filter(([name]) => name.startsWith("_global_"))
+      .map(([, global]) => global);
+  }
+
+  // JIT側で書き換えたグローバル変数をインタプリタに反映する
+  syncGlobalsToInterpreter(interpreter, skeletonInstance) {
+    const exports = this.wasmInstance.exports;
+    this.skeletonGlobals(skeletonInstance).forEach((global, idx) => {
+      const ty =
+        GLOBAL_TYPES[exports.interpreter_global_type(interpreter, idx)];
+      exports[`interpreter_set_global_${ty}`](interpreter, idx, global.value);
+    });
+  }
+
+  syncGlobalsToJit(interpreter, skeletonInstance) {
+    const exports = this.wasmInstance.exports;
+    this.skeletonGlobals(skeletonInstance).forEach((global, idx) => {
+      const ty =
+        GLOBAL_TYPES[exports.interpreter_global_type(interpreter, idx)];
+      global.value = exports[`interpreter_get_global_${ty}`](interpreter, idx);
+    });
+  }
 }
 
-const code = fs.readFileSync(process.argv[2], { encoding: "utf8" });
+Error.stackTraceLimit = 100;
+
 const runner = new Runner();
-const irModule = runner.makeIrModule(code);
+let irModule;
+try {
+  irModule = runner.makeIrModule(process.argv[2]);
+} catch (e) {
+  console.error(e.message);
+  process.exit(1);
+}
 const compiler = runner.makeCompiler(irModule);
 const skeletonModule = runner.makeSkeletonModule(compiler);
 const skeletonInstance = runner.makeSkeltonInstance(compiler, skeletonModule);
 
-skeletonInstance.exports.main();
+// trapしたらメッセージを表示して終了する
+function runOrTrap(f) {
+  try {
+    f();
+  } catch (e) {
+    if (!(e instanceof Trap)) {
+      throw e;
+    }
+    console.error(`trap: ${e.message}`);
+    process.exit(1);
+  }
+}
+
+runOrTrap(() => {
+  try {
+    skeletonInstance.exports.main();
+  } catch (e) {
+    if (!(e instanceof WebAssembly.RuntimeError || e instanceof RangeError)) {
+      throw e;
+    }
+    throw new Trap(runner.jitTrapMessage(compiler, skeletonInstance, e));
+  }
+});
 const interpreter = runner.makeInterpreter(irModule);
-runner.interpreterCall(interpreter, 0, []);
+runner.syncGlobalsToInterpreter(interpreter, skeletonInstance);
+runOrTrap(() => runner.interpreterCall(interpreter, 0, []));
+runner.syncGlobalsToJit(interpreter, skeletonInstance);
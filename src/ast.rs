@@ -1,27 +1,98 @@
+use crate::span::Span;
+use crate::types::{Ty, Type};
+
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
 pub struct Module {
+    pub imports: Vec<Import>,
+    pub structs: Vec<Struct>,
+    pub globals: Vec<Global>,
     pub funcs: Vec<Func>,
 }
 
+// `import "path" as name;`
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+pub struct Import {
+    pub path: String,
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+pub struct Struct {
+    pub name: String,
+    pub fields: Vec<(String, Ty)>,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+pub struct Global {
+    pub name: String,
+    pub ty: Option<Ty>,
+    pub init: Expr,
+    pub span: Span,
+}
+
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
 pub struct Func {
     pub name: String,
-    pub args: Vec<String>,
+    pub args: Vec<(String, Ty)>,
+    pub ret: Ty,
     pub body: Expr,
+    pub doc: Option<String>,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+    // 型検査で埋める
+    pub ty: Option<Ty>,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr {
+            kind,
+            span,
+            ty: None,
+        }
+    }
+
+    pub fn ty(&self) -> &Ty {
+        self.ty.as_ref().unwrap()
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
-pub enum Expr {
-    IntLiteral(i32),
+pub enum ExprKind {
+    // 符号は`PrefixOp::Minus`として別に持つ
+    IntLiteral(u64, Option<Type>),
+    // f64のビット列
+    FloatLiteral(u64),
+    StringLiteral(String),
     Ident(String),
     BinaryOp(Box<Expr>, BinaryOp, Box<Expr>),
     PrefixOp(PrefixOp, Box<Expr>),
-    Assign(String, Box<Expr>),
-    Call(String, Vec<Expr>),
+    Cast(Box<Expr>, Type),
+    // 左辺は変数か配列の要素か構造体のフィールド
+    Assign(Box<Expr>, Box<Expr>),
+    // 関数名でなければ関数の値を呼び出す
+    Call(Box<Expr>, Vec<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    StructLiteral(String, Vec<(String, Expr)>),
+    Field(Box<Expr>, String),
+    // 引数、戻り値の型、本体
+    Lambda(Vec<(String, Ty)>, Ty, Box<Expr>),
     While(Box<Expr>, Box<Expr>),
-    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    If(Box<Expr>, Box<Expr>, Option<Box<Expr>>),
+    // パターンは整数リテラルで、最後に`_`の腕を持つ
+    Match(Box<Expr>, Vec<(Expr, Expr)>, Box<Expr>),
     Block(Vec<Expr>),
-    Var(String, Box<Expr>, Box<Expr>),
+    Var(String, Option<Ty>, Box<Expr>, Box<Expr>),
+    Return(Box<Expr>),
+    Break,
+    Continue,
 }
 
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
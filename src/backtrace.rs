@@ -0,0 +1,55 @@
+use std::fmt;
+
+use crate::ir::Module;
+
+#[derive(Debug, PartialEq, Clone, Eq)]
+pub struct Frame {
+    pub func: String,
+    // ソースのパスと行。コンパイラが生成した命令では分からない
+    pub location: Option<(String, usize)>,
+}
+
+impl Frame {
+    pub fn new(module: &Module, func: usize, instr: Option<usize>) -> Self {
+        let func = &module.funcs[func];
+        let location = instr
+            .and_then(|instr| func.span_at(instr))
+            .and_then(|span| {
+                module
+                    .files
+                    .get(span.file)
+                    .map(|path| (path.clone(), span.line))
+            });
+        Frame {
+            func: func.name.clone(),
+            location,
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some((path, line)) => write!(f, "at {} ({}:{})", self.func, path, line),
+            None => write!(f, "at {}", self.func),
+        }
+    }
+}
+
+// 内側の呼び出しほど前
+#[derive(Debug, PartialEq, Clone, Eq, Default)]
+pub struct Backtrace {
+    pub frames: Vec<Frame>,
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, frame) in self.frames.iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "    {}", frame)?;
+        }
+        Ok(())
+    }
+}
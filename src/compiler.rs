@@ -1,60 +1,218 @@
+use crate::backtrace::Frame;
+use crate::interpreter::Trap;
 use crate::ir;
+use crate::ir::Signature;
+use crate::types;
 use crate::wasm_generator;
 use parity_wasm::elements::{
-    CodeSection, ElementSection, ElementSegment, ExportEntry, ExportSection, External, Func,
-    FuncBody, FunctionSection, FunctionType, ImportEntry, ImportSection, InitExpr, Instruction,
-    Instructions, Internal, Local, Module, Section, TableSection, TableType, Type, TypeSection,
-    ValueType,
+    BlockType, CodeSection, DataSection, DataSegment, ElementSection, ElementSegment, ExportEntry,
+    ExportSection, External, Func, FuncBody, FunctionNameSubsection, FunctionSection, FunctionType,
+    GlobalEntry, GlobalSection, GlobalType, ImportEntry, ImportSection, InitExpr, Instruction,
+    Instructions, Internal, Local, MemorySection, MemoryType, Module, ModuleNameSubsection,
+    NameSection, Section, Serialize, TableSection, TableType, Type, TypeSection, ValueType,
 };
 
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+struct Signatures {
+    // index = type_id。スケルトンと関数モジュールで同じものを使う
+    signatures: Vec<Signature>,
+    // func -> type_id
+    func_types: Vec<u32>,
+}
+
+impl Signatures {
+    fn new(module: &ir::Module) -> Self {
+        let mut signatures = Signatures {
+            signatures: Vec::new(),
+            func_types: Vec::new(),
+        };
+        for func in &module.funcs {
+            let type_id = signatures.intern(func.signature());
+            signatures.func_types.push(type_id);
+        }
+        for func in &module.funcs {
+            for instr in &func.instrs {
+                if let ir::Instr::CallIndirect(signature) = instr {
+                    signatures.intern(signature.clone());
+                }
+            }
+        }
+        for host_func in &module.host_funcs {
+            signatures.intern(host_func.signature());
+        }
+        signatures.intern(Self::i32_signature());
+        signatures
+    }
+
+    // compile_funcと_allocの型
+    fn i32_signature() -> Signature {
+        Signature {
+            params: vec![types::Type::I32],
+            ret: types::Type::I32,
+        }
+    }
+
+    fn intern(&mut self, signature: Signature) -> u32 {
+        match self.signatures.iter().position(|x| *x == signature) {
+            Some(idx) => idx as u32,
+            None => {
+                self.signatures.push(signature);
+                (self.signatures.len() - 1) as u32
+            }
+        }
+    }
+
+    fn get(&self, signature: &Signature) -> u32 {
+        self.signatures.iter().position(|x| x == signature).unwrap() as u32
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
 
 pub struct Compiler<'a> {
     module: &'a ir::Module,
-    // typeはめんどくさいのでパラメータが0〜5のものをそれぞれindex 0〜5で
+    signatures: Signatures,
 }
 
 impl<'a> Compiler<'a> {
     pub fn new(module: &'a ir::Module) -> Self {
-        Compiler { module }
+        Compiler {
+            module,
+            signatures: Signatures::new(module),
+        }
+    }
+
+    // スケルトンではcompile_funcと各関数の後ろ
+    fn skeleton_alloc_idx(&self) -> u32 {
+        self.module.funcs.len() as u32 + 1
+    }
+
+    fn table_size(&self) -> u32 {
+        ir::table_idx(self.module.funcs.len())
+    }
+
+    // スケルトンではユーザー定義のグローバル変数の後ろ
+    fn skeleton_heap_idx(&self) -> u32 {
+        self.module.globals.len() as u32
+    }
+
+    // スケルトンでは_heapの後ろ
+    fn skeleton_fuel_idx(&self) -> u32 {
+        self.skeleton_heap_idx() + 1
+    }
+
+    // スケルトンでは_fuelの後ろ
+    fn skeleton_trap_idx(&self) -> u32 {
+        self.skeleton_fuel_idx() + 1
     }
 
-    fn type_section() -> TypeSection {
+    // 関数モジュールではユーザー定義のグローバル変数の後ろ
+    fn func_module_fuel_idx(&self) -> u32 {
+        self.module.globals.len() as u32
+    }
+
+    fn func_module_trap_idx(&self) -> u32 {
+        self.func_module_fuel_idx() + 1
+    }
+
+    fn global_export_name(global: &ir::Global) -> String {
+        format!("_global_{}", global.name)
+    }
+
+    fn i32_type_idx(&self) -> u32 {
+        self.signatures.get(&Signatures::i32_signature())
+    }
+
+    // 関数モジュールはホスト関数、_allocの順にimportする
+    fn func_module_alloc_idx(&self) -> u32 {
+        self.module.host_funcs.len() as u32
+    }
+
+    fn func_module_imported_funcs_count(&self) -> u32 {
+        self.func_module_alloc_idx() + 1
+    }
+
+    fn type_section(&self) -> TypeSection {
         TypeSection::with_types(
-            (0..5)
-                .map(|i| {
+            self.signatures
+                .signatures
+                .iter()
+                .map(|signature| {
                     Type::Function(FunctionType::new(
-                        {
-                            let mut params = Vec::new();
-                            params.resize_with(i, || ValueType::I32);
-                            params
-                        },
-                        vec![ValueType::I32],
+                        signature
+                            .params
+                            .iter()
+                            .map(|ty| wasm_generator::value_type(*ty))
+                            .collect(),
+                        vec![wasm_generator::value_type(signature.ret)],
                     ))
                 })
                 .collect(),
         )
     }
 
+    fn init_expr(x: ir::Const) -> InitExpr {
+        InitExpr::new(vec![
+            match x {
+                ir::Const::I32(x) => Instruction::I32Const(x),
+                ir::Const::I64(x) => Instruction::I64Const(x),
+                ir::Const::F64(x) => Instruction::F64Const(x),
+            },
+            Instruction::End,
+        ])
+    }
+
     pub fn compile_skeleton(&self) -> Module {
         Module::new(vec![
-            Section::Type(Self::type_section()),
+            Section::Type(self.type_section()),
             Section::Import(ImportSection::with_entries(vec![ImportEntry::new(
                 "env".to_string(),
                 "compile_func".to_string(),
-                External::Function(1),
+                External::Function(self.i32_type_idx()),
             )])),
             Section::Function(FunctionSection::with_entries(
-                self.module
-                    .funcs
+                self.signatures
+                    .func_types
                     .iter()
-                    .map(|func| Func::new(func.args_count as u32))
+                    .map(|type_id| Func::new(*type_id))
+                    .chain(std::iter::once(Func::new(self.i32_type_idx())))
                     .collect(),
             )),
             Section::Table(TableSection::with_entries(vec![TableType::new(
-                self.module.funcs.len() as u32,
+                self.table_size(),
                 None,
             )])),
+            Section::Memory(MemorySection::with_entries(vec![MemoryType::new(
+                // 文字列定数が収まるようにする
+                self.module.data_layout().2.div_ceil(0x10000),
+                None,
+            )])),
+            // 全ての関数モジュールから共有されるようにグローバル変数はスケルトンで定義してexportする
+            Section::Global(GlobalSection::with_entries(
+                self.module
+                    .globals
+                    .iter()
+                    .map(|global| {
+                        GlobalEntry::new(
+                            GlobalType::new(wasm_generator::value_type(global.init.ty()), true),
+                            Self::init_expr(global.init),
+                        )
+                    })
+                    .chain(std::iter::once(GlobalEntry::new(
+                        GlobalType::new(ValueType::I32, true),
+                        Self::init_expr(ir::Const::I32(self.module.data_layout().2 as i32)),
+                    )))
+                    .chain(std::iter::once(GlobalEntry::new(
+                        GlobalType::new(ValueType::I64, true),
+                        Self::init_expr(ir::Const::I64(ir::DEFAULT_FUEL)),
+                    )))
+                    // trapの理由。0ならwasm自身がtrapした
+                    .chain(std::iter::once(GlobalEntry::new(
+                        GlobalType::new(ValueType::I32, true),
+                        Self::init_expr(ir::Const::I32(0)),
+                    )))
+                    .collect(),
+            )),
             Section::Export(ExportSection::with_entries({
                 let mut entries = Vec::new();
                 for (i, func) in self.module.funcs.iter().enumerate() {
@@ -64,12 +222,35 @@ impl<'a> Compiler<'a> {
                     ));
                 }
                 entries.push(ExportEntry::new("_table".to_string(), Internal::Table(0)));
+                entries.push(ExportEntry::new("_memory".to_string(), Internal::Memory(0)));
+                entries.push(ExportEntry::new(
+                    "_alloc".to_string(),
+                    Internal::Function(self.skeleton_alloc_idx()),
+                ));
+                entries.push(ExportEntry::new(
+                    "_heap".to_string(),
+                    Internal::Global(self.skeleton_heap_idx()),
+                ));
+                entries.push(ExportEntry::new(
+                    "_fuel".to_string(),
+                    Internal::Global(self.skeleton_fuel_idx()),
+                ));
+                entries.push(ExportEntry::new(
+                    "_trap".to_string(),
+                    Internal::Global(self.skeleton_trap_idx()),
+                ));
+                for (i, global) in self.module.globals.iter().enumerate() {
+                    entries.push(ExportEntry::new(
+                        Self::global_export_name(global),
+                        Internal::Global(i as u32),
+                    ));
+                }
                 entries
             })),
             Section::Element(ElementSection::with_entries(vec![ElementSegment::new(
                 0,
                 Some(InitExpr::new(vec![
-                    Instruction::I32Const(0),
+                    Instruction::I32Const(ir::table_idx(0) as i32),
                     Instruction::End,
                 ])),
                 self.module
@@ -85,6 +266,38 @@ impl<'a> Compiler<'a> {
                     .iter()
                     .enumerate()
                     .map(|(i, _)| self.compile_skeleton_func(i))
+                    .chain(std::iter::once(self.compile_alloc()))
+                    .collect(),
+            )),
+            Section::Data(DataSection::with_entries(
+                self.module
+                    .strings
+                    .iter()
+                    .zip(self.module.data_layout().0)
+                    .map(|(s, addr)| {
+                        let mut value = (s.len() as u32).to_le_bytes().to_vec();
+                        value.resize(ir::ARRAY_HEADER_SIZE as usize, 0);
+                        value.extend(s.as_bytes());
+                        DataSegment::new(
+                            0,
+                            Some(Self::init_expr(ir::Const::I32(addr as i32))),
+                            value,
+                        )
+                    })
+                    .chain({
+                        let (_, closure_addrs, _) = self.module.data_layout();
+                        self.module
+                            .closures
+                            .iter()
+                            .zip(closure_addrs)
+                            .map(|(&func, addr)| {
+                                DataSegment::new(
+                                    0,
+                                    Some(Self::init_expr(ir::Const::I32(addr as i32))),
+                                    ir::table_idx(func).to_le_bytes().to_vec(),
+                                )
+                            })
+                    })
                     .collect(),
             )),
         ])
@@ -103,8 +316,8 @@ impl<'a> Compiler<'a> {
                     Instruction::I32Const(idx as i32),
                     Instruction::Call(0), // compile
                     Instruction::Drop,
-                    Instruction::I32Const(idx as i32),
-                    Instruction::CallIndirect(func.args_count as u32, 0),
+                    Instruction::I32Const(ir::table_idx(idx) as i32),
+                    Instruction::CallIndirect(self.signatures.func_types[idx], 0),
                     Instruction::End,
                 ]);
                 instrs
@@ -112,58 +325,232 @@ impl<'a> Compiler<'a> {
         )
     }
 
+    // (size: i32) -> ptr: i32
+    // 確保した領域は解放しない。メモリが足りなければ増やし、増やせなければtrapする
+    fn compile_alloc(&self) -> FuncBody {
+        let heap = self.skeleton_heap_idx();
+        FuncBody::new(
+            vec![Local::new(3, ValueType::I32)],
+            Instructions::new(vec![
+                // ptr = _heap
+                Instruction::GetGlobal(heap),
+                Instruction::SetLocal(1),
+                // new_heap = (ptr + size + 7) & !7
+                Instruction::GetLocal(1),
+                Instruction::GetLocal(0),
+                Instruction::I32Add,
+                Instruction::I32Const(7),
+                Instruction::I32Add,
+                Instruction::I32Const(!7),
+                Instruction::I32And,
+                Instruction::TeeLocal(2),
+                // 4GiBを超える場合
+                Instruction::GetLocal(1),
+                Instruction::I32LtU,
+                Instruction::If(BlockType::NoResult),
+                Instruction::I32Const(Trap::OutOfMemory.code()),
+                Instruction::SetGlobal(self.skeleton_trap_idx()),
+                Instruction::Unreachable,
+                Instruction::End,
+                Instruction::GetLocal(2),
+                Instruction::SetGlobal(heap),
+                // 足りないページ数 = ceil(new_heap / 64KiB) - memory.size
+                Instruction::GetLocal(2),
+                Instruction::I64ExtendUI32,
+                Instruction::I64Const(0xffff),
+                Instruction::I64Add,
+                Instruction::I64Const(16),
+                Instruction::I64ShrU,
+                Instruction::I32WrapI64,
+                Instruction::CurrentMemory(0),
+                Instruction::I32Sub,
+                Instruction::TeeLocal(3),
+                Instruction::I32Const(0),
+                Instruction::I32GtS,
+                Instruction::If(BlockType::NoResult),
+                Instruction::GetLocal(3),
+                Instruction::GrowMemory(0),
+                Instruction::I32Const(-1),
+                Instruction::I32Eq,
+                Instruction::If(BlockType::NoResult),
+                Instruction::I32Const(Trap::OutOfMemory.code()),
+                Instruction::SetGlobal(self.skeleton_trap_idx()),
+                Instruction::Unreachable,
+                Instruction::End,
+                Instruction::End,
+                Instruction::GetLocal(1),
+                Instruction::End,
+            ]),
+        )
+    }
+
     pub fn compile_func_module(&self, idx: usize) -> Module {
-        let func = &self.module.funcs[idx];
+        self.func_module(idx, self.compile_func(idx).0)
+    }
+
+    // trapしたときのスタックトレースで関数を特定できるようにモジュールに名前を付ける
+    pub fn func_module_name(idx: usize) -> String {
+        format!("wjit_func_{}", idx)
+    }
+
+    fn func_module(&self, idx: usize, body: FuncBody) -> Module {
+        let mut func_names = FunctionNameSubsection::default();
+        func_names.names_mut().insert(
+            self.func_module_imported_funcs_count(),
+            self.module.funcs[idx].name.clone(),
+        );
         Module::new(vec![
-            Section::Type(Self::type_section()),
-            Section::Import(ImportSection::with_entries(vec![
-                ImportEntry::new(
+            Section::Type(self.type_section()),
+            Section::Import(ImportSection::with_entries({
+                let mut entries = Vec::new();
+                for host_func in &self.module.host_funcs {
+                    entries.push(ImportEntry::new(
+                        "env".to_string(),
+                        host_func.name.clone(),
+                        External::Function(self.signatures.get(&host_func.signature())),
+                    ));
+                }
+                entries.push(ImportEntry::new(
                     "env".to_string(),
-                    "println".to_string(),
-                    External::Function(1),
-                ),
-                ImportEntry::new(
+                    "_alloc".to_string(),
+                    External::Function(self.i32_type_idx()),
+                ));
+                entries.push(ImportEntry::new(
                     "env".to_string(),
                     "_table".to_string(),
-                    External::Table(TableType::new(self.module.funcs.len() as u32, None)),
-                ),
-            ])),
+                    External::Table(TableType::new(self.table_size(), None)),
+                ));
+                entries.push(ImportEntry::new(
+                    "env".to_string(),
+                    "_memory".to_string(),
+                    External::Memory(MemoryType::new(1, None)),
+                ));
+                for global in &self.module.globals {
+                    entries.push(ImportEntry::new(
+                        "env".to_string(),
+                        Self::global_export_name(global),
+                        External::Global(GlobalType::new(
+                            wasm_generator::value_type(global.init.ty()),
+                            true,
+                        )),
+                    ));
+                }
+                entries.push(ImportEntry::new(
+                    "env".to_string(),
+                    "_fuel".to_string(),
+                    External::Global(GlobalType::new(ValueType::I64, true)),
+                ));
+                entries.push(ImportEntry::new(
+                    "env".to_string(),
+                    "_trap".to_string(),
+                    External::Global(GlobalType::new(ValueType::I32, true)),
+                ));
+                entries
+            })),
             Section::Function(FunctionSection::with_entries(vec![Func::new(
-                func.args_count as u32,
+                self.signatures.func_types[idx],
             )])),
             Section::Element(ElementSection::with_entries(vec![ElementSegment::new(
                 0,
                 Some(InitExpr::new(vec![
-                    Instruction::I32Const(idx as i32),
+                    Instruction::I32Const(ir::table_idx(idx) as i32),
                     Instruction::End,
                 ])),
-                vec![1],
+                vec![self.func_module_imported_funcs_count()],
             )])),
-            Section::Code(CodeSection::with_bodies(vec![self.compile_func(idx)])),
+            Section::Code(CodeSection::with_bodies(vec![body])),
+            Section::Name(NameSection::new(
+                Some(ModuleNameSubsection::new(Self::func_module_name(idx))),
+                Some(func_names),
+                None,
+            )),
         ])
     }
 
-    pub fn compile_func(&self, idx: usize) -> FuncBody {
+    // 関数モジュール上のバイトオフセットをIRの命令のindexに戻す
+    pub fn func_module_instr(&self, idx: usize, offset: u32) -> Option<usize> {
+        let (body, starts) = self.compile_func(idx);
+        let module = self.func_module(idx, body.clone());
+
+        // マジックナンバーとバージョン
+        let mut pos = 8;
+        for section in module.sections() {
+            if let Section::Code(code) = section {
+                let instrs = code.bodies()[0].code().elements();
+                let instrs_len = instrs.iter().map(serialized_len).sum::<usize>();
+                pos += serialized_len(section) - instrs_len;
+                let mut wasm_instr = None;
+                for (i, instr) in instrs.iter().enumerate() {
+                    if pos > offset as usize {
+                        break;
+                    }
+                    wasm_instr = Some(i);
+                    pos += serialized_len(instr);
+                }
+                return wasm_instr
+                    .and_then(|wasm_instr| starts.iter().rposition(|&start| start <= wasm_instr));
+            }
+            pos += serialized_len(section);
+        }
+        None
+    }
+
+    pub fn backtrace_frame(&self, idx: usize, offset: u32) -> Frame {
+        Frame::new(self.module, idx, self.func_module_instr(idx, offset))
+    }
+
+    pub fn compile_func(&self, idx: usize) -> (FuncBody, Vec<usize>) {
         let func = &self.module.funcs[idx];
 
         let mut generator = wasm_generator::InstrsGenerator::new();
 
-        generator.types = (0..5).map(|x| (x, x as u32)).collect();
+        generator.types = self
+            .signatures
+            .func_types
+            .iter()
+            .enumerate()
+            .map(|(func, type_id)| (func, *type_id))
+            .collect();
         generator.func_refs = (0..self.module.funcs.len())
-            .map(|x| (x, wasm_generator::FuncRef::Indirect(x as u32)))
+            .map(|x| (x, wasm_generator::FuncRef::Indirect(ir::table_idx(x))))
+            .collect();
+        generator.builtin_func_refs = (0..self.module.host_funcs.len())
+            .map(|i| {
+                (
+                    wasm_generator::BuiltinFunc::Host(i),
+                    wasm_generator::FuncRef::Direct(i as u32),
+                )
+            })
+            .chain(std::iter::once((
+                wasm_generator::BuiltinFunc::Alloc,
+                wasm_generator::FuncRef::Direct(self.func_module_alloc_idx()),
+            )))
+            .collect();
+        (generator.string_addrs, generator.closure_addrs, _) = self.module.data_layout();
+        generator.signature_types = self
+            .signatures
+            .signatures
+            .iter()
+            .enumerate()
+            .map(|(type_id, signature)| (signature.clone(), type_id as u32))
             .collect();
-        generator.builtin_func_refs = vec![(
-            wasm_generator::BuiltinFunc::Println,
-            wasm_generator::FuncRef::Direct(0),
-        )]
-        .into_iter()
-        .collect();
+        generator.fuel_global = Some(self.func_module_fuel_idx());
+        generator.trap_global = Some(self.func_module_trap_idx());
 
-        let instrs = generator.gen_instrs(&func.instrs);
+        let (instrs, starts) = generator.gen_instrs(func);
 
-        FuncBody::new(
-            vec![Local::new(func.locals_count as u32, ValueType::I32)],
+        let body = FuncBody::new(
+            func.locals[func.args_count..]
+                .iter()
+                .chain(wasm_generator::SCRATCH_LOCALS.iter())
+                .map(|ty| Local::new(1, wasm_generator::value_type(*ty)))
+                .collect(),
             Instructions::new(instrs),
-        )
+        );
+        (body, starts)
     }
 }
+
+fn serialized_len<T: Serialize<Error = parity_wasm::elements::Error> + Clone>(x: &T) -> usize {
+    parity_wasm::serialize(x.clone()).unwrap().len()
+}
@@ -0,0 +1,302 @@
+use std::fmt;
+
+use crate::span::Span;
+use crate::token::Token;
+use crate::types::Ty;
+
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+pub enum CompileError {
+    Lex {
+        found: char,
+        span: Span,
+    },
+    UnterminatedBlockComment {
+        span: Span,
+    },
+    UnterminatedStringLiteral {
+        span: Span,
+    },
+    InvalidEscape {
+        escape: String,
+        span: Span,
+    },
+    InvalidIntLiteral {
+        literal: String,
+        span: Span,
+    },
+    IntLiteralOutOfRange {
+        span: Span,
+    },
+    InvalidFloatLiteral {
+        literal: String,
+        span: Span,
+    },
+    FloatLiteralOutOfRange {
+        span: Span,
+    },
+    Parse {
+        found: Option<Token>,
+        expected: Vec<String>,
+        span: Span,
+    },
+    UnknownIdent {
+        name: String,
+        span: Span,
+    },
+    UnknownFunc {
+        name: String,
+        span: Span,
+    },
+    DuplicateFunc {
+        name: String,
+        span: Span,
+    },
+    DuplicateGlobal {
+        name: String,
+        span: Span,
+    },
+    NonConstGlobalInit {
+        span: Span,
+    },
+    DuplicateStruct {
+        name: String,
+        span: Span,
+    },
+    UnknownStruct {
+        name: String,
+        span: Span,
+    },
+    DuplicateField {
+        name: String,
+        span: Span,
+    },
+    UnknownField {
+        struct_name: String,
+        name: String,
+        span: Span,
+    },
+    MissingField {
+        struct_name: String,
+        name: String,
+        span: Span,
+    },
+    AssignToCapturedVar {
+        name: String,
+        span: Span,
+    },
+    DuplicatePattern {
+        pattern: i128,
+        span: Span,
+    },
+    BreakOutsideLoop {
+        span: Span,
+    },
+    ContinueOutsideLoop {
+        span: Span,
+    },
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        actual: usize,
+        span: Span,
+    },
+    TypeMismatch {
+        expected: Ty,
+        found: Ty,
+        span: Span,
+    },
+    UnexpectedType {
+        expected: String,
+        found: Ty,
+        span: Span,
+    },
+    UnsupportedOperandType {
+        op: String,
+        ty: Ty,
+        span: Span,
+    },
+    InvalidMainSignature {
+        span: Span,
+    },
+    FileNotFound {
+        path: String,
+        span: Span,
+    },
+    ImportCycle {
+        path: String,
+        span: Span,
+    },
+    DuplicateImport {
+        name: String,
+        span: Span,
+    },
+}
+
+impl CompileError {
+    pub fn span(&self) -> &Span {
+        match self {
+            CompileError::Lex { span, .. } => span,
+            CompileError::UnterminatedBlockComment { span } => span,
+            CompileError::UnterminatedStringLiteral { span } => span,
+            CompileError::InvalidEscape { span, .. } => span,
+            CompileError::InvalidIntLiteral { span, .. } => span,
+            CompileError::IntLiteralOutOfRange { span } => span,
+            CompileError::InvalidFloatLiteral { span, .. } => span,
+            CompileError::FloatLiteralOutOfRange { span } => span,
+            CompileError::Parse { span, .. } => span,
+            CompileError::UnknownIdent { span, .. } => span,
+            CompileError::UnknownFunc { span, .. } => span,
+            CompileError::DuplicateFunc { span, .. } => span,
+            CompileError::DuplicateGlobal { span, .. } => span,
+            CompileError::NonConstGlobalInit { span } => span,
+            CompileError::DuplicateStruct { span, .. } => span,
+            CompileError::UnknownStruct { span, .. } => span,
+            CompileError::DuplicateField { span, .. } => span,
+            CompileError::UnknownField { span, .. } => span,
+            CompileError::MissingField { span, .. } => span,
+            CompileError::AssignToCapturedVar { span, .. } => span,
+            CompileError::DuplicatePattern { span, .. } => span,
+            CompileError::BreakOutsideLoop { span } => span,
+            CompileError::ContinueOutsideLoop { span } => span,
+            CompileError::ArityMismatch { span, .. } => span,
+            CompileError::TypeMismatch { span, .. } => span,
+            CompileError::UnexpectedType { span, .. } => span,
+            CompileError::UnsupportedOperandType { span, .. } => span,
+            CompileError::InvalidMainSignature { span } => span,
+            CompileError::FileNotFound { span, .. } => span,
+            CompileError::ImportCycle { span, .. } => span,
+            CompileError::DuplicateImport { span, .. } => span,
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = self.span();
+        write!(f, "{}:{}: ", span.line, span.column)?;
+        match self {
+            CompileError::Lex { found, .. } => write!(f, "unexpected character {:?}", found),
+            CompileError::UnterminatedBlockComment { .. } => {
+                write!(f, "unterminated block comment")
+            }
+            CompileError::UnterminatedStringLiteral { .. } => {
+                write!(f, "unterminated string literal")
+            }
+            CompileError::InvalidEscape { escape, .. } => {
+                write!(f, "invalid escape sequence `{}`", escape)
+            }
+            CompileError::InvalidIntLiteral { literal, .. } => {
+                write!(f, "invalid integer literal `{}`", literal)
+            }
+            CompileError::IntLiteralOutOfRange { .. } => {
+                write!(f, "integer literal is out of range")
+            }
+            CompileError::InvalidFloatLiteral { literal, .. } => {
+                write!(f, "invalid float literal `{}`", literal)
+            }
+            CompileError::FloatLiteralOutOfRange { .. } => {
+                write!(f, "float literal is out of range")
+            }
+            CompileError::Parse {
+                found, expected, ..
+            } => {
+                match expected.split_last() {
+                    Some((last, [])) => write!(f, "expected {}, ", last)?,
+                    Some((last, init)) => write!(f, "expected {} or {}, ", init.join(", "), last)?,
+                    None => (),
+                }
+                match found {
+                    Some(token) => write!(f, "found `{}`", token),
+                    None => write!(f, "found end of input"),
+                }
+            }
+            CompileError::UnknownIdent { name, .. } => {
+                write!(f, "unknown identifier `{}`", name)
+            }
+            CompileError::UnknownFunc { name, .. } => write!(f, "unknown function `{}`", name),
+            CompileError::DuplicateFunc { name, .. } => {
+                write!(f, "function `{}` is defined more than once", name)
+            }
+            CompileError::DuplicateGlobal { name, .. } => {
+                write!(f, "global `{}` is defined more than once", name)
+            }
+            CompileError::NonConstGlobalInit { .. } => {
+                write!(f, "global initializer must be an integer constant")
+            }
+            CompileError::DuplicateStruct { name, .. } => {
+                write!(f, "struct `{}` is defined more than once", name)
+            }
+            CompileError::UnknownStruct { name, .. } => write!(f, "unknown struct `{}`", name),
+            CompileError::DuplicateField { name, .. } => {
+                write!(f, "field `{}` is specified more than once", name)
+            }
+            CompileError::UnknownField {
+                struct_name, name, ..
+            } => write!(f, "struct `{}` has no field `{}`", struct_name, name),
+            CompileError::MissingField {
+                struct_name, name, ..
+            } => write!(f, "missing field `{}` in `{}`", name, struct_name),
+            CompileError::AssignToCapturedVar { name, .. } => {
+                write!(f, "cannot assign to captured variable `{}`", name)
+            }
+            CompileError::DuplicatePattern { pattern, .. } => {
+                write!(f, "pattern `{}` is matched more than once", pattern)
+            }
+            CompileError::BreakOutsideLoop { .. } => write!(f, "`break` outside of a loop"),
+            CompileError::ContinueOutsideLoop { .. } => {
+                write!(f, "`continue` outside of a loop")
+            }
+            CompileError::ArityMismatch {
+                name,
+                expected,
+                actual,
+                ..
+            } => write!(
+                f,
+                "function `{}` takes {} argument(s) but {} were supplied",
+                name, expected, actual
+            ),
+            CompileError::TypeMismatch {
+                expected, found, ..
+            } => write!(f, "expected `{}`, found `{}`", expected, found),
+            CompileError::UnexpectedType {
+                expected, found, ..
+            } => write!(f, "expected {}, found `{}`", expected, found),
+            CompileError::UnsupportedOperandType { op, ty, .. } => {
+                write!(f, "operator `{}` cannot be applied to `{}`", op, ty)
+            }
+            CompileError::InvalidMainSignature { .. } => {
+                write!(f, "`main` must take no arguments and return `i32`")
+            }
+            CompileError::FileNotFound { path, .. } => write!(f, "cannot find file `{}`", path),
+            CompileError::ImportCycle { path, .. } => {
+                write!(f, "`{}` is imported cyclically", path)
+            }
+            CompileError::DuplicateImport { name, .. } => {
+                write!(f, "import `{}` is defined more than once", name)
+            }
+        }
+    }
+}
+
+// 読み込んだファイルのパスと合わせて表示する
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+pub struct CompileErrors {
+    pub files: Vec<String>,
+    pub errors: Vec<CompileError>,
+}
+
+impl fmt::Display for CompileErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            if let Some(path) = self.files.get(error.span().file) {
+                write!(f, "{}:", path)?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
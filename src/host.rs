@@ -0,0 +1,255 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{BufRead, Write};
+
+use crate::interpreter::Value;
+use crate::ir::HostFunc;
+use crate::types::{Ty, Type};
+
+// 埋め込み側の関数に渡す引数
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HostValue<'a> {
+    I32(i32),
+    I64(i64),
+    F64(f64),
+    Str(&'a str),
+}
+
+type HostImpl = Box<dyn FnMut(&[HostValue]) -> Value>;
+
+// コンパイル時に使った宣言と、インタプリタに渡した登録が合わない
+#[derive(Debug, PartialEq, Clone, Eq)]
+pub enum HostFuncError {
+    NotRegistered { name: String },
+    SignatureMismatch { name: String },
+}
+
+impl fmt::Display for HostFuncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostFuncError::NotRegistered { name } => {
+                write!(f, "host function `{}` is not registered", name)
+            }
+            HostFuncError::SignatureMismatch { name } => write!(
+                f,
+                "host function `{}` is registered with a different signature",
+                name
+            ),
+        }
+    }
+}
+
+// 埋め込み側が登録した関数の宣言と実装
+pub struct HostFuncs {
+    funcs: Vec<(HostFunc, HostImpl)>,
+}
+
+impl fmt::Debug for HostFuncs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.funcs.iter().map(|(decl, _)| decl))
+            .finish()
+    }
+}
+
+impl Default for HostFuncs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HostFuncs {
+    pub fn new() -> Self {
+        HostFuncs { funcs: Vec::new() }
+    }
+
+    // 同じ名前の関数は置き換える
+    pub fn register(
+        &mut self,
+        name: &str,
+        params: Vec<Ty>,
+        ret: Type,
+        f: impl FnMut(&[HostValue]) -> Value + 'static,
+    ) {
+        let decl = HostFunc {
+            name: name.to_string(),
+            params,
+            ret,
+        };
+        match self.find(name) {
+            Some(idx) => self.funcs[idx] = (decl, Box::new(f)),
+            None => self.funcs.push((decl, Box::new(f))),
+        }
+    }
+
+    pub fn decls(&self) -> Vec<HostFunc> {
+        self.funcs.iter().map(|(decl, _)| decl.clone()).collect()
+    }
+
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.funcs.iter().position(|(decl, _)| decl.name == name)
+    }
+
+    // declと同じ名前で、引数と戻り値の型も同じ関数を探す
+    pub fn resolve(&self, decl: &HostFunc) -> Result<usize, HostFuncError> {
+        let idx = self
+            .find(&decl.name)
+            .ok_or_else(|| HostFuncError::NotRegistered {
+                name: decl.name.clone(),
+            })?;
+        if self.funcs[idx].0 != *decl {
+            return Err(HostFuncError::SignatureMismatch {
+                name: decl.name.clone(),
+            });
+        }
+        Ok(idx)
+    }
+
+    pub fn ret(&self, idx: usize) -> Type {
+        self.funcs[idx].0.ret
+    }
+
+    pub fn call(&mut self, idx: usize, args: &[HostValue]) -> Value {
+        (self.funcs[idx].1)(args)
+    }
+
+    // 組み込みのprintlnは引数の型に応じてprintln, println_i64, println_f64を呼び出す
+    fn register_print(
+        &mut self,
+        println: fn(HostValue),
+        print_str: fn(&str),
+        println_str: fn(&str),
+    ) {
+        for ty in [Type::I32, Type::I64, Type::F64] {
+            self.register(
+                &println_name(ty),
+                vec![Ty::Scalar(ty)],
+                Type::I32,
+                move |args| {
+                    println(args[0]);
+                    Value::I32(0)
+                },
+            );
+        }
+        self.register("print_str", vec![Ty::Str], Type::I32, move |args| {
+            if let HostValue::Str(s) = args[0] {
+                print_str(s);
+            }
+            Value::I32(0)
+        });
+        self.register("println_str", vec![Ty::Str], Type::I32, move |args| {
+            if let HostValue::Str(s) = args[0] {
+                println_str(s);
+            }
+            Value::I32(0)
+        });
+    }
+
+    // exitは戻らない
+    fn register_process(&mut self, exit: fn(i32), mut read_int: impl FnMut() -> i32 + 'static) {
+        self.register("exit", vec![Ty::I32], Type::I32, move |args| {
+            if let HostValue::I32(code) = args[0] {
+                exit(code);
+            }
+            Value::I32(0)
+        });
+        self.register("read_int", vec![], Type::I32, move |_| {
+            Value::I32(read_int())
+        });
+    }
+
+    // 標準入出力を使う
+    pub fn stdout() -> Self {
+        let mut host_funcs = Self::new();
+        host_funcs.register_print(
+            |x| match x {
+                HostValue::I32(x) => println!("{}", x),
+                HostValue::I64(x) => println!("{}", x),
+                HostValue::F64(x) => println!("{}", x),
+                HostValue::Str(s) => println!("{}", s),
+            },
+            |s| print!("{}", s),
+            |s| println!("{}", s),
+        );
+        let mut tokens: VecDeque<String> = VecDeque::new();
+        host_funcs.register_process(
+            |code| {
+                std::io::stdout().flush().unwrap();
+                std::process::exit(code)
+            },
+            // 空白区切りで読む。入力が尽きたか整数でなければ0
+            move || loop {
+                if let Some(token) = tokens.pop_front() {
+                    return parse_int(&token);
+                }
+                let mut line = String::new();
+                if std::io::stdin().lock().read_line(&mut line).unwrap() == 0 {
+                    return 0;
+                }
+                tokens.extend(line.split_whitespace().map(|token| token.to_string()));
+            },
+        );
+        host_funcs
+    }
+
+    // JS側の関数を呼び出す
+    pub fn wasm() -> Self {
+        let mut host_funcs = Self::new();
+        host_funcs.register_print(
+            |x| unsafe {
+                match x {
+                    HostValue::I32(x) => println(x),
+                    HostValue::I64(x) => println_i64(x),
+                    HostValue::F64(x) => println_f64(x),
+                    HostValue::Str(s) => println_str(s.as_ptr(), s.len()),
+                }
+            },
+            |s| unsafe { print_str(s.as_ptr(), s.len()) },
+            |s| unsafe { println_str(s.as_ptr(), s.len()) },
+        );
+        host_funcs.register_process(|code| unsafe { exit(code) }, || unsafe { read_int() });
+        host_funcs
+    }
+}
+
+fn parse_int(token: &str) -> i32 {
+    token.parse().unwrap_or(0)
+}
+
+pub fn println_name(ty: Type) -> String {
+    match ty {
+        Type::I32 => "println".to_string(),
+        ty => format!("println_{}", ty),
+    }
+}
+
+extern "C" {
+    fn println(x: i32);
+    fn println_i64(x: i64);
+    fn println_f64(x: f64);
+    fn print_str(ptr: *const u8, len: usize);
+    fn println_str(ptr: *const u8, len: usize);
+    fn exit(code: i32);
+    fn read_int() -> i32;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_replaces_functions_with_the_same_name() {
+        let mut host_funcs = HostFuncs::new();
+        host_funcs.register("f", vec![], Type::I32, |_| Value::I32(1));
+        host_funcs.register("g", vec![], Type::I32, |_| Value::I32(2));
+        host_funcs.register("f", vec![Ty::I32], Type::I64, |_| Value::I64(3));
+        let decls = host_funcs.decls();
+        assert_eq!(decls.len(), 2);
+        assert_eq!((decls[0].params.len(), decls[0].ret), (1, Type::I64));
+        assert_eq!(
+            host_funcs.call(host_funcs.find("f").unwrap(), &[]),
+            Value::I64(3)
+        );
+        assert_eq!(host_funcs.find("h"), None);
+    }
+}
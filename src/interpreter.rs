@@ -1,32 +1,355 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Div, Mul, Sub};
+
+use crate::backtrace::{Backtrace, Frame};
+use crate::host::{HostFuncError, HostFuncs, HostValue};
 use crate::ir::*;
+use crate::types::{Ty, Type};
+
+// 終わらない再帰でホストのメモリを使い切らないようにする
+// JITではV8のスタックの大きさで決まるので、それより浅くしておく
+pub const MAX_CALL_DEPTH: usize = 5000;
 
-pub trait Builtin {
-    fn println(&mut self, x: i32);
+// 実行時エラー。wasmでtrapする場合に対応する
+#[derive(Debug, PartialEq, Clone, Copy, Hash, Eq)]
+pub enum Trap {
+    DivideByZero,
+    // MIN / -1や範囲外の浮動小数点数から整数への変換
+    IntegerOverflow,
+    // NaNから整数への変換
+    InvalidConversion,
+    ArrayIndexOutOfBounds,
+    InvalidArrayLength,
+    NullPointer,
+    NullFunctionCall,
+    IndirectCallTypeMismatch,
+    // 埋め込み側の関数が宣言と違う型の値を返した
+    HostFuncTypeMismatch,
+    AssertionFailed,
+    OutOfMemory,
+    // 呼び出しがMAX_CALL_DEPTHより深くなった
+    StackOverflow,
+    OutOfFuel,
 }
 
-extern "C" {
-    fn println(x: i32);
+impl Trap {
+    const ALL: [Trap; 13] = [
+        Trap::DivideByZero,
+        Trap::IntegerOverflow,
+        Trap::InvalidConversion,
+        Trap::ArrayIndexOutOfBounds,
+        Trap::InvalidArrayLength,
+        Trap::NullPointer,
+        Trap::NullFunctionCall,
+        Trap::IndirectCallTypeMismatch,
+        Trap::HostFuncTypeMismatch,
+        Trap::AssertionFailed,
+        Trap::OutOfMemory,
+        Trap::StackOverflow,
+        Trap::OutOfFuel,
+    ];
+
+    // JITのコードがtrapする前に_trapに書き込む番号。0はwasm自身がtrapした場合
+    pub fn code(self) -> i32 {
+        Self::ALL.iter().position(|&trap| trap == self).unwrap() as i32 + 1
+    }
+
+    pub fn from_code(code: i32) -> Option<Trap> {
+        Self::ALL.get((code as usize).checked_sub(1)?).copied()
+    }
 }
 
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::DivideByZero => write!(f, "integer divide by zero"),
+            Trap::IntegerOverflow => write!(f, "integer overflow"),
+            Trap::InvalidConversion => write!(f, "invalid conversion to integer"),
+            Trap::ArrayIndexOutOfBounds => write!(f, "array index out of bounds"),
+            Trap::InvalidArrayLength => write!(f, "invalid array length"),
+            Trap::NullPointer => write!(f, "null pointer access"),
+            Trap::NullFunctionCall => write!(f, "null function call"),
+            Trap::IndirectCallTypeMismatch => write!(f, "indirect call type mismatch"),
+            Trap::HostFuncTypeMismatch => {
+                write!(f, "host function returned a value of the wrong type")
+            }
+            Trap::AssertionFailed => write!(f, "assertion failed"),
+            Trap::OutOfMemory => write!(f, "out of memory"),
+            Trap::StackOverflow => write!(f, "stack overflow"),
+            Trap::OutOfFuel => write!(f, "out of fuel"),
+        }
+    }
+}
+
+// trapした位置までの呼び出し履歴を持つ
 #[derive(Debug, PartialEq, Clone, Eq)]
+pub struct RuntimeError {
+    pub trap: Trap,
+    pub backtrace: Backtrace,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.trap)?;
+        if !self.backtrace.frames.is_empty() {
+            write!(f, "\n{}", self.backtrace)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F64(f64),
+}
+
+impl Value {
+    pub fn zero(ty: Type) -> Self {
+        match ty {
+            Type::I32 => Value::I32(0),
+            Type::I64 => Value::I64(0),
+            Type::F64 => Value::F64(0.0),
+        }
+    }
+
+    pub fn ty(&self) -> Type {
+        match self {
+            Value::I32(_) => Type::I32,
+            Value::I64(_) => Type::I64,
+            Value::F64(_) => Type::F64,
+        }
+    }
+
+    // 型検査済みなので違う型が来ることはない
+    pub fn as_i32(&self) -> i32 {
+        match self {
+            Value::I32(x) => *x,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_i64(&self) -> i64 {
+        match self {
+            Value::I64(x) => *x,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Value::F64(x) => *x,
+            _ => unreachable!(),
+        }
+    }
+
+    // wasmと同じく範囲外の浮動小数点数から整数への変換はエラーにする
+    fn convert(self, to: Type) -> Result<Self, Trap> {
+        Ok(match (self, to) {
+            (Value::I32(x), Type::I32) => Value::I32(x),
+            (Value::I32(x), Type::I64) => Value::I64(x as i64),
+            (Value::I32(x), Type::F64) => Value::F64(x as f64),
+            (Value::I64(x), Type::I32) => Value::I32(x as i32),
+            (Value::I64(x), Type::I64) => Value::I64(x),
+            (Value::I64(x), Type::F64) => Value::F64(x as f64),
+            (Value::F64(x), Type::I32) => {
+                check_truncate(x, -2147483648.0..2147483648.0)?;
+                Value::I32(x as i32)
+            }
+            (Value::F64(x), Type::I64) => {
+                check_truncate(x, -9223372036854775808.0..9223372036854775808.0)?;
+                Value::I64(x as i64)
+            }
+            (Value::F64(x), Type::F64) => Value::F64(x),
+        })
+    }
+}
+
+impl From<Const> for Value {
+    fn from(x: Const) -> Self {
+        match x {
+            Const::I32(x) => Value::I32(x),
+            Const::I64(x) => Value::I64(x),
+            Const::F64(x) => Value::F64(f64::from_bits(x)),
+        }
+    }
+}
 
-pub struct WasmBuiltin;
+fn check_truncate(x: f64, range: std::ops::Range<f64>) -> Result<(), Trap> {
+    if x.is_nan() {
+        Err(Trap::InvalidConversion)
+    } else if !range.contains(&x.trunc()) {
+        Err(Trap::IntegerOverflow)
+    } else {
+        Ok(())
+    }
+}
 
-impl Builtin for WasmBuiltin {
-    fn println(&mut self, x: i32) {
-        unsafe {
-            println(x);
+fn arith(
+    stack: &mut Vec<Value>,
+    op_i32: fn(i32, i32) -> i32,
+    op_i64: fn(i64, i64) -> i64,
+    op_f64: fn(f64, f64) -> f64,
+) {
+    let y = stack.pop().unwrap();
+    let x = stack.pop().unwrap();
+    stack.push(match (x, y) {
+        (Value::I32(x), Value::I32(y)) => Value::I32(op_i32(x, y)),
+        (Value::I64(x), Value::I64(y)) => Value::I64(op_i64(x, y)),
+        (Value::F64(x), Value::F64(y)) => Value::F64(op_f64(x, y)),
+        _ => unreachable!(),
+    });
+}
+
+// wasmのf64.minと同じくNaNがあればNaNを返し、-0は0より小さいとする
+fn f64_min(x: f64, y: f64) -> f64 {
+    if x.is_nan() || y.is_nan() {
+        f64::NAN
+    } else if x == y {
+        if x.is_sign_negative() {
+            x
+        } else {
+            y
         }
+    } else {
+        x.min(y)
     }
 }
 
+fn f64_max(x: f64, y: f64) -> f64 {
+    if x.is_nan() || y.is_nan() {
+        f64::NAN
+    } else if x == y {
+        if x.is_sign_positive() {
+            x
+        } else {
+            y
+        }
+    } else {
+        x.max(y)
+    }
+}
+
+// 整数の割り算はwasmと同じくtrapすることがある
+fn try_arith(
+    stack: &mut Vec<Value>,
+    op_i32: fn(i32, i32) -> Result<i32, Trap>,
+    op_i64: fn(i64, i64) -> Result<i64, Trap>,
+    op_f64: fn(f64, f64) -> f64,
+) -> Result<(), Trap> {
+    let y = stack.pop().unwrap();
+    let x = stack.pop().unwrap();
+    stack.push(match (x, y) {
+        (Value::I32(x), Value::I32(y)) => Value::I32(op_i32(x, y)?),
+        (Value::I64(x), Value::I64(y)) => Value::I64(op_i64(x, y)?),
+        (Value::F64(x), Value::F64(y)) => Value::F64(op_f64(x, y)),
+        _ => unreachable!(),
+    });
+    Ok(())
+}
+
+macro_rules! int_div {
+    ($name:ident, $rem_name:ident, $ty:ty) => {
+        fn $name(x: $ty, y: $ty) -> Result<$ty, Trap> {
+            if y == 0 {
+                Err(Trap::DivideByZero)
+            } else {
+                x.checked_div(y).ok_or(Trap::IntegerOverflow)
+            }
+        }
+
+        // MIN % -1は0
+        fn $rem_name(x: $ty, y: $ty) -> Result<$ty, Trap> {
+            if y == 0 {
+                Err(Trap::DivideByZero)
+            } else {
+                Ok(x.wrapping_rem(y))
+            }
+        }
+    };
+}
+
+int_div!(div_i32, rem_i32, i32);
+int_div!(div_i64, rem_i64, i64);
+
+fn compare(stack: &mut Vec<Value>, f: impl FnOnce(Option<Ordering>) -> bool) {
+    let y = stack.pop().unwrap();
+    let x = stack.pop().unwrap();
+    let ord = match (x, y) {
+        (Value::I32(x), Value::I32(y)) => x.partial_cmp(&y),
+        (Value::I64(x), Value::I64(y)) => x.partial_cmp(&y),
+        (Value::F64(x), Value::F64(y)) => x.partial_cmp(&y),
+        _ => unreachable!(),
+    };
+    stack.push(Value::I32(if f(ord) { 1 } else { 0 }));
+}
+
+// wasmの線形メモリに合わせたバイト列。確保したものは解放しない
 #[derive(Debug, PartialEq, Clone, Eq)]
+pub struct Memory {
+    pub bytes: Vec<u8>,
+    pub heap: u32,
+}
 
-pub struct RustBuiltin;
+impl Memory {
+    // wasmとアドレスを揃えるため、文字列定数と静的なクロージャの分を空けてヒープを始める
+    pub fn new(heap_base: u32) -> Self {
+        Memory {
+            bytes: vec![0; heap_base as usize],
+            heap: heap_base,
+        }
+    }
 
-impl Builtin for RustBuiltin {
-    fn println(&mut self, x: i32) {
-        println!("{}", x);
+    pub fn alloc(&mut self, size: u32) -> Result<u32, Trap> {
+        let ptr = self.heap;
+        self.heap = ptr
+            .checked_add(size)
+            .and_then(|heap| heap.checked_add(7))
+            .map(|heap| heap & !7)
+            .ok_or(Trap::OutOfMemory)?;
+        self.bytes.resize(self.heap as usize, 0);
+        Ok(ptr)
+    }
+
+    pub fn load(&self, ty: Type, addr: u32) -> Value {
+        let addr = addr as usize;
+        match ty {
+            Type::I32 => Value::I32(i32::from_le_bytes(
+                self.bytes[addr..addr + 4].try_into().unwrap(),
+            )),
+            Type::I64 => Value::I64(i64::from_le_bytes(
+                self.bytes[addr..addr + 8].try_into().unwrap(),
+            )),
+            Type::F64 => Value::F64(f64::from_le_bytes(
+                self.bytes[addr..addr + 8].try_into().unwrap(),
+            )),
+        }
+    }
+
+    pub fn store(&mut self, addr: u32, x: Value) {
+        let addr = addr as usize;
+        match x {
+            Value::I32(x) => self.bytes[addr..addr + 4].copy_from_slice(&x.to_le_bytes()),
+            Value::I64(x) => self.bytes[addr..addr + 8].copy_from_slice(&x.to_le_bytes()),
+            Value::F64(x) => self.bytes[addr..addr + 8].copy_from_slice(&x.to_le_bytes()),
+        }
+    }
+
+    fn field_addr(&self, addr: i32, offset: u32) -> Result<u32, Trap> {
+        if addr == 0 {
+            return Err(Trap::NullPointer);
+        }
+        Ok(addr as u32 + offset)
+    }
+
+    fn array_elem_addr(&self, ty: Type, array: i32, idx: i32) -> Result<u32, Trap> {
+        let len = self.load(Type::I32, array as u32).as_i32();
+        if idx as u32 >= len as u32 {
+            return Err(Trap::ArrayIndexOutOfBounds);
+        }
+        Ok(array as u32 + ARRAY_HEADER_SIZE + idx as u32 * ty.size())
     }
 }
 
@@ -42,27 +365,58 @@ pub struct StackFrame {
     pub base: usize,
 }
 
-#[derive(Debug, PartialEq, Clone, Eq)]
-pub struct Interpreter<'a, B: Builtin> {
+#[derive(Debug)]
+pub struct Interpreter<'a> {
     pub pc: PC,
-    pub stack: Vec<i32>,
+    pub stack: Vec<Value>,
+    pub globals: Vec<Value>,
+    pub memory: Memory,
     pub call_stack: Vec<StackFrame>,
     pub module: &'a Module,
-    pub builtin: B,
+    pub host_funcs: HostFuncs,
+    // Module::host_funcsのindex -> host_funcsのindex
+    host_func_idxs: Vec<usize>,
+    // Module::closuresのindex -> メモリ上のアドレス
+    closure_addrs: Vec<u32>,
+    // 関数の入口とループの先頭で、そこから実行しうる命令の数だけ消費する。負になったらtrapする
+    pub fuel: i64,
+    // Module::funcsのindexごと
+    fuel_costs: Vec<FuelCosts>,
 }
 
-impl<'a, B: Builtin> Interpreter<'a, B> {
-    pub fn new(module: &'a Module, builtin: B) -> Self {
-        Interpreter {
+impl<'a> Interpreter<'a> {
+    // moduleが使うホスト関数はすべて同じ型でhost_funcsに登録されていなければならない
+    pub fn new(module: &'a Module, host_funcs: HostFuncs) -> Result<Self, HostFuncError> {
+        let host_func_idxs = module
+            .host_funcs
+            .iter()
+            .map(|decl| host_funcs.resolve(decl))
+            .collect::<Result<_, _>>()?;
+        let (_, closure_addrs, heap_base) = module.data_layout();
+        let mut memory = Memory::new(heap_base);
+        for (&func, &addr) in module.closures.iter().zip(&closure_addrs) {
+            memory.store(addr, Value::I32(table_idx(func) as i32));
+        }
+        Ok(Interpreter {
             pc: PC { func: 0, instr: 0 },
             stack: Vec::new(),
+            globals: module
+                .globals
+                .iter()
+                .map(|global| Value::from(global.init))
+                .collect(),
+            memory,
             call_stack: Vec::new(),
             module,
-            builtin,
-        }
+            host_funcs,
+            host_func_idxs,
+            closure_addrs,
+            fuel: DEFAULT_FUEL,
+            fuel_costs: module.funcs.iter().map(Func::fuel_costs).collect(),
+        })
     }
 
-    pub fn step(&mut self) {
+    pub fn step(&mut self) -> Result<(), Trap> {
         let func = &self.module.funcs[self.pc.func];
         let instr = &func.instrs[self.pc.instr];
         let stack_frame = self.call_stack.last().unwrap();
@@ -70,8 +424,8 @@ impl<'a, B: Builtin> Interpreter<'a, B> {
         match instr {
             Instr::NonControl(non_control) => {
                 match non_control {
-                    NonControlInstr::IntConst(x) => {
-                        self.stack.push(*x);
+                    &NonControlInstr::Const(x) => {
+                        self.stack.push(Value::from(x));
                     }
                     NonControlInstr::VarRef(idx) => {
                         self.stack.push(self.stack[stack_frame.base + *idx]);
@@ -80,83 +434,179 @@ impl<'a, B: Builtin> Interpreter<'a, B> {
                         let x = self.stack.pop().unwrap();
                         self.stack[stack_frame.base + *idx] = x;
                     }
-                    NonControlInstr::Println => {
-                        let x = self.stack.pop().unwrap();
-                        self.builtin.println(x);
-                        self.stack.push(0);
+                    NonControlInstr::GlobalRef(idx) => {
+                        self.stack.push(self.globals[*idx]);
                     }
-                    NonControlInstr::Add => {
-                        let y = self.stack.pop().unwrap();
+                    NonControlInstr::GlobalAssign(idx) => {
                         let x = self.stack.pop().unwrap();
-                        self.stack.push(x + y);
+                        self.globals[*idx] = x;
                     }
-                    NonControlInstr::Sub => {
-                        let y = self.stack.pop().unwrap();
-                        let x = self.stack.pop().unwrap();
-                        self.stack.push(x - y);
+                    &NonControlInstr::FuncRef(idx) => {
+                        self.stack.push(Value::I32(table_idx(idx) as i32));
                     }
-                    NonControlInstr::Mul => {
-                        let y = self.stack.pop().unwrap();
-                        let x = self.stack.pop().unwrap();
-                        self.stack.push(x * y);
+                    &NonControlInstr::CallHost(idx) => {
+                        let params = &self.module.host_funcs[idx].params;
+                        let args = self
+                            .stack
+                            .split_off(self.stack.len() - params.len())
+                            .into_iter()
+                            .zip(params)
+                            .map(|(x, ty)| match (x, ty) {
+                                // 文字列はコンスタントプールのindex。ゼロ初期化された0番は空文字列
+                                (Value::I32(idx), Ty::Str) => {
+                                    HostValue::Str(&self.module.strings[idx as usize])
+                                }
+                                (Value::I32(x), _) => HostValue::I32(x),
+                                (Value::I64(x), _) => HostValue::I64(x),
+                                (Value::F64(x), _) => HostValue::F64(x),
+                            })
+                            .collect::<Vec<_>>();
+                        let host_func_idx = self.host_func_idxs[idx];
+                        let ret_val = self.host_funcs.call(host_func_idx, &args);
+                        if ret_val.ty() != self.host_funcs.ret(host_func_idx) {
+                            return Err(Trap::HostFuncTypeMismatch);
+                        }
+                        self.stack.push(ret_val);
                     }
-                    NonControlInstr::Div => {
-                        let y = self.stack.pop().unwrap();
-                        let x = self.stack.pop().unwrap();
-                        self.stack.push(x / y);
+                    &NonControlInstr::StringConst(idx) => {
+                        self.stack.push(Value::I32(idx as i32));
                     }
-                    NonControlInstr::Mod => {
-                        let y = self.stack.pop().unwrap();
-                        let x = self.stack.pop().unwrap();
-                        self.stack.push(x % y);
+                    &NonControlInstr::ClosureConst(idx) => {
+                        self.stack.push(Value::I32(self.closure_addrs[idx] as i32));
                     }
-                    NonControlInstr::Lt => {
-                        let y = self.stack.pop().unwrap();
-                        let x = self.stack.pop().unwrap();
-                        self.stack.push(if x < y { 1 } else { 0 });
+                    // 整数はwasmと同じくオーバーフローしたら折り返す
+                    NonControlInstr::Add(_) => {
+                        arith(
+                            &mut self.stack,
+                            i32::wrapping_add,
+                            i64::wrapping_add,
+                            f64::add,
+                        );
                     }
-                    NonControlInstr::Gt => {
-                        let y = self.stack.pop().unwrap();
-                        let x = self.stack.pop().unwrap();
-                        self.stack.push(if x > y { 1 } else { 0 });
+                    NonControlInstr::Sub(_) => {
+                        arith(
+                            &mut self.stack,
+                            i32::wrapping_sub,
+                            i64::wrapping_sub,
+                            f64::sub,
+                        );
                     }
-                    NonControlInstr::Le => {
-                        let y = self.stack.pop().unwrap();
-                        let x = self.stack.pop().unwrap();
-                        self.stack.push(if x <= y { 1 } else { 0 });
+                    NonControlInstr::Mul(_) => {
+                        arith(
+                            &mut self.stack,
+                            i32::wrapping_mul,
+                            i64::wrapping_mul,
+                            f64::mul,
+                        );
                     }
-                    NonControlInstr::Ge => {
-                        let y = self.stack.pop().unwrap();
-                        let x = self.stack.pop().unwrap();
-                        self.stack.push(if x >= y { 1 } else { 0 });
+                    NonControlInstr::Div(_) => {
+                        try_arith(&mut self.stack, div_i32, div_i64, f64::div)?;
                     }
-                    NonControlInstr::Eq => {
-                        let y = self.stack.pop().unwrap();
-                        let x = self.stack.pop().unwrap();
-                        self.stack.push(if x == y { 1 } else { 0 });
+                    // 浮動小数点数の剰余は型検査で弾いている
+                    NonControlInstr::Mod(_) => {
+                        try_arith(&mut self.stack, rem_i32, rem_i64, |_, _| unreachable!())?;
+                    }
+                    NonControlInstr::Lt(_) => {
+                        compare(&mut self.stack, |ord| ord == Some(Ordering::Less));
                     }
-                    NonControlInstr::Ne => {
-                        let y = self.stack.pop().unwrap();
+                    NonControlInstr::Gt(_) => {
+                        compare(&mut self.stack, |ord| ord == Some(Ordering::Greater));
+                    }
+                    NonControlInstr::Le(_) => {
+                        compare(&mut self.stack, |ord| {
+                            matches!(ord, Some(Ordering::Less | Ordering::Equal))
+                        });
+                    }
+                    NonControlInstr::Ge(_) => {
+                        compare(&mut self.stack, |ord| {
+                            matches!(ord, Some(Ordering::Greater | Ordering::Equal))
+                        });
+                    }
+                    NonControlInstr::Eq(_) => {
+                        compare(&mut self.stack, |ord| ord == Some(Ordering::Equal));
+                    }
+                    NonControlInstr::Ne(_) => {
+                        compare(&mut self.stack, |ord| ord != Some(Ordering::Equal));
+                    }
+                    NonControlInstr::Not => {
+                        let x = self.stack.pop().unwrap().as_i32();
+                        self.stack.push(Value::I32(if x == 0 { 1 } else { 0 }));
+                    }
+                    NonControlInstr::Minus(_) => {
                         let x = self.stack.pop().unwrap();
-                        self.stack.push(if x != y { 1 } else { 0 });
+                        self.stack.push(match x {
+                            Value::I32(x) => Value::I32(x.wrapping_neg()),
+                            Value::I64(x) => Value::I64(x.wrapping_neg()),
+                            Value::F64(x) => Value::F64(-x),
+                        });
                     }
-                    NonControlInstr::And => {
-                        let y = self.stack.pop().unwrap();
+                    NonControlInstr::Abs(_) => {
                         let x = self.stack.pop().unwrap();
-                        self.stack.push(if x != 0 && y != 0 { 1 } else { 0 });
+                        self.stack.push(match x {
+                            Value::I32(x) => Value::I32(x.wrapping_abs()),
+                            Value::I64(x) => Value::I64(x.wrapping_abs()),
+                            Value::F64(x) => Value::F64(x.abs()),
+                        });
+                    }
+                    NonControlInstr::Min(_) => {
+                        arith(&mut self.stack, i32::min, i64::min, f64_min);
+                    }
+                    NonControlInstr::Max(_) => {
+                        arith(&mut self.stack, i32::max, i64::max, f64_max);
                     }
-                    NonControlInstr::Or => {
-                        let y = self.stack.pop().unwrap();
+                    NonControlInstr::Assert => {
+                        let cond = self.stack.pop().unwrap().as_i32();
+                        if cond == 0 {
+                            return Err(Trap::AssertionFailed);
+                        }
+                        self.stack.push(Value::I32(0));
+                    }
+                    &NonControlInstr::Convert { to, .. } => {
                         let x = self.stack.pop().unwrap();
-                        self.stack.push(if x != 0 || y != 0 { 1 } else { 0 });
+                        self.stack.push(x.convert(to)?);
                     }
-                    NonControlInstr::Not => {
+                    &NonControlInstr::ArrayNew(ty) => {
+                        let len = self.stack.pop().unwrap().as_i32();
+                        if len as u32 > max_array_len(ty) {
+                            return Err(Trap::InvalidArrayLength);
+                        }
+                        let array = self
+                            .memory
+                            .alloc(ARRAY_HEADER_SIZE + len as u32 * ty.size())?;
+                        self.memory.store(array, Value::I32(len));
+                        self.stack.push(Value::I32(array as i32));
+                    }
+                    &NonControlInstr::ArrayGet(ty) => {
+                        let idx = self.stack.pop().unwrap().as_i32();
+                        let array = self.stack.pop().unwrap().as_i32();
+                        let addr = self.memory.array_elem_addr(ty, array, idx)?;
+                        self.stack.push(self.memory.load(ty, addr));
+                    }
+                    &NonControlInstr::ArraySet(ty) => {
                         let x = self.stack.pop().unwrap();
-                        self.stack.push(if x == 0 { 1 } else { 0 });
+                        let idx = self.stack.pop().unwrap().as_i32();
+                        let array = self.stack.pop().unwrap().as_i32();
+                        let addr = self.memory.array_elem_addr(ty, array, idx)?;
+                        self.memory.store(addr, x);
+                    }
+                    NonControlInstr::ArrayLen => {
+                        let array = self.stack.pop().unwrap().as_i32();
+                        self.stack.push(self.memory.load(Type::I32, array as u32));
                     }
-                    NonControlInstr::Minus => {
+                    &NonControlInstr::Alloc(size) => {
+                        let addr = self.memory.alloc(size)?;
+                        self.stack.push(Value::I32(addr as i32));
+                    }
+                    &NonControlInstr::Load { ty, offset } => {
+                        let addr = self.stack.pop().unwrap().as_i32();
+                        let addr = self.memory.field_addr(addr, offset)?;
+                        self.stack.push(self.memory.load(ty, addr));
+                    }
+                    &NonControlInstr::Store { offset, .. } => {
                         let x = self.stack.pop().unwrap();
-                        self.stack.push(-x);
+                        let addr = self.stack.pop().unwrap().as_i32();
+                        let addr = self.memory.field_addr(addr, offset)?;
+                        self.memory.store(addr, x);
                     }
                     NonControlInstr::Drop => {
                         self.stack.pop();
@@ -164,24 +614,20 @@ impl<'a, B: Builtin> Interpreter<'a, B> {
                 }
                 self.pc.instr += 1;
             }
-            Instr::Call { func, args_count } => {
-                self.call_stack.push(StackFrame {
-                    pc: {
-                        let mut pc = self.pc.clone();
-                        pc.instr += 1;
-                        pc
-                    },
-                    base: self.stack.len() - *args_count,
-                });
-                self.stack
-                    .extend((0..self.module.funcs[*func].locals_count).map(|_| 0));
-                self.pc = PC {
-                    func: *func,
-                    instr: 0,
-                };
+            &Instr::Call { func, args_count } => {
+                self.enter_func(func, args_count)?;
+            }
+            Instr::CallIndirect(signature) => {
+                let callee = table_func(self.stack.pop().unwrap().as_i32() as u32)
+                    .filter(|&func| func < self.module.funcs.len())
+                    .ok_or(Trap::NullFunctionCall)?;
+                if self.module.funcs[callee].signature() != *signature {
+                    return Err(Trap::IndirectCallTypeMismatch);
+                }
+                self.enter_func(callee, signature.params.len())?;
             }
             &Instr::If(if_id) => {
-                let x = self.stack.pop().unwrap();
+                let x = self.stack.pop().unwrap().as_i32();
                 if x != 0 {
                     self.pc.instr += 1;
                 } else {
@@ -194,11 +640,28 @@ impl<'a, B: Builtin> Interpreter<'a, B> {
             &Instr::IfEnd(_) => {
                 self.pc.instr += 1;
             }
-            &Instr::Loop(_) => {
+            &Instr::Switch(switch_id) => {
+                let x = match self.stack.pop().unwrap() {
+                    Value::I32(x) => x as i64,
+                    Value::I64(x) => x,
+                    Value::F64(_) => unreachable!(),
+                };
+                let switch_info = &func.switch_infos[switch_id];
+                self.pc.instr = switch_info.cases[switch_info.case(x)] + 1;
+            }
+            &Instr::Case(switch_id, _) => {
+                self.pc.instr = func.switch_infos[switch_id].switch_end + 1;
+            }
+            &Instr::SwitchEnd(_) => {
+                self.pc.instr += 1;
+            }
+            // continueもここに戻ってくる
+            &Instr::Loop(loop_id) => {
+                self.consume_fuel(self.fuel_costs[self.pc.func].loops[loop_id])?;
                 self.pc.instr += 1;
             }
             &Instr::LoopThen(loop_id) => {
-                let x = self.stack.pop().unwrap();
+                let x = self.stack.pop().unwrap().as_i32();
                 if x != 0 {
                     self.pc.instr += 1;
                 } else {
@@ -208,6 +671,18 @@ impl<'a, B: Builtin> Interpreter<'a, B> {
             &Instr::LoopEnd(loop_id) => {
                 self.pc.instr = func.loop_infos[loop_id].loop_;
             }
+            &Instr::Break(loop_id) => {
+                let loop_info = &func.loop_infos[loop_id];
+                self.stack
+                    .truncate(stack_frame.base + func.locals.len() + loop_info.depth);
+                self.pc.instr = loop_info.loop_end + 1;
+            }
+            &Instr::Continue(loop_id) => {
+                let loop_info = &func.loop_infos[loop_id];
+                self.stack
+                    .truncate(stack_frame.base + func.locals.len() + loop_info.depth);
+                self.pc.instr = loop_info.loop_;
+            }
             Instr::Return => {
                 let ret_val = self.stack.pop().unwrap();
                 self.pc = stack_frame.pc.clone();
@@ -216,13 +691,46 @@ impl<'a, B: Builtin> Interpreter<'a, B> {
                 self.stack.push(ret_val);
             }
         }
+        Ok(())
+    }
+
+    // コンパイルしたコードと消費量が一致するようにする
+    fn consume_fuel(&mut self, amount: i64) -> Result<(), Trap> {
+        self.fuel = self.fuel.wrapping_sub(amount);
+        if self.fuel < 0 {
+            return Err(Trap::OutOfFuel);
+        }
+        Ok(())
+    }
+
+    // 引数は既にスタックに積まれている
+    // 燃料は呼び出された側で消費する
+    fn enter_func(&mut self, func: usize, args_count: usize) -> Result<(), Trap> {
+        if self.call_stack.len() >= MAX_CALL_DEPTH {
+            return Err(Trap::StackOverflow);
+        }
+        self.call_stack.push(StackFrame {
+            pc: {
+                let mut pc = self.pc.clone();
+                pc.instr += 1;
+                pc
+            },
+            base: self.stack.len() - args_count,
+        });
+        self.stack.extend(
+            self.module.funcs[func].locals[args_count..]
+                .iter()
+                .map(|ty| Value::zero(*ty)),
+        );
+        self.pc = PC { func, instr: 0 };
+        self.consume_fuel(self.fuel_costs[func].entry)
     }
 
     pub fn dummy_func(&self) -> usize {
         self.module.funcs.len()
     }
 
-    pub fn call_prepare(&mut self, func: usize, args: &[i32]) {
+    pub fn call_prepare(&mut self, func: usize, args: &[Value]) -> Result<(), Trap> {
         self.call_stack.push(StackFrame {
             pc: PC {
                 func: self.dummy_func(),
@@ -232,11 +740,15 @@ impl<'a, B: Builtin> Interpreter<'a, B> {
         });
         self.pc = PC { func, instr: 0 };
         self.stack.extend(args.iter().cloned());
-        self.stack
-            .extend((0..self.module.funcs[func].locals_count).map(|_| 0));
+        self.stack.extend(
+            self.module.funcs[func].locals[args.len()..]
+                .iter()
+                .map(|ty| Value::zero(*ty)),
+        );
+        self.consume_fuel(self.fuel_costs[func].entry)
     }
 
-    pub fn call_result(&mut self) -> Option<i32> {
+    pub fn call_result(&mut self) -> Option<Value> {
         if self.pc.func == self.dummy_func() {
             Some(self.stack.pop().unwrap())
         } else {
@@ -244,13 +756,79 @@ impl<'a, B: Builtin> Interpreter<'a, B> {
         }
     }
 
-    pub fn call(&mut self, func: usize, args: &[i32]) -> i32 {
-        self.call_prepare(func, args);
+    // 各呼び出しの戻り先は呼び出し命令の次を指している
+    pub fn backtrace(&self) -> Backtrace {
+        let mut frames = vec![Frame::new(self.module, self.pc.func, Some(self.pc.instr))];
+        for stack_frame in self.call_stack.iter().rev() {
+            if stack_frame.pc.func != self.dummy_func() {
+                frames.push(Frame::new(
+                    self.module,
+                    stack_frame.pc.func,
+                    Some(stack_frame.pc.instr - 1),
+                ));
+            }
+        }
+        Backtrace { frames }
+    }
+
+    // trapしたら呼び出す前の状態に戻す
+    pub fn call(&mut self, func: usize, args: &[Value]) -> Result<Value, RuntimeError> {
+        let pc = self.pc.clone();
+        let stack_len = self.stack.len();
+        let call_stack_len = self.call_stack.len();
+        let result = self.call_prepare(func, args).and_then(|()| self.run());
+        result.map_err(|trap| {
+            let backtrace = self.backtrace();
+            self.pc = pc;
+            self.stack.truncate(stack_len);
+            self.call_stack.truncate(call_stack_len);
+            RuntimeError { trap, backtrace }
+        })
+    }
+
+    fn run(&mut self) -> Result<Value, Trap> {
         loop {
-            self.step();
+            self.step()?;
             if let Some(ret_val) = self.call_result() {
-                return ret_val;
+                return Ok(ret_val);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trap_codes_round_trip() {
+        for trap in Trap::ALL {
+            assert_eq!(Trap::from_code(trap.code()), Some(trap));
+        }
+        assert_eq!(Trap::from_code(0), None);
+        assert_eq!(Trap::from_code(Trap::ALL.len() as i32 + 1), None);
+    }
+
+    #[test]
+    fn truncation_range() {
+        let range = i32::MIN as f64..i32::MAX as f64 + 1.0;
+        assert_eq!(check_truncate(-2147483648.9, range.clone()), Ok(()));
+        assert_eq!(check_truncate(2147483647.9, range.clone()), Ok(()));
+        assert_eq!(
+            check_truncate(2147483648.0, range.clone()),
+            Err(Trap::IntegerOverflow)
+        );
+        assert_eq!(
+            check_truncate(f64::NAN, range),
+            Err(Trap::InvalidConversion)
+        );
+    }
+
+    #[test]
+    fn f64_min_max_follow_wasm() {
+        assert!(f64_min(f64::NAN, 1.0).is_nan());
+        assert!(f64_max(1.0, f64::NAN).is_nan());
+        assert!(f64_min(0.0, -0.0).is_sign_negative());
+        assert!(f64_max(-0.0, 0.0).is_sign_positive());
+    }
+}
@@ -1,6 +1,115 @@
+use crate::layout;
+use crate::span::Span;
+use crate::types::{Ty, Type};
+
+// 0番地をnullとして使えるように文字列定数とヒープは8番地から配置する
+pub const DATA_BASE: u32 = 8;
+// 配列は先頭に長さ(i32)を持ち、その後に要素が並ぶ。要素の境界を揃えるため8バイト
+pub const ARRAY_HEADER_SIZE: u32 = 8;
+
+// 燃料の初期値。実質的に無制限
+pub const DEFAULT_FUEL: i64 = i64::MAX;
+
+// 確保するバイト数がi32に収まるようにする
+pub fn max_array_len(ty: Type) -> u32 {
+    (i32::MAX as u32 - ARRAY_HEADER_SIZE - 7) / ty.size()
+}
+
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
 pub struct Module {
+    // 関数モジュールはこの順でimportする
+    pub host_funcs: Vec<HostFunc>,
+    pub globals: Vec<Global>,
     pub funcs: Vec<Func>,
+    // 文字列定数のコンスタントプール。0番は空文字列
+    pub strings: Vec<String>,
+    // 値として使う関数ごとに1つだけ置く、捕捉する変数のないクロージャ
+    // 要素はクロージャの呼び出し規約に合わせた関数
+    pub closures: Vec<usize>,
+    // Span::fileに対応するパス
+    pub files: Vec<String>,
+}
+
+impl Module {
+    // 文字列定数のアドレス、静的なクロージャのアドレスとヒープの先頭
+    // 文字列定数は配列と同じく先頭に長さを持つ
+    pub fn data_layout(&self) -> (Vec<u32>, Vec<u32>, u32) {
+        let mut addr = DATA_BASE;
+        let string_addrs = self
+            .strings
+            .iter()
+            .map(|s| {
+                let string_addr = addr;
+                addr = align(addr + ARRAY_HEADER_SIZE + s.len() as u32);
+                string_addr
+            })
+            .collect();
+        let closure_addrs = self
+            .closures
+            .iter()
+            .map(|_| {
+                let closure_addr = addr;
+                addr = align(addr + layout::closure_layout(&[]).1);
+                closure_addr
+            })
+            .collect();
+        (string_addrs, closure_addrs, addr)
+    }
+}
+
+pub fn align(addr: u32) -> u32 {
+    (addr + 7) & !7
+}
+
+// 関数のテーブル上のindex。0番は空けておき、不正なindexの呼び出しがtrapするようにする
+pub fn table_idx(func: usize) -> u32 {
+    func as u32 + 1
+}
+
+// nullならNone
+pub fn table_func(table_idx: u32) -> Option<usize> {
+    (table_idx as usize).checked_sub(1)
+}
+
+// 埋め込み側が登録した関数。引数はスカラーかstr
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+pub struct HostFunc {
+    pub name: String,
+    pub params: Vec<Ty>,
+    pub ret: Type,
+}
+
+impl HostFunc {
+    pub fn signature(&self) -> Signature {
+        Signature {
+            params: self.params.iter().map(|ty| ty.repr()).collect(),
+            ret: self.ret,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+pub struct Global {
+    pub name: String,
+    pub init: Const,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Hash, Eq)]
+pub enum Const {
+    I32(i32),
+    I64(i64),
+    // f64のビット列
+    F64(u64),
+}
+
+impl Const {
+    pub fn ty(&self) -> Type {
+        match self {
+            Const::I32(_) => Type::I32,
+            Const::I64(_) => Type::I64,
+            Const::F64(_) => Type::F64,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
@@ -8,6 +117,7 @@ pub struct IfInfo {
     pub if_: usize,
     pub else_: usize,
     pub if_end: usize,
+    pub ty: Type,
 }
 
 impl IfInfo {
@@ -16,6 +126,7 @@ impl IfInfo {
             if_: 0,
             else_: 0,
             if_end: 0,
+            ty: Type::I32,
         }
     }
 }
@@ -25,6 +136,8 @@ pub struct LoopInfo {
     pub loop_: usize,
     pub loop_then: usize,
     pub loop_end: usize,
+    // ループに入る時点でローカル変数の上に積まれている値の数
+    pub depth: usize,
 }
 
 impl LoopInfo {
@@ -33,6 +146,46 @@ impl LoopInfo {
             loop_: 0,
             loop_then: 0,
             loop_end: 0,
+            depth: 0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+pub struct SwitchInfo {
+    // 各腕のCaseの位置。最後は`_`の腕
+    pub cases: Vec<usize>,
+    pub switch_end: usize,
+    pub ty: Type,
+    // 比較する値の型。i32かi64
+    pub scrutinee_ty: Type,
+    pub min: i64,
+    // 値 - min -> 腕のindex
+    pub table: Vec<usize>,
+}
+
+impl SwitchInfo {
+    pub fn dummy() -> Self {
+        SwitchInfo {
+            cases: Vec::new(),
+            switch_end: 0,
+            ty: Type::I32,
+            scrutinee_ty: Type::I32,
+            min: 0,
+            table: Vec::new(),
+        }
+    }
+
+    pub fn default_case(&self) -> usize {
+        self.cases.len() - 1
+    }
+
+    pub fn case(&self, x: i64) -> usize {
+        let idx = x.wrapping_sub(self.min) as u64;
+        if idx < self.table.len() as u64 {
+            self.table[idx as usize]
+        } else {
+            self.default_case()
         }
     }
 }
@@ -40,49 +193,149 @@ impl LoopInfo {
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
 pub struct Func {
     pub args_count: usize,
-    pub locals_count: usize,
+    // 引数を含む
+    pub locals: Vec<Type>,
+    pub ret: Type,
     pub instrs: Vec<Instr>,
     pub if_infos: Vec<IfInfo>,
     pub loop_infos: Vec<LoopInfo>,
+    pub switch_infos: Vec<SwitchInfo>,
     pub name: String,
+    // (命令のindex, その命令以降を生成した式のSpan)。indexの昇順
+    pub spans: Vec<(usize, Span)>,
+}
+
+impl Func {
+    pub fn signature(&self) -> Signature {
+        Signature {
+            params: self.locals[..self.args_count].to_vec(),
+            ret: self.ret,
+        }
+    }
+
+    // コンパイラが生成した命令にはない
+    pub fn span_at(&self, instr: usize) -> Option<Span> {
+        self.spans
+            .iter()
+            .rev()
+            .find(|&&(start, _)| start <= instr)
+            .map(|&(_, span)| span)
+    }
+
+    // 次に燃料を消費する位置までにある命令の数。ネストしたループの中の命令は含めない
+    // 分岐のどちらを通っても同じだけ消費するので、インタプリタとJITで消費量が変わらない
+    pub fn fuel_costs(&self) -> FuelCosts {
+        let mut costs = FuelCosts {
+            entry: 0,
+            loops: vec![0; self.loop_infos.len()],
+        };
+        let mut loops: Vec<LoopId> = Vec::new();
+        for instr in &self.instrs {
+            let cost = match loops.last() {
+                Some(&loop_id) => &mut costs.loops[loop_id],
+                None => &mut costs.entry,
+            };
+            *cost += 1;
+            match *instr {
+                Instr::Loop(loop_id) => loops.push(loop_id),
+                Instr::LoopEnd(_) => {
+                    loops.pop();
+                }
+                _ => {}
+            }
+        }
+        costs
+    }
+}
+
+// 関数の入口とループの先頭で消費する燃料
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+pub struct FuelCosts {
+    pub entry: i64,
+    // LoopIdごと
+    pub loops: Vec<i64>,
+}
+
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+pub struct Signature {
+    pub params: Vec<Type>,
+    pub ret: Type,
 }
 
 pub type LoopId = usize;
 pub type IfId = usize;
+pub type SwitchId = usize;
 
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
 pub enum Instr {
     Call { func: usize, args_count: usize },
+    // [args..., callee] -> [ret]。calleeのシグネチャが異なればtrapする
+    CallIndirect(Signature),
     If(IfId),
     Else(IfId),
     IfEnd(IfId),
+    // [x] -> []。xに対応する腕のCaseの次に飛ぶ
+    Switch(SwitchId),
+    // 前の腕の終わりから到達したらSwitchEndの次に飛ぶ
+    Case(SwitchId, usize),
+    SwitchEnd(SwitchId),
     Loop(LoopId),
     LoopThen(LoopId),
     LoopEnd(LoopId),
+    Break(LoopId),
+    Continue(LoopId),
     Return,
     NonControl(NonControlInstr),
 }
 
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
 pub enum NonControlInstr {
-    IntConst(i32),
+    Const(Const),
     VarRef(usize),
     Assign(usize),
-    Println,
-    Add,
-    Sub,
-    Mul,
-    Div,
-    Mod,
-    Lt,
-    Gt,
-    Le,
-    Ge,
-    Eq,
-    Ne,
-    And,
-    Or,
+    GlobalRef(usize),
+    GlobalAssign(usize),
+    // [] -> [table_idx(func)]
+    FuncRef(usize),
+    // [args...] -> [ret]。Module::host_funcsのindex
+    CallHost(usize),
+    // コンスタントプールのindex
+    StringConst(usize),
+    // [] -> [クロージャのアドレス]。Module::closuresのindex
+    ClosureConst(usize),
+    Add(Type),
+    Sub(Type),
+    Mul(Type),
+    Div(Type),
+    Mod(Type),
+    Lt(Type),
+    Gt(Type),
+    Le(Type),
+    Ge(Type),
+    Eq(Type),
+    Ne(Type),
     Not,
-    Minus,
+    Minus(Type),
+    // 整数の最小値はそのまま
+    Abs(Type),
+    Min(Type),
+    Max(Type),
+    // [cond] -> [0]。condが0ならtrapする
+    Assert,
+    Convert { from: Type, to: Type },
+    // [len] -> [array]
+    ArrayNew(Type),
+    // [array, idx] -> [value]
+    ArrayGet(Type),
+    // [array, idx, value] -> []
+    ArraySet(Type),
+    // [array] -> [len]
+    ArrayLen,
+    // [] -> [addr]
+    Alloc(u32),
+    // [addr] -> [value]。addrが0ならtrapする
+    Load { ty: Type, offset: u32 },
+    // [addr, value] -> []。addrが0ならtrapする
+    Store { ty: Type, offset: u32 },
     Drop,
 }
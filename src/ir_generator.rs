@@ -1,13 +1,35 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 
 use crate::ast;
+use crate::error::CompileError;
+use crate::host;
 use crate::ir::*;
+use crate::layout::{self, StructLayout};
+use crate::span::Span;
+use crate::types::{Ty, Type};
 
 #[derive(Debug, PartialEq, Clone, Eq)]
 
 struct IrGenerator<'a> {
     module: &'a ast::Module,
+    layouts: &'a HashMap<String, StructLayout>,
+    host_funcs: &'a [HostFunc],
     func_refs: HashMap<String, FuncRef>,
+    global_refs: HashMap<String, usize>,
+    // 同じ内容の文字列リテラルは1つにまとめる
+    strings: RefCell<Vec<String>>,
+    // ラムダ式などのために追加した関数。ユーザー定義の関数の後ろに並ぶ
+    lifted_funcs: RefCell<Vec<Func>>,
+    // 関数 -> Module::closuresのindex
+    closure_idxs: RefCell<HashMap<usize, usize>>,
+    closures: RefCell<Vec<usize>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+enum VarRef {
+    Local(usize),
+    Global(usize),
 }
 
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
@@ -15,241 +37,1115 @@ struct IrGenerator<'a> {
 enum FuncRef {
     UserDefined { idx: usize },
     Builtin { kind: BuiltinFunc },
+    Host { idx: usize },
 }
 
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
 enum BuiltinFunc {
     Println,
+    Array,
+    Len,
+    Abs,
+    Min,
+    Max,
+    Assert,
+}
+
+impl BuiltinFunc {
+    fn args_count(&self) -> usize {
+        match self {
+            BuiltinFunc::Println => 1,
+            BuiltinFunc::Array => 1,
+            BuiltinFunc::Len => 1,
+            BuiltinFunc::Abs => 1,
+            BuiltinFunc::Min => 2,
+            BuiltinFunc::Max => 2,
+            BuiltinFunc::Assert => 1,
+        }
+    }
 }
 
 impl<'a> IrGenerator<'a> {
-    fn new(module: &'a ast::Module) -> Option<Self> {
+    fn new(
+        module: &'a ast::Module,
+        layouts: &'a HashMap<String, StructLayout>,
+        host_funcs: &'a [HostFunc],
+    ) -> Result<Self, CompileError> {
         let mut func_refs = HashMap::new();
 
-        func_refs.insert(
-            "println".to_string(),
-            FuncRef::Builtin {
-                kind: BuiltinFunc::Println,
-            },
-        );
+        for (i, host_func) in host_funcs.iter().enumerate() {
+            func_refs.insert(host_func.name.clone(), FuncRef::Host { idx: i });
+        }
+
+        // printlnは引数の型に応じたホスト関数を呼び出すので、同じ名前のホスト関数より優先する
+        for (name, kind) in [
+            ("println", BuiltinFunc::Println),
+            ("array", BuiltinFunc::Array),
+            ("len", BuiltinFunc::Len),
+            ("abs", BuiltinFunc::Abs),
+            ("min", BuiltinFunc::Min),
+            ("max", BuiltinFunc::Max),
+            ("assert", BuiltinFunc::Assert),
+        ] {
+            func_refs.insert(name.to_string(), FuncRef::Builtin { kind });
+        }
 
+        // ユーザー定義の関数は組み込み関数やホスト関数をシャドウイングする
+        let mut user_funcs = HashSet::new();
         for (i, func) in module.funcs.iter().enumerate() {
-            match func_refs.insert(func.name.clone(), FuncRef::UserDefined { idx: i }) {
-                None => (),
-                _ => return None,
+            if !user_funcs.insert(&func.name) {
+                return Err(CompileError::DuplicateFunc {
+                    name: func.name.clone(),
+                    span: func.span,
+                });
             }
+            func_refs.insert(func.name.clone(), FuncRef::UserDefined { idx: i });
         }
 
-        Some(IrGenerator { module, func_refs })
+        let mut global_refs = HashMap::new();
+
+        for (i, global) in module.globals.iter().enumerate() {
+            if global_refs.insert(global.name.clone(), i).is_some() {
+                return Err(CompileError::DuplicateGlobal {
+                    name: global.name.clone(),
+                    span: global.span,
+                });
+            }
+        }
+
+        Ok(IrGenerator {
+            module,
+            layouts,
+            host_funcs,
+            func_refs,
+            global_refs,
+            // 0番はゼロ初期化された文字列と同じ空文字列にする
+            strings: RefCell::new(vec![String::new()]),
+            lifted_funcs: RefCell::new(Vec::new()),
+            closure_idxs: RefCell::new(HashMap::new()),
+            closures: RefCell::new(Vec::new()),
+        })
     }
 
-    fn generate(&self) -> Module {
+    fn generate(&self) -> Result<Module, CompileError> {
+        let mut globals = Vec::new();
+        for global in &self.module.globals {
+            globals.push(Global {
+                name: global.name.clone(),
+                init: Self::const_value(&global.init)?,
+            });
+        }
+
         let mut funcs = Vec::new();
         for i in 0..self.module.funcs.len() {
-            funcs.push(self.gen_func(i));
+            funcs.push(self.gen_func(i)?);
+        }
+        funcs.extend(self.lifted_funcs.take());
+
+        Ok(Module {
+            host_funcs: self.host_funcs.to_vec(),
+            globals,
+            funcs,
+            strings: self.strings.take(),
+            closures: self.closures.take(),
+            files: Vec::new(),
+        })
+    }
+
+    fn const_value(expr: &ast::Expr) -> Result<Const, CompileError> {
+        match &expr.kind {
+            &ast::ExprKind::IntLiteral(x, _) => Ok(Self::int_const(x as i128, expr.ty().repr())),
+            &ast::ExprKind::FloatLiteral(x) => Ok(Const::F64(x)),
+            ast::ExprKind::PrefixOp(ast::PrefixOp::Minus, operand) => {
+                match Self::const_value(operand)? {
+                    Const::I32(x) => Ok(Self::int_const(-(x as i128), expr.ty().repr())),
+                    Const::I64(x) => Ok(Self::int_const(-(x as i128), expr.ty().repr())),
+                    Const::F64(x) => Ok(Const::F64((-f64::from_bits(x)).to_bits())),
+                }
+            }
+            _ => Err(CompileError::NonConstGlobalInit { span: expr.span }),
         }
+    }
 
-        Module { funcs }
+    // 範囲は型検査で確認済み
+    fn int_const(x: i128, ty: Type) -> Const {
+        match ty {
+            Type::I32 => Const::I32(x as i32),
+            Type::I64 => Const::I64(x as i64),
+            Type::F64 => unreachable!(),
+        }
     }
-    fn gen_func(&self, idx: usize) -> Func {
-        let mut state = GenFuncState::new();
 
+    fn field_offset(&self, operand: &ast::Expr, name: &str) -> u32 {
+        match operand.ty() {
+            Ty::Struct(struct_name) => self.layouts[struct_name].fields[name].0,
+            // 値を返さない式のフィールドには到達しない
+            _ => 0,
+        }
+    }
+
+    fn string_const(&self, s: &str) -> usize {
+        let mut strings = self.strings.borrow_mut();
+        match strings.iter().position(|x| x == s) {
+            Some(idx) => idx,
+            None => {
+                strings.push(s.to_string());
+                strings.len() - 1
+            }
+        }
+    }
+
+    fn is_var(&self, state: &GenFuncState, name: &str) -> bool {
+        state.locals.contains_key(name) || self.global_refs.contains_key(name)
+    }
+
+    // ローカル変数はグローバル変数をシャドウイングする
+    fn var_ref(
+        &self,
+        state: &GenFuncState,
+        name: &str,
+        span: &Span,
+    ) -> Result<VarRef, CompileError> {
+        if let Some(&idx) = state.locals.get(name) {
+            Ok(VarRef::Local(idx))
+        } else if let Some(&idx) = self.global_refs.get(name) {
+            Ok(VarRef::Global(idx))
+        } else {
+            Err(CompileError::UnknownIdent {
+                name: name.to_string(),
+                span: *span,
+            })
+        }
+    }
+    fn gen_func(&self, idx: usize) -> Result<Func, CompileError> {
         let func = &self.module.funcs[idx];
 
-        for name in &func.args {
-            state.add_local(name.clone());
+        let mut state = GenFuncState::new(func.name.clone());
+
+        for (name, ty) in &func.args {
+            state.add_local(name.clone(), ty.repr());
         }
 
-        self.gen_expr(&mut state, &func.body);
+        self.gen_expr(&mut state, &func.body)?;
         state.instrs.push(Instr::Return);
 
-        Func {
+        Ok(state.into_func(func.args.len(), func.ret.repr()))
+    }
+
+    fn add_lifted_func(&self, func: Func) -> usize {
+        let mut lifted_funcs = self.lifted_funcs.borrow_mut();
+        lifted_funcs.push(func);
+        self.module.funcs.len() + lifted_funcs.len() - 1
+    }
+
+    // 関数を値として使うときの静的なクロージャ。同じ関数なら同じアドレスになる
+    // クロージャの呼び出し規約では最後の引数にクロージャ自身を渡すので、それを捨てる関数を挟む
+    fn static_closure(&self, func_idx: usize) -> usize {
+        if let Some(&idx) = self.closure_idxs.borrow().get(&func_idx) {
+            return idx;
+        }
+
+        let func = &self.module.funcs[func_idx];
+        let mut state = GenFuncState::new(format!("{}$closure", func.name));
+        for (name, ty) in &func.args {
+            let idx = state.add_local(name.clone(), ty.repr());
+            state
+                .instrs
+                .push(Instr::NonControl(NonControlInstr::VarRef(idx)));
+        }
+        state.add_temp_local(Type::I32);
+        state.instrs.push(Instr::Call {
+            func: func_idx,
             args_count: func.args.len(),
-            locals_count: state.locals_count,
-            instrs: state.instrs,
-            if_infos: state.if_infos,
-            loop_infos: state.loop_infos,
-            name: func.name.clone(),
+        });
+        state.instrs.push(Instr::Return);
+
+        let adapter = self.add_lifted_func(state.into_func(func.args.len() + 1, func.ret.repr()));
+        let mut closures = self.closures.borrow_mut();
+        closures.push(adapter);
+        let idx = closures.len() - 1;
+        self.closure_idxs.borrow_mut().insert(func_idx, idx);
+        idx
+    }
+
+    // 捕捉した変数の値をコピーしたクロージャを確保する
+    fn gen_closure(&self, state: &mut GenFuncState, func_idx: usize, captures: &[usize]) {
+        let capture_types = captures
+            .iter()
+            .map(|&idx| state.local_types[idx])
+            .collect::<Vec<_>>();
+        let (offsets, size) = layout::closure_layout(&capture_types);
+        let local_idx = state.add_temp_local(Type::I32);
+        state
+            .instrs
+            .push(Instr::NonControl(NonControlInstr::Alloc(size)));
+        state
+            .instrs
+            .push(Instr::NonControl(NonControlInstr::Assign(local_idx)));
+        state
+            .instrs
+            .push(Instr::NonControl(NonControlInstr::VarRef(local_idx)));
+        state
+            .instrs
+            .push(Instr::NonControl(NonControlInstr::FuncRef(func_idx)));
+        state.instrs.push(Instr::NonControl(NonControlInstr::Store {
+            ty: Type::I32,
+            offset: 0,
+        }));
+        for ((&capture, offset), ty) in captures.iter().zip(offsets).zip(capture_types) {
+            state
+                .instrs
+                .push(Instr::NonControl(NonControlInstr::VarRef(local_idx)));
+            state
+                .instrs
+                .push(Instr::NonControl(NonControlInstr::VarRef(capture)));
+            state
+                .instrs
+                .push(Instr::NonControl(NonControlInstr::Store { ty, offset }));
+        }
+        state
+            .instrs
+            .push(Instr::NonControl(NonControlInstr::VarRef(local_idx)));
+    }
+
+    // 本体で使われている外側のローカル変数を捕捉し、関数として切り出す
+    fn gen_lambda(
+        &self,
+        state: &mut GenFuncState,
+        args: &[(String, Ty)],
+        ret: &Ty,
+        body: &ast::Expr,
+    ) -> Result<(), CompileError> {
+        let mut bound = args.iter().map(|(name, _)| name.clone()).collect();
+        let mut vars = Vec::new();
+        free_vars(body, &mut bound, &mut vars);
+        let captures = vars
+            .iter()
+            .filter_map(|name| state.locals.get(name).map(|&idx| (name, idx)))
+            .collect::<Vec<_>>();
+
+        let name = format!("{}$lambda{}", state.func_name, state.lambdas_count);
+        state.lambdas_count += 1;
+        let mut lambda_state = GenFuncState::new(name);
+        for (name, ty) in args {
+            lambda_state.add_local(name.clone(), ty.repr());
+        }
+        let env = lambda_state.add_temp_local(Type::I32);
+        // 捕捉した変数は呼び出されるたびにクロージャからローカル変数にコピーする
+        let capture_types = captures
+            .iter()
+            .map(|&(_, idx)| state.local_types[idx])
+            .collect::<Vec<_>>();
+        let (offsets, _) = layout::closure_layout(&capture_types);
+        for ((&(name, _), offset), ty) in captures.iter().zip(offsets).zip(capture_types) {
+            let local_idx = lambda_state.add_local(name.clone(), ty);
+            lambda_state
+                .instrs
+                .push(Instr::NonControl(NonControlInstr::VarRef(env)));
+            lambda_state
+                .instrs
+                .push(Instr::NonControl(NonControlInstr::Load { ty, offset }));
+            lambda_state
+                .instrs
+                .push(Instr::NonControl(NonControlInstr::Assign(local_idx)));
+        }
+        self.gen_expr(&mut lambda_state, body)?;
+        lambda_state.instrs.push(Instr::Return);
+
+        let func_idx = self.add_lifted_func(lambda_state.into_func(args.len() + 1, ret.repr()));
+        let captures = captures.iter().map(|&(_, idx)| idx).collect::<Vec<_>>();
+        self.gen_closure(state, func_idx, &captures);
+        Ok(())
+    }
+
+    // 引数より先に評価したクロージャを一時変数に退避し、引数の後に積む
+    // クロージャの先頭の関数をクロージャ自身を最後の引数として呼び出す
+    fn gen_call_indirect(
+        &self,
+        state: &mut GenFuncState,
+        callee: &ast::Expr,
+        args: &[ast::Expr],
+    ) -> Result<(), CompileError> {
+        self.gen_expr(state, callee)?;
+        let signature = match callee.ty() {
+            Ty::Func(params, ret) => Signature {
+                params: params
+                    .iter()
+                    .map(|ty| ty.repr())
+                    .chain(std::iter::once(Type::I32))
+                    .collect(),
+                ret: ret.repr(),
+            },
+            // 型検査を通るのはreturnなどで制御が戻らない式だけ
+            found => {
+                return Err(CompileError::UnexpectedType {
+                    expected: "function".to_string(),
+                    found: found.clone(),
+                    span: callee.span,
+                })
+            }
+        };
+        let local_idx = state.add_temp_local(Type::I32);
+        state
+            .instrs
+            .push(Instr::NonControl(NonControlInstr::Assign(local_idx)));
+
+        let depth = state.depth;
+        for arg in args {
+            self.gen_expr(state, arg)?;
+            state.depth += 1;
         }
+        state.depth = depth;
+
+        state
+            .instrs
+            .push(Instr::NonControl(NonControlInstr::VarRef(local_idx)));
+        state
+            .instrs
+            .push(Instr::NonControl(NonControlInstr::VarRef(local_idx)));
+        state.instrs.push(Instr::NonControl(NonControlInstr::Load {
+            ty: Type::I32,
+            offset: 0,
+        }));
+        state.instrs.push(Instr::CallIndirect(signature));
+        Ok(())
+    }
+
+    // 生成した命令に式のSpanを対応付ける
+    fn gen_expr(&self, state: &mut GenFuncState, expr: &ast::Expr) -> Result<(), CompileError> {
+        let outer = state.span;
+        state.set_span(Some(expr.span));
+        self.gen_expr_kind(state, expr)?;
+        state.set_span(outer);
+        Ok(())
     }
 
-    fn gen_expr(&self, state: &mut GenFuncState, expr: &ast::Expr) {
-        match expr {
-            ast::Expr::IntLiteral(x) => {
+    fn gen_expr_kind(
+        &self,
+        state: &mut GenFuncState,
+        expr: &ast::Expr,
+    ) -> Result<(), CompileError> {
+        match &expr.kind {
+            ast::ExprKind::IntLiteral(..) | ast::ExprKind::FloatLiteral(_) => {
+                let x = Self::const_value(expr)?;
                 state
                     .instrs
-                    .push(Instr::NonControl(NonControlInstr::IntConst(*x)));
+                    .push(Instr::NonControl(NonControlInstr::Const(x)));
             }
-            ast::Expr::Ident(name) => {
-                let local_idx = state.locals.get(name).cloned().unwrap();
+            ast::ExprKind::StringLiteral(s) => {
+                let idx = self.string_const(s);
                 state
                     .instrs
-                    .push(Instr::NonControl(NonControlInstr::VarRef(local_idx)));
+                    .push(Instr::NonControl(NonControlInstr::StringConst(idx)));
             }
-            ast::Expr::BinaryOp(expr1, op, expr2) => {
-                self.gen_expr(state, expr1);
-                self.gen_expr(state, expr2);
-                match op {
-                    ast::BinaryOp::Add => {
-                        state.instrs.push(Instr::NonControl(NonControlInstr::Add))
+            // 変数でなければ関数の値
+            ast::ExprKind::Ident(name) if !self.is_var(state, name) => {
+                match self.func_refs.get(name) {
+                    Some(&FuncRef::UserDefined { idx }) => {
+                        let idx = self.static_closure(idx);
+                        state
+                            .instrs
+                            .push(Instr::NonControl(NonControlInstr::ClosureConst(idx)));
                     }
-                    ast::BinaryOp::Sub => {
-                        state.instrs.push(Instr::NonControl(NonControlInstr::Sub))
+                    _ => {
+                        return Err(CompileError::UnknownIdent {
+                            name: name.clone(),
+                            span: expr.span,
+                        })
                     }
-                    ast::BinaryOp::Mul => {
-                        state.instrs.push(Instr::NonControl(NonControlInstr::Mul))
-                    }
-                    ast::BinaryOp::Div => {
-                        state.instrs.push(Instr::NonControl(NonControlInstr::Div))
-                    }
-                    ast::BinaryOp::Mod => {
-                        state.instrs.push(Instr::NonControl(NonControlInstr::Mod))
-                    }
-                    ast::BinaryOp::Lt => state.instrs.push(Instr::NonControl(NonControlInstr::Lt)),
-                    ast::BinaryOp::Gt => state.instrs.push(Instr::NonControl(NonControlInstr::Gt)),
-                    ast::BinaryOp::Le => state.instrs.push(Instr::NonControl(NonControlInstr::Le)),
-                    ast::BinaryOp::Ge => state.instrs.push(Instr::NonControl(NonControlInstr::Ge)),
-                    ast::BinaryOp::Eq => state.instrs.push(Instr::NonControl(NonControlInstr::Eq)),
-                    ast::BinaryOp::Ne => state.instrs.push(Instr::NonControl(NonControlInstr::Ne)),
-                    ast::BinaryOp::And => {
-                        state.instrs.push(Instr::NonControl(NonControlInstr::And))
-                    }
-                    ast::BinaryOp::Or => state.instrs.push(Instr::NonControl(NonControlInstr::Or)),
                 }
             }
-            ast::Expr::PrefixOp(op, expr) => {
-                self.gen_expr(state, expr);
+            ast::ExprKind::Ident(name) => {
+                let instr = match self.var_ref(state, name, &expr.span)? {
+                    VarRef::Local(idx) => NonControlInstr::VarRef(idx),
+                    VarRef::Global(idx) => NonControlInstr::GlobalRef(idx),
+                };
+                state.instrs.push(Instr::NonControl(instr));
+            }
+            ast::ExprKind::BinaryOp(expr1, ast::BinaryOp::And, expr2) => {
+                self.gen_expr(state, expr1)?;
+                self.gen_if(
+                    state,
+                    Type::I32,
+                    |state| self.gen_bool(state, expr2),
+                    |state| {
+                        state
+                            .instrs
+                            .push(Instr::NonControl(NonControlInstr::Const(Const::I32(0))));
+                        Ok(())
+                    },
+                )?;
+            }
+            ast::ExprKind::BinaryOp(expr1, ast::BinaryOp::Or, expr2) => {
+                self.gen_expr(state, expr1)?;
+                self.gen_if(
+                    state,
+                    Type::I32,
+                    |state| {
+                        state
+                            .instrs
+                            .push(Instr::NonControl(NonControlInstr::Const(Const::I32(1))));
+                        Ok(())
+                    },
+                    |state| self.gen_bool(state, expr2),
+                )?;
+            }
+            ast::ExprKind::BinaryOp(expr1, op, expr2) => {
+                self.gen_expr(state, expr1)?;
+                state.depth += 1;
+                self.gen_expr(state, expr2)?;
+                state.depth -= 1;
+                let ty = expr1.ty().repr();
+                match op {
+                    ast::BinaryOp::Add => state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Add(ty))),
+                    ast::BinaryOp::Sub => state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Sub(ty))),
+                    ast::BinaryOp::Mul => state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Mul(ty))),
+                    ast::BinaryOp::Div => state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Div(ty))),
+                    ast::BinaryOp::Mod => state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Mod(ty))),
+                    ast::BinaryOp::Lt => state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Lt(ty))),
+                    ast::BinaryOp::Gt => state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Gt(ty))),
+                    ast::BinaryOp::Le => state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Le(ty))),
+                    ast::BinaryOp::Ge => state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Ge(ty))),
+                    ast::BinaryOp::Eq => state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Eq(ty))),
+                    ast::BinaryOp::Ne => state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Ne(ty))),
+                    ast::BinaryOp::And | ast::BinaryOp::Or => unreachable!(),
+                }
+            }
+            ast::ExprKind::PrefixOp(op, operand) => {
+                // i32::MINを書けるように負の整数リテラルは畳み込む
+                if let (ast::PrefixOp::Minus, ast::ExprKind::IntLiteral(..)) = (op, &operand.kind) {
+                    let x = Self::const_value(expr)?;
+                    state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Const(x)));
+                    return Ok(());
+                }
+
+                self.gen_expr(state, operand)?;
                 match op {
                     ast::PrefixOp::Not => {
                         state.instrs.push(Instr::NonControl(NonControlInstr::Not))
                     }
                     ast::PrefixOp::Minus => {
-                        state.instrs.push(Instr::NonControl(NonControlInstr::Minus));
+                        state
+                            .instrs
+                            .push(Instr::NonControl(NonControlInstr::Minus(expr.ty().repr())));
+                    }
+                }
+            }
+            ast::ExprKind::Cast(operand, to) => {
+                self.gen_expr(state, operand)?;
+                let from = operand.ty().repr();
+                if from != *to {
+                    state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Convert {
+                            from,
+                            to: *to,
+                        }));
+                }
+            }
+            ast::ExprKind::Assign(lhs, rhs) => {
+                match &lhs.kind {
+                    ast::ExprKind::Ident(ident) => {
+                        self.gen_expr(state, rhs)?;
+                        let instr = match self.var_ref(state, ident, &lhs.span)? {
+                            VarRef::Local(idx) => NonControlInstr::Assign(idx),
+                            VarRef::Global(idx) => NonControlInstr::GlobalAssign(idx),
+                        };
+                        state.instrs.push(Instr::NonControl(instr));
+                    }
+                    ast::ExprKind::Field(operand, name) => {
+                        self.gen_expr(state, operand)?;
+                        state.depth += 1;
+                        self.gen_expr(state, rhs)?;
+                        state.depth -= 1;
+                        state.instrs.push(Instr::NonControl(NonControlInstr::Store {
+                            ty: lhs.ty().repr(),
+                            offset: self.field_offset(operand, name),
+                        }));
                     }
+                    ast::ExprKind::Index(array, index) => {
+                        let depth = state.depth;
+                        self.gen_expr(state, array)?;
+                        state.depth += 1;
+                        self.gen_expr(state, index)?;
+                        state.depth += 1;
+                        self.gen_expr(state, rhs)?;
+                        state.depth = depth;
+                        state
+                            .instrs
+                            .push(Instr::NonControl(NonControlInstr::ArraySet(
+                                lhs.ty().repr(),
+                            )));
+                    }
+                    // 構文解析で弾いている
+                    _ => unreachable!(),
                 }
+                state
+                    .instrs
+                    .push(Instr::NonControl(NonControlInstr::Const(Const::I32(0))));
             }
-            ast::Expr::Assign(ident, expr) => {
-                self.gen_expr(state, expr);
-                let local_idx = state.locals.get(ident).cloned().unwrap();
+            ast::ExprKind::StructLiteral(name, fields) => {
+                let layout = &self.layouts[name];
+                let local_idx = state.add_temp_local(Type::I32);
+                state
+                    .instrs
+                    .push(Instr::NonControl(NonControlInstr::Alloc(layout.size)));
                 state
                     .instrs
                     .push(Instr::NonControl(NonControlInstr::Assign(local_idx)));
+                for (field, value) in fields {
+                    let (offset, ty) = layout.fields[field];
+                    state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::VarRef(local_idx)));
+                    state.depth += 1;
+                    self.gen_expr(state, value)?;
+                    state.depth -= 1;
+                    state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Store { ty, offset }));
+                }
                 state
                     .instrs
-                    .push(Instr::NonControl(NonControlInstr::IntConst(0)));
+                    .push(Instr::NonControl(NonControlInstr::VarRef(local_idx)));
+            }
+            ast::ExprKind::Field(operand, name) => {
+                self.gen_expr(state, operand)?;
+                state.instrs.push(Instr::NonControl(NonControlInstr::Load {
+                    ty: expr.ty().repr(),
+                    offset: self.field_offset(operand, name),
+                }));
             }
-            ast::Expr::Call(ident, exprs) => {
-                for expr in exprs {
-                    self.gen_expr(state, expr);
+            ast::ExprKind::Index(array, index) => {
+                self.gen_expr(state, array)?;
+                state.depth += 1;
+                self.gen_expr(state, index)?;
+                state.depth -= 1;
+                state
+                    .instrs
+                    .push(Instr::NonControl(NonControlInstr::ArrayGet(
+                        expr.ty().repr(),
+                    )));
+            }
+            ast::ExprKind::Call(callee, args) => {
+                let ident = match &callee.kind {
+                    ast::ExprKind::Ident(ident) if !self.is_var(state, ident) => ident,
+                    _ => return self.gen_call_indirect(state, callee, args),
+                };
+                let func_ref = self.func_refs.get(ident).cloned().ok_or_else(|| {
+                    CompileError::UnknownFunc {
+                        name: ident.clone(),
+                        span: expr.span,
+                    }
+                })?;
+
+                let expected_args_count = match &func_ref {
+                    FuncRef::UserDefined { idx } => self.module.funcs[*idx].args.len(),
+                    FuncRef::Builtin { kind } => kind.args_count(),
+                    FuncRef::Host { idx } => self.host_funcs[*idx].params.len(),
+                };
+                if args.len() != expected_args_count {
+                    return Err(CompileError::ArityMismatch {
+                        name: ident.clone(),
+                        expected: expected_args_count,
+                        actual: args.len(),
+                        span: expr.span,
+                    });
                 }
 
-                let func_ref = self.func_refs.get(ident).cloned().unwrap();
+                let depth = state.depth;
+                for arg in args {
+                    self.gen_expr(state, arg)?;
+                    state.depth += 1;
+                }
+                state.depth = depth;
+
                 match func_ref {
                     FuncRef::UserDefined { idx, .. } => {
                         state.instrs.push(Instr::Call {
                             func: idx,
-                            args_count: exprs.len(),
+                            args_count: args.len(),
                         });
                     }
+                    FuncRef::Host { idx } => {
+                        state
+                            .instrs
+                            .push(Instr::NonControl(NonControlInstr::CallHost(idx)));
+                    }
                     FuncRef::Builtin { kind } => match kind {
                         BuiltinFunc::Println => {
+                            let name = host::println_name(args[0].ty().repr());
+                            let idx = self
+                                .host_funcs
+                                .iter()
+                                .position(|host_func| host_func.name == name)
+                                .ok_or(CompileError::UnknownFunc {
+                                    name,
+                                    span: expr.span,
+                                })?;
+                            state
+                                .instrs
+                                .push(Instr::NonControl(NonControlInstr::CallHost(idx)));
+                        }
+                        BuiltinFunc::Array => {
+                            let elem = match expr.ty() {
+                                Ty::Array(elem) => elem.repr(),
+                                _ => unreachable!(),
+                            };
+                            state
+                                .instrs
+                                .push(Instr::NonControl(NonControlInstr::ArrayNew(elem)));
+                        }
+                        BuiltinFunc::Len => {
+                            state
+                                .instrs
+                                .push(Instr::NonControl(NonControlInstr::ArrayLen));
+                        }
+                        BuiltinFunc::Abs => {
+                            state
+                                .instrs
+                                .push(Instr::NonControl(NonControlInstr::Abs(expr.ty().repr())));
+                        }
+                        BuiltinFunc::Min => {
+                            state
+                                .instrs
+                                .push(Instr::NonControl(NonControlInstr::Min(expr.ty().repr())));
+                        }
+                        BuiltinFunc::Max => {
                             state
                                 .instrs
-                                .push(Instr::NonControl(NonControlInstr::Println));
+                                .push(Instr::NonControl(NonControlInstr::Max(expr.ty().repr())));
+                        }
+                        BuiltinFunc::Assert => {
+                            state
+                                .instrs
+                                .push(Instr::NonControl(NonControlInstr::Assert));
                         }
                     },
                 };
             }
-            ast::Expr::While(cond, body) => {
+            ast::ExprKind::Lambda(args, ret, body) => self.gen_lambda(state, args, ret, body)?,
+            ast::ExprKind::While(cond, body) => {
                 let loop_id = state.loop_infos.len();
                 let mut loop_info = LoopInfo::dummy();
                 state.loop_infos.push(loop_info.clone());
+                state.loops.push(loop_id);
+                loop_info.depth = state.depth;
                 loop_info.loop_ = state.instrs.len();
                 state.instrs.push(Instr::Loop(loop_id));
-                self.gen_expr(state, cond);
+                self.gen_expr(state, cond)?;
                 loop_info.loop_then = state.instrs.len();
                 state.instrs.push(Instr::LoopThen(loop_id));
-                self.gen_expr(state, body);
+                self.gen_expr(state, body)?;
                 state.instrs.push(Instr::NonControl(NonControlInstr::Drop));
                 loop_info.loop_end = state.instrs.len();
                 state.instrs.push(Instr::LoopEnd(loop_id));
                 state.loop_infos[loop_id] = loop_info;
+                state.loops.pop();
 
                 state
                     .instrs
-                    .push(Instr::NonControl(NonControlInstr::IntConst(0)));
-            }
-            ast::Expr::If(cond, body, else_body) => {
-                let if_id = state.if_infos.len();
-                let mut if_info = IfInfo::dummy();
-                state.if_infos.push(if_info.clone());
-                self.gen_expr(state, cond);
-                if_info.if_ = state.instrs.len();
-                state.instrs.push(Instr::If(if_id));
-                self.gen_expr(state, body);
-                if_info.else_ = state.instrs.len();
-                state.instrs.push(Instr::Else(if_id));
-                self.gen_expr(state, else_body);
-                if_info.if_end = state.instrs.len();
-                state.instrs.push(Instr::IfEnd(if_id));
-                state.if_infos[if_id] = if_info;
-            }
-            ast::Expr::Block(exprs) => match exprs.split_last() {
-                Some((expr, last)) => {
-                    for expr in last {
-                        self.gen_expr(state, expr);
+                    .push(Instr::NonControl(NonControlInstr::Const(Const::I32(0))));
+            }
+            ast::ExprKind::If(cond, body, else_body) => {
+                self.gen_expr(state, cond)?;
+                self.gen_if(
+                    state,
+                    expr.ty().repr(),
+                    |state| {
+                        self.gen_expr(state, body)?;
+                        // elseがない場合はi32以外の値は捨てて0にする
+                        if else_body.is_none() && *body.ty() != Ty::I32 {
+                            state.instrs.push(Instr::NonControl(NonControlInstr::Drop));
+                            state
+                                .instrs
+                                .push(Instr::NonControl(NonControlInstr::Const(Const::I32(0))));
+                        }
+                        Ok(())
+                    },
+                    |state| {
+                        match else_body {
+                            Some(else_body) => self.gen_expr(state, else_body)?,
+                            None => state
+                                .instrs
+                                .push(Instr::NonControl(NonControlInstr::Const(Const::I32(0)))),
+                        }
+                        Ok(())
+                    },
+                )?;
+            }
+            ast::ExprKind::Match(scrutinee, arms, default) => {
+                let arms = arms
+                    .iter()
+                    .map(|(pattern, body)| Ok((Self::const_value(pattern)?, body)))
+                    .collect::<Result<Vec<_>, CompileError>>()?;
+                self.gen_expr(state, scrutinee)?;
+                if is_dense(&arms) {
+                    self.gen_switch(
+                        state,
+                        expr.ty().repr(),
+                        scrutinee.ty().repr(),
+                        &arms,
+                        default,
+                    )?;
+                } else {
+                    let local_idx = state.add_temp_local(scrutinee.ty().repr());
+                    state
+                        .instrs
+                        .push(Instr::NonControl(NonControlInstr::Assign(local_idx)));
+                    self.gen_match_chain(state, expr.ty().repr(), local_idx, &arms, default)?;
+                }
+            }
+            ast::ExprKind::Block(exprs) => match exprs.split_last() {
+                Some((last, init)) => {
+                    for expr in init {
+                        self.gen_expr(state, expr)?;
                         state.instrs.push(Instr::NonControl(NonControlInstr::Drop));
                     }
-                    self.gen_expr(state, expr);
+                    self.gen_expr(state, last)?;
                 }
                 None => state
                     .instrs
-                    .push(Instr::NonControl(NonControlInstr::IntConst(0))),
+                    .push(Instr::NonControl(NonControlInstr::Const(Const::I32(0)))),
             },
-            ast::Expr::Var(ident, expr1, expr2) => {
-                self.gen_expr(state, expr1);
+            ast::ExprKind::Var(ident, _, expr1, expr2) => {
+                self.gen_expr(state, expr1)?;
                 let prev_locals = state.locals.clone();
-                let local_idx = state.add_local(ident.clone());
+                let local_idx = state.add_local(ident.clone(), expr1.ty().repr());
                 state
                     .instrs
                     .push(Instr::NonControl(NonControlInstr::Assign(local_idx)));
-                self.gen_expr(state, expr2);
+                self.gen_expr(state, expr2)?;
                 state.locals = prev_locals;
             }
+            ast::ExprKind::Return(ret_val) => {
+                self.gen_expr(state, ret_val)?;
+                state.instrs.push(Instr::Return);
+            }
+            ast::ExprKind::Break => {
+                let loop_id = *state
+                    .loops
+                    .last()
+                    .ok_or(CompileError::BreakOutsideLoop { span: expr.span })?;
+                state.instrs.push(Instr::Break(loop_id));
+            }
+            ast::ExprKind::Continue => {
+                let loop_id = *state
+                    .loops
+                    .last()
+                    .ok_or(CompileError::ContinueOutsideLoop { span: expr.span })?;
+                state.instrs.push(Instr::Continue(loop_id));
+            }
+        }
+        Ok(())
+    }
+
+    // 条件は評価済みでスタックに積まれているものとする
+    fn gen_if(
+        &self,
+        state: &mut GenFuncState,
+        ty: Type,
+        gen_then: impl FnOnce(&mut GenFuncState) -> Result<(), CompileError>,
+        gen_else: impl FnOnce(&mut GenFuncState) -> Result<(), CompileError>,
+    ) -> Result<(), CompileError> {
+        let if_id = state.if_infos.len();
+        let mut if_info = IfInfo::dummy();
+        if_info.ty = ty;
+        state.if_infos.push(if_info.clone());
+        if_info.if_ = state.instrs.len();
+        state.instrs.push(Instr::If(if_id));
+        gen_then(state)?;
+        if_info.else_ = state.instrs.len();
+        state.instrs.push(Instr::Else(if_id));
+        gen_else(state)?;
+        if_info.if_end = state.instrs.len();
+        state.instrs.push(Instr::IfEnd(if_id));
+        state.if_infos[if_id] = if_info;
+        Ok(())
+    }
+
+    // スタックに積まれた値で腕を選ぶジャンプテーブル
+    fn gen_switch(
+        &self,
+        state: &mut GenFuncState,
+        ty: Type,
+        scrutinee_ty: Type,
+        arms: &[(Const, &ast::Expr)],
+        default: &ast::Expr,
+    ) -> Result<(), CompileError> {
+        let values = arms
+            .iter()
+            .map(|(pattern, _)| const_int(*pattern))
+            .collect::<Vec<_>>();
+        let min = values.iter().copied().min().unwrap();
+        let max = values.iter().copied().max().unwrap();
+        let mut table = vec![arms.len(); (max - min + 1) as usize];
+        for (i, x) in values.iter().enumerate() {
+            table[(x - min) as usize] = i;
+        }
+
+        let switch_id = state.switch_infos.len();
+        let mut switch_info = SwitchInfo::dummy();
+        switch_info.ty = ty;
+        switch_info.scrutinee_ty = scrutinee_ty;
+        switch_info.min = min;
+        switch_info.table = table;
+        state.switch_infos.push(switch_info.clone());
+        state.instrs.push(Instr::Switch(switch_id));
+        for (i, (_, body)) in arms.iter().enumerate() {
+            switch_info.cases.push(state.instrs.len());
+            state.instrs.push(Instr::Case(switch_id, i));
+            self.gen_expr(state, body)?;
+        }
+        switch_info.cases.push(state.instrs.len());
+        state.instrs.push(Instr::Case(switch_id, arms.len()));
+        self.gen_expr(state, default)?;
+        switch_info.switch_end = state.instrs.len();
+        state.instrs.push(Instr::SwitchEnd(switch_id));
+        state.switch_infos[switch_id] = switch_info;
+        Ok(())
+    }
+
+    // ローカル変数に入れた値を先頭の腕から順に比較する
+    fn gen_match_chain(
+        &self,
+        state: &mut GenFuncState,
+        ty: Type,
+        local_idx: usize,
+        arms: &[(Const, &ast::Expr)],
+        default: &ast::Expr,
+    ) -> Result<(), CompileError> {
+        match arms.split_first() {
+            Some(((pattern, body), rest)) => {
+                state
+                    .instrs
+                    .push(Instr::NonControl(NonControlInstr::VarRef(local_idx)));
+                state
+                    .instrs
+                    .push(Instr::NonControl(NonControlInstr::Const(*pattern)));
+                state
+                    .instrs
+                    .push(Instr::NonControl(NonControlInstr::Eq(pattern.ty())));
+                self.gen_if(
+                    state,
+                    ty,
+                    |state| self.gen_expr(state, body),
+                    |state| self.gen_match_chain(state, ty, local_idx, rest, default),
+                )
+            }
+            None => self.gen_expr(state, default),
         }
     }
+
+    // 0か1に正規化して積む
+    fn gen_bool(&self, state: &mut GenFuncState, expr: &ast::Expr) -> Result<(), CompileError> {
+        self.gen_expr(state, expr)?;
+        state
+            .instrs
+            .push(Instr::NonControl(NonControlInstr::Const(Const::I32(0))));
+        state
+            .instrs
+            .push(Instr::NonControl(NonControlInstr::Ne(Type::I32)));
+        Ok(())
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Eq)]
 
 struct GenFuncState {
+    func_name: String,
+    // ラムダ式の名前に使う
+    lambdas_count: usize,
     locals: HashMap<String, usize>,
-    locals_count: usize,
+    local_types: Vec<Type>,
     instrs: Vec<Instr>,
     if_infos: Vec<IfInfo>,
     loop_infos: Vec<LoopInfo>,
+    switch_infos: Vec<SwitchInfo>,
+    // 囲んでいるループ。内側のものほど後ろ
+    loops: Vec<LoopId>,
+    // 評価途中でスタックに積まれたままになっている値の数
+    depth: usize,
+    spans: Vec<(usize, Span)>,
+    // 生成中の式
+    span: Option<Span>,
 }
 
 impl GenFuncState {
-    fn new() -> Self {
+    fn new(func_name: String) -> Self {
         GenFuncState {
+            func_name,
+            lambdas_count: 0,
             locals: HashMap::new(),
-            locals_count: 0,
+            local_types: Vec::new(),
             instrs: Vec::new(),
             if_infos: Vec::new(),
             loop_infos: Vec::new(),
+            switch_infos: Vec::new(),
+            loops: Vec::new(),
+            depth: 0,
+            spans: Vec::new(),
+            span: None,
+        }
+    }
+
+    // 次に生成する命令から対応するSpanを切り替える
+    fn set_span(&mut self, span: Option<Span>) {
+        self.span = span;
+        if let Some(span) = span {
+            match self.spans.last_mut() {
+                Some(last) if last.0 == self.instrs.len() => last.1 = span,
+                Some(last) if last.1 == span => {}
+                _ => self.spans.push((self.instrs.len(), span)),
+            }
         }
     }
 
-    fn add_local(&mut self, name: String) -> usize {
-        let idx = self.locals_count;
-        self.locals_count += 1;
+    fn add_local(&mut self, name: String, ty: Type) -> usize {
+        let idx = self.add_temp_local(ty);
         self.locals.insert(name, idx);
         idx
     }
+
+    // 名前を持たない一時的なローカル変数
+    fn add_temp_local(&mut self, ty: Type) -> usize {
+        let idx = self.local_types.len();
+        self.local_types.push(ty);
+        idx
+    }
+
+    fn into_func(self, args_count: usize, ret: Type) -> Func {
+        Func {
+            args_count,
+            locals: self.local_types,
+            ret,
+            instrs: self.instrs,
+            if_infos: self.if_infos,
+            loop_infos: self.loop_infos,
+            switch_infos: self.switch_infos,
+            name: self.func_name,
+            spans: self.spans,
+        }
+    }
+}
+
+fn const_int(x: Const) -> i64 {
+    match x {
+        Const::I32(x) => x as i64,
+        Const::I64(x) => x,
+        Const::F64(_) => unreachable!(),
+    }
+}
+
+// パターンの値の範囲が腕の数の2倍以内ならジャンプテーブルにし、そうでなければ比較を繰り返す
+fn is_dense(arms: &[(Const, &ast::Expr)]) -> bool {
+    if arms.len() < 3 {
+        return false;
+    }
+    let values = arms.iter().map(|(pattern, _)| const_int(*pattern) as i128);
+    let min = values.clone().min().unwrap();
+    let max = values.max().unwrap();
+    max - min < 2 * arms.len() as i128
+}
+
+// boundに含まれない変数を現れた順にvarsに集める
+fn free_vars(expr: &ast::Expr, bound: &mut Vec<String>, vars: &mut Vec<String>) {
+    match &expr.kind {
+        ast::ExprKind::IntLiteral(..)
+        | ast::ExprKind::FloatLiteral(_)
+        | ast::ExprKind::StringLiteral(_)
+        | ast::ExprKind::Break
+        | ast::ExprKind::Continue => {}
+        ast::ExprKind::Ident(name) => {
+            if !bound.contains(name) && !vars.contains(name) {
+                vars.push(name.clone());
+            }
+        }
+        ast::ExprKind::BinaryOp(expr1, _, expr2)
+        | ast::ExprKind::Assign(expr1, expr2)
+        | ast::ExprKind::Index(expr1, expr2)
+        | ast::ExprKind::While(expr1, expr2) => {
+            free_vars(expr1, bound, vars);
+            free_vars(expr2, bound, vars);
+        }
+        ast::ExprKind::PrefixOp(_, operand)
+        | ast::ExprKind::Cast(operand, _)
+        | ast::ExprKind::Field(operand, _)
+        | ast::ExprKind::Return(operand) => free_vars(operand, bound, vars),
+        ast::ExprKind::Call(callee, args) => {
+            free_vars(callee, bound, vars);
+            for arg in args {
+                free_vars(arg, bound, vars);
+            }
+        }
+        ast::ExprKind::StructLiteral(_, fields) => {
+            for (_, value) in fields {
+                free_vars(value, bound, vars);
+            }
+        }
+        ast::ExprKind::If(cond, body, else_body) => {
+            free_vars(cond, bound, vars);
+            free_vars(body, bound, vars);
+            if let Some(else_body) = else_body {
+                free_vars(else_body, bound, vars);
+            }
+        }
+        ast::ExprKind::Match(scrutinee, arms, default) => {
+            free_vars(scrutinee, bound, vars);
+            for (_, body) in arms {
+                free_vars(body, bound, vars);
+            }
+            free_vars(default, bound, vars);
+        }
+        ast::ExprKind::Block(exprs) => {
+            for expr in exprs {
+                free_vars(expr, bound, vars);
+            }
+        }
+        ast::ExprKind::Var(name, _, init, body) => {
+            free_vars(init, bound, vars);
+            bound.push(name.clone());
+            free_vars(body, bound, vars);
+            bound.pop();
+        }
+        ast::ExprKind::Lambda(args, _, body) => {
+            let len = bound.len();
+            bound.extend(args.iter().map(|(name, _)| name.clone()));
+            free_vars(body, bound, vars);
+            bound.truncate(len);
+        }
+    }
 }
 
-pub fn generate(module: &ast::Module) -> Module {
-    let gen = IrGenerator::new(&module).unwrap();
+pub fn generate(
+    module: &ast::Module,
+    layouts: &HashMap<String, StructLayout>,
+    host_funcs: &[HostFunc],
+) -> Result<Module, CompileError> {
+    let gen = IrGenerator::new(module, layouts, host_funcs)?;
     gen.generate()
 }
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use crate::ast;
+use crate::types::Type;
+
+#[derive(Debug, PartialEq, Clone, Eq)]
+pub struct StructLayout {
+    // フィールド名 -> (オフセット, 型)
+    pub fields: HashMap<String, (u32, Type)>,
+    pub size: u32,
+}
+
+// 順に並べ、それぞれのサイズの倍数のオフセットに置く。オフセットと全体のサイズを返す
+fn place(tys: &[Type]) -> (Vec<u32>, u32) {
+    let mut offset: u32 = 0;
+    let offsets = tys
+        .iter()
+        .map(|ty| {
+            offset = offset.next_multiple_of(ty.size());
+            let field_offset = offset;
+            offset += ty.size();
+            field_offset
+        })
+        .collect();
+    (offsets, offset)
+}
+
+// フィールドは宣言順に並べる
+fn struct_layout(struct_: &ast::Struct) -> StructLayout {
+    let tys = struct_
+        .fields
+        .iter()
+        .map(|(_, ty)| ty.repr())
+        .collect::<Vec<_>>();
+    let (offsets, size) = place(&tys);
+    StructLayout {
+        fields: struct_
+            .fields
+            .iter()
+            .zip(offsets)
+            .zip(tys)
+            .map(|(((name, _), offset), ty)| (name.clone(), (offset, ty)))
+            .collect(),
+        // 空の構造体も別々のアドレスになるようにする
+        size: size.max(1),
+    }
+}
+
+// クロージャは先頭に関数のテーブルのindex(i32)を持ち、その後に捕捉した変数が並ぶ
+// 捕捉した変数のオフセットと全体のサイズを返す
+pub fn closure_layout(captures: &[Type]) -> (Vec<u32>, u32) {
+    let tys = std::iter::once(Type::I32)
+        .chain(captures.iter().copied())
+        .collect::<Vec<_>>();
+    let (offsets, size) = place(&tys);
+    (offsets[1..].to_vec(), size)
+}
+
+pub fn layout(module: &ast::Module) -> HashMap<String, StructLayout> {
+    module
+        .structs
+        .iter()
+        .map(|struct_| (struct_.name.clone(), struct_layout(struct_)))
+        .collect()
+}
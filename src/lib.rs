@@ -1,10 +1,43 @@
 pub mod ast;
+pub mod backtrace;
 pub mod compiler;
+pub mod error;
+pub mod host;
 pub mod interpreter;
 pub mod ir;
 pub mod ir_generator;
+pub mod layout;
+pub mod loader;
 pub mod parser;
+pub mod span;
 pub mod token;
 pub mod tokenizer;
+pub mod typeck;
+pub mod types;
 pub mod vm;
 pub mod wasm_generator;
+
+// host_funcsはhost::HostFuncs::declsで得た宣言
+pub fn compile<L: loader::Loader>(
+    path: &str,
+    loader: &mut L,
+    host_funcs: &[ir::HostFunc],
+) -> Result<ir::Module, error::CompileErrors> {
+    let mut files = Vec::new();
+    compile_files(path, loader, host_funcs, &mut files)
+        .map_err(|errors| error::CompileErrors { files, errors })
+}
+
+fn compile_files<L: loader::Loader>(
+    path: &str,
+    loader: &mut L,
+    host_funcs: &[ir::HostFunc],
+    files: &mut Vec<String>,
+) -> Result<ir::Module, Vec<error::CompileError>> {
+    let mut module = loader::load(loader, path, files)?;
+    typeck::check(&mut module, host_funcs).map_err(|e| vec![e])?;
+    let layouts = layout::layout(&module);
+    let mut module = ir_generator::generate(&module, &layouts, host_funcs).map_err(|e| vec![e])?;
+    module.files = files.clone();
+    Ok(module)
+}
@@ -0,0 +1,408 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast;
+use crate::error::CompileError;
+use crate::parser;
+use crate::span::Span;
+use crate::tokenizer;
+use crate::types::Ty;
+
+pub trait Loader {
+    // 見つからなければNone
+    fn load(&mut self, path: &str) -> Option<String>;
+}
+
+extern "C" {
+    // 見つからなければnullを返す。バッファはexportしたallocで確保する
+    fn load_file(path: *const u8, path_len: usize, len: *mut i32) -> *mut u8;
+}
+
+#[derive(Debug, PartialEq, Clone, Eq)]
+pub struct WasmLoader;
+
+impl Loader for WasmLoader {
+    fn load(&mut self, path: &str) -> Option<String> {
+        unsafe {
+            let mut len = 0;
+            let ptr = load_file(path.as_ptr(), path.len(), &mut len);
+            if ptr.is_null() {
+                return None;
+            }
+            let buf = Vec::from_raw_parts(ptr, len as usize, len as usize);
+            String::from_utf8(buf).ok()
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Eq)]
+pub struct FsLoader;
+
+impl Loader for FsLoader {
+    fn load(&mut self, path: &str) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+}
+
+// importするファイルのディレクトリからの相対パスとして解決し、`.`と`..`を畳む
+pub fn resolve(importer: &str, path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    let joined = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        match importer.rfind('/') {
+            Some(i) => format!("{}/{}", &importer[..i], path),
+            None => path.to_string(),
+        }
+    };
+    for component in joined.split('/') {
+        match component {
+            "." => {}
+            ".." if matches!(components.last(), Some(&c) if c != ".." && !c.is_empty()) => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+    components.retain(|c| !c.is_empty());
+    let resolved = components.join("/");
+    if joined.starts_with('/') {
+        format!("/{}", resolved)
+    } else {
+        resolved
+    }
+}
+
+struct Unit {
+    module: ast::Module,
+    // importの名前 -> ファイルの番号
+    imports: HashMap<String, usize>,
+}
+
+struct Loading<'a, L: Loader> {
+    loader: &'a mut L,
+    files: &'a mut Vec<String>,
+    units: Vec<Option<Unit>>,
+    // 読み込み中のファイル。循環の検出に使う
+    stack: Vec<usize>,
+    errors: Vec<CompileError>,
+}
+
+impl<'a, L: Loader> Loading<'a, L> {
+    fn load_unit(&mut self, path: String, span: Span) -> Option<usize> {
+        if let Some(file) = self.files.iter().position(|f| *f == path) {
+            if self.stack.contains(&file) {
+                self.errors.push(CompileError::ImportCycle { path, span });
+                return None;
+            }
+            return Some(file);
+        }
+
+        let Some(code) = self.loader.load(&path) else {
+            self.errors.push(CompileError::FileNotFound { path, span });
+            return None;
+        };
+        let file = self.files.len();
+        self.files.push(path.clone());
+        self.units.push(None);
+
+        let tokens = match tokenizer::tokenize(&code, file) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                self.errors.push(e);
+                return Some(file);
+            }
+        };
+        let (module, errors) = parser::parse(&tokens);
+        self.errors.extend(errors);
+
+        self.stack.push(file);
+        let mut imports = HashMap::new();
+        for import in &module.imports {
+            if imports.contains_key(&import.name) {
+                self.errors.push(CompileError::DuplicateImport {
+                    name: import.name.clone(),
+                    span: import.span,
+                });
+                continue;
+            }
+            if let Some(imported) = self.load_unit(resolve(&path, &import.path), import.span) {
+                imports.insert(import.name.clone(), imported);
+            }
+        }
+        self.stack.pop();
+
+        self.units[file] = Some(Unit { module, imports });
+        Some(file)
+    }
+}
+
+// 各ファイルで定義された名前
+struct Names {
+    funcs: HashSet<String>,
+    globals: HashSet<String>,
+    structs: HashSet<String>,
+}
+
+impl Names {
+    fn new(module: &ast::Module) -> Self {
+        Names {
+            funcs: module.funcs.iter().map(|f| f.name.clone()).collect(),
+            globals: module.globals.iter().map(|g| g.name.clone()).collect(),
+            structs: module.structs.iter().map(|s| s.name.clone()).collect(),
+        }
+    }
+}
+
+struct Renamer<'a> {
+    files: &'a [String],
+    names: &'a [Names],
+    file: usize,
+    imports: &'a HashMap<String, usize>,
+}
+
+impl<'a> Renamer<'a> {
+    // エントリポイントの名前はそのまま使う
+    fn qualify(&self, file: usize, name: &str) -> String {
+        if file == 0 {
+            name.to_string()
+        } else {
+            format!("{}::{}", self.files[file], name)
+        }
+    }
+
+    fn value_name(&self, name: &str) -> Option<String> {
+        let names = &self.names[self.file];
+        if names.funcs.contains(name) || names.globals.contains(name) {
+            Some(self.qualify(self.file, name))
+        } else {
+            None
+        }
+    }
+
+    // `m.f`。importした名前でなければNone
+    fn qualified_value_name(&self, module: &str, name: &str) -> Option<String> {
+        let file = *self.imports.get(module)?;
+        let names = &self.names[file];
+        if names.funcs.contains(name) || names.globals.contains(name) {
+            Some(self.qualify(file, name))
+        } else {
+            // 存在しない名前として後で報告させる
+            Some(format!("{}.{}", module, name))
+        }
+    }
+
+    fn struct_name(&self, name: &str) -> String {
+        match name.split_once('.') {
+            Some((module, item)) => match self.imports.get(module) {
+                Some(&file) if self.names[file].structs.contains(item) => self.qualify(file, item),
+                _ => name.to_string(),
+            },
+            None if self.names[self.file].structs.contains(name) => self.qualify(self.file, name),
+            None => name.to_string(),
+        }
+    }
+
+    fn rename_ty(&self, ty: &mut Ty) {
+        match ty {
+            Ty::Scalar(_) | Ty::Str => {}
+            Ty::Array(elem) => self.rename_ty(elem),
+            Ty::Struct(name) => *name = self.struct_name(name),
+            Ty::Func(params, ret) => {
+                for param in params {
+                    self.rename_ty(param);
+                }
+                self.rename_ty(ret);
+            }
+        }
+    }
+
+    fn rename_expr(&self, expr: &mut ast::Expr, bound: &mut Vec<String>) {
+        match &mut expr.kind {
+            ast::ExprKind::IntLiteral(..)
+            | ast::ExprKind::FloatLiteral(_)
+            | ast::ExprKind::StringLiteral(_)
+            | ast::ExprKind::Break
+            | ast::ExprKind::Continue => {}
+            ast::ExprKind::Ident(name) => {
+                if !bound.contains(name) {
+                    if let Some(qualified) = self.value_name(name) {
+                        *name = qualified;
+                    }
+                }
+            }
+            ast::ExprKind::Field(operand, name) => {
+                if let ast::ExprKind::Ident(module) = &operand.kind {
+                    if !bound.contains(module) {
+                        if let Some(qualified) = self.qualified_value_name(module, name) {
+                            expr.kind = ast::ExprKind::Ident(qualified);
+                            return;
+                        }
+                    }
+                }
+                self.rename_expr(operand, bound);
+            }
+            ast::ExprKind::BinaryOp(expr1, _, expr2)
+            | ast::ExprKind::Assign(expr1, expr2)
+            | ast::ExprKind::Index(expr1, expr2)
+            | ast::ExprKind::While(expr1, expr2) => {
+                self.rename_expr(expr1, bound);
+                self.rename_expr(expr2, bound);
+            }
+            ast::ExprKind::PrefixOp(_, operand)
+            | ast::ExprKind::Cast(operand, _)
+            | ast::ExprKind::Return(operand) => self.rename_expr(operand, bound),
+            ast::ExprKind::Call(callee, args) => {
+                self.rename_expr(callee, bound);
+                for arg in args {
+                    self.rename_expr(arg, bound);
+                }
+            }
+            ast::ExprKind::StructLiteral(name, fields) => {
+                *name = self.struct_name(name);
+                for (_, value) in fields {
+                    self.rename_expr(value, bound);
+                }
+            }
+            ast::ExprKind::If(cond, body, else_body) => {
+                self.rename_expr(cond, bound);
+                self.rename_expr(body, bound);
+                if let Some(else_body) = else_body {
+                    self.rename_expr(else_body, bound);
+                }
+            }
+            ast::ExprKind::Match(scrutinee, arms, default) => {
+                self.rename_expr(scrutinee, bound);
+                for (_, body) in arms {
+                    self.rename_expr(body, bound);
+                }
+                self.rename_expr(default, bound);
+            }
+            ast::ExprKind::Block(exprs) => {
+                for expr in exprs {
+                    self.rename_expr(expr, bound);
+                }
+            }
+            ast::ExprKind::Var(name, ty, init, body) => {
+                if let Some(ty) = ty {
+                    self.rename_ty(ty);
+                }
+                self.rename_expr(init, bound);
+                bound.push(name.clone());
+                self.rename_expr(body, bound);
+                bound.pop();
+            }
+            ast::ExprKind::Lambda(args, ret, body) => {
+                for (_, ty) in args.iter_mut() {
+                    self.rename_ty(ty);
+                }
+                self.rename_ty(ret);
+                let len = bound.len();
+                bound.extend(args.iter().map(|(name, _)| name.clone()));
+                self.rename_expr(body, bound);
+                bound.truncate(len);
+            }
+        }
+    }
+
+    fn rename_module(&self, module: &mut ast::Module) {
+        for struct_ in &mut module.structs {
+            struct_.name = self.qualify(self.file, &struct_.name);
+            for (_, ty) in &mut struct_.fields {
+                self.rename_ty(ty);
+            }
+        }
+        for global in &mut module.globals {
+            global.name = self.qualify(self.file, &global.name);
+            if let Some(ty) = &mut global.ty {
+                self.rename_ty(ty);
+            }
+            self.rename_expr(&mut global.init, &mut Vec::new());
+        }
+        for func in &mut module.funcs {
+            func.name = self.qualify(self.file, &func.name);
+            for (_, ty) in &mut func.args {
+                self.rename_ty(ty);
+            }
+            self.rename_ty(&mut func.ret);
+            let mut bound = func.args.iter().map(|(name, _)| name.clone()).collect();
+            self.rename_expr(&mut func.body, &mut bound);
+        }
+    }
+}
+
+// エントリポイントから辿れるファイルを読み込み、名前を修飾して1つのモジュールにまとめる
+// 読み込んだファイルのパスをfilesに入れる。エラーのSpanのfileはその番号
+pub fn load<L: Loader>(
+    loader: &mut L,
+    path: &str,
+    files: &mut Vec<String>,
+) -> Result<ast::Module, Vec<CompileError>> {
+    let mut loading = Loading {
+        loader,
+        files,
+        units: Vec::new(),
+        stack: Vec::new(),
+        errors: Vec::new(),
+    };
+    let span = Span {
+        file: 0,
+        start: 0,
+        end: 0,
+        line: 1,
+        column: 1,
+    };
+    loading.load_unit(resolve("", path), span);
+    if !loading.errors.is_empty() {
+        return Err(loading.errors);
+    }
+
+    let units = loading
+        .units
+        .into_iter()
+        .map(|unit| unit.unwrap())
+        .collect::<Vec<_>>();
+    let names = units
+        .iter()
+        .map(|unit| Names::new(&unit.module))
+        .collect::<Vec<_>>();
+
+    // 実行時はエントリポイントの関数から呼ぶので、先に並べる
+    let mut merged = ast::Module {
+        imports: Vec::new(),
+        structs: Vec::new(),
+        globals: Vec::new(),
+        funcs: Vec::new(),
+    };
+    for (file, unit) in units.into_iter().enumerate() {
+        let mut module = unit.module;
+        let renamer = Renamer {
+            files,
+            names: &names,
+            file,
+            imports: &unit.imports,
+        };
+        renamer.rename_module(&mut module);
+        merged.imports.extend(module.imports);
+        merged.structs.extend(module.structs);
+        merged.globals.extend(module.globals);
+        merged.funcs.extend(module.funcs);
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_is_relative_to_the_importer() {
+        assert_eq!(resolve("main.wjit", "lib.wjit"), "lib.wjit");
+        assert_eq!(resolve("src/main.wjit", "lib.wjit"), "src/lib.wjit");
+        assert_eq!(
+            resolve("src/a/main.wjit", "../b/./lib.wjit"),
+            "src/b/lib.wjit"
+        );
+        assert_eq!(resolve("src/main.wjit", "/abs/lib.wjit"), "/abs/lib.wjit");
+        assert_eq!(resolve("main.wjit", "../lib.wjit"), "../lib.wjit");
+    }
+}
@@ -1,32 +1,56 @@
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::RefCell;
 use std::ffi::CString;
 use std::os::raw::c_char;
 use wjit::*;
 
 fn main() {}
 
+thread_local! {
+    static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+fn set_last_error(message: String) {
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+}
+
 #[no_mangle]
 pub fn alloc(size: i32) -> *mut u8 {
-    let mut buf = Vec::with_capacity(size as usize);
-    buf.resize(size as usize, 0);
+    let mut buf = vec![0; size as usize];
     let ptr = buf.as_mut_ptr();
     std::mem::forget(buf);
     ptr
 }
 
 #[no_mangle]
-pub unsafe fn make_ir_module(code: *mut c_char) -> *mut ir::Module {
-    let code = CString::from_raw(code);
-    let code = code.into_string().unwrap();
-    let tokens = tokenizer::tokenize(code.as_str()).unwrap().1;
-    let module = parser::parse(tokens.as_slice()).unwrap().1;
-    let module = ir_generator::generate(&module);
+pub unsafe fn last_error(len: *mut i32) -> *const u8 {
+    let buf = LAST_ERROR.with(|last_error| last_error.borrow().clone().into_bytes());
+    let result = buf.as_ptr();
+    *len = buf.len() as i32;
 
-    let result = Box::new(module);
-    let result = Box::into_raw(result);
+    std::mem::forget(buf);
+    result
+}
 
-    std::mem::forget(result);
+#[no_mangle]
+pub unsafe fn make_ir_module(path: *mut c_char) -> *mut ir::Module {
+    let path = CString::from_raw(path);
+    let path = path.into_string().unwrap();
+    let module = match compile(
+        path.as_str(),
+        &mut loader::WasmLoader,
+        &host::HostFuncs::wasm().decls(),
+    ) {
+        Ok(module) => module,
+        Err(errors) => {
+            set_last_error(errors.to_string());
+            return std::ptr::null_mut();
+        }
+    };
 
-    result
+    let result = Box::new(module);
+    Box::into_raw(result)
 }
 
 #[no_mangle]
@@ -35,11 +59,7 @@ pub unsafe fn make_compiler<'a>(module: *mut ir::Module) -> *mut compiler::Compi
     let compiler = compiler::Compiler::new(module);
 
     let result = Box::new(compiler);
-    let result = Box::into_raw(result);
-
-    std::mem::forget(result);
-
-    result
+    Box::into_raw(result)
 }
 
 #[no_mangle]
@@ -55,42 +75,151 @@ pub unsafe fn compile_skeleton(compiler: *mut compiler::Compiler, len: *mut i32)
 }
 
 #[no_mangle]
-pub fn compile_func(compiler: *mut compiler::Compiler, idx: i32, len: *mut i32) -> *const u8 {
-    let compiler = unsafe { &*compiler };
+pub unsafe fn compile_func(
+    compiler: *mut compiler::Compiler,
+    idx: i32,
+    len: *mut i32,
+) -> *const u8 {
+    let compiler = &*compiler;
     let module = compiler.compile_func_module(idx as usize);
     let buf = parity_wasm::serialize(module).unwrap();
     let result = buf.as_ptr();
-    unsafe {
-        *len = buf.len() as i32;
-    };
+    *len = buf.len() as i32;
 
     std::mem::forget(buf);
     result
 }
 
+// JIT側でtrapしたときのバックトレースをlast_errorに書き込む
+// framesには(関数のindex, 関数モジュール上のバイトオフセット)の組を内側の呼び出しから並べる
 #[no_mangle]
-pub fn make_interpreter(
-    module: &ir::Module,
-) -> *mut interpreter::Interpreter<interpreter::WasmBuiltin> {
-    let interpreter = interpreter::Interpreter::new(module, interpreter::WasmBuiltin);
+pub unsafe fn compiler_backtrace(
+    compiler: *mut compiler::Compiler,
+    frames_count: usize,
+    frames: *const u32,
+) {
+    let compiler = &*compiler;
+    let frames = if frames.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(frames, frames_count * 2)
+    };
+    let backtrace = backtrace::Backtrace {
+        frames: frames
+            .chunks(2)
+            .map(|frame| compiler.backtrace_frame(frame[0] as usize, frame[1]))
+            .collect(),
+    };
+    set_last_error(backtrace.to_string());
+}
+
+// JIT側のコードが_trapに書き込んだ番号のメッセージをlast_errorに書き込む
+// 番号が0ならwasm自身がtrapしたので何もせず0を返す
+#[no_mangle]
+pub fn trap_message(code: i32) -> i32 {
+    match interpreter::Trap::from_code(code) {
+        Some(trap) => {
+            set_last_error(trap.to_string());
+            1
+        }
+        None => 0,
+    }
+}
+
+// V8はwasmのスタックが溢れるとRangeErrorを投げる。インタプリタと同じtrapとして扱うための番号
+#[no_mangle]
+pub fn stack_overflow_trap_code() -> i32 {
+    interpreter::Trap::StackOverflow.code()
+}
+
+// 失敗したらnullを返し、メッセージはlast_errorで取得する
+#[no_mangle]
+pub fn make_interpreter(module: &ir::Module) -> *mut interpreter::Interpreter<'_> {
+    let interpreter = match interpreter::Interpreter::new(module, host::HostFuncs::wasm()) {
+        Ok(interpreter) => interpreter,
+        Err(error) => {
+            set_last_error(error.to_string());
+            return std::ptr::null_mut();
+        }
+    };
     let interpreter = Box::new(interpreter);
-    let interpreter = Box::into_raw(interpreter);
+    Box::into_raw(interpreter)
+}
 
-    std::mem::forget(interpreter);
-    interpreter
+#[no_mangle]
+pub fn interpreter_set_fuel(interpreter: &mut interpreter::Interpreter, fuel: i64) {
+    interpreter.fuel = fuel;
 }
 
+// JS側とはi32の値だけをやりとりする
+// trapしたらtrappedに1を書き込み、メッセージはlast_errorで取得する
 #[no_mangle]
 pub unsafe fn interpreter_call_func(
-    interpreter: &mut interpreter::Interpreter<interpreter::WasmBuiltin>,
+    interpreter: &mut interpreter::Interpreter,
     func: usize,
     args_count: usize,
     args: *const i32,
+    trapped: *mut i32,
 ) -> i32 {
-    let args = if args == std::ptr::null() {
+    let args = if args.is_null() {
         &[]
     } else {
         std::slice::from_raw_parts(args, args_count)
     };
-    interpreter.call(func, args)
+    let args = args
+        .iter()
+        .map(|x| interpreter::Value::I32(*x))
+        .collect::<Vec<_>>();
+    match interpreter.call(func, &args) {
+        Ok(ret_val) => {
+            *trapped = 0;
+            ret_val.as_i32()
+        }
+        Err(error) => {
+            set_last_error(error.to_string());
+            *trapped = 1;
+            0
+        }
+    }
+}
+
+// JS側はグローバル変数の型をこれで調べて、型ごとのアクセサを使い分ける
+// 0: i32, 1: i64, 2: f64
+#[no_mangle]
+pub fn interpreter_global_type(interpreter: &interpreter::Interpreter, idx: usize) -> i32 {
+    match interpreter.globals[idx].ty() {
+        types::Type::I32 => 0,
+        types::Type::I64 => 1,
+        types::Type::F64 => 2,
+    }
+}
+
+#[no_mangle]
+pub fn interpreter_get_global_i32(interpreter: &interpreter::Interpreter, idx: usize) -> i32 {
+    interpreter.globals[idx].as_i32()
+}
+
+#[no_mangle]
+pub fn interpreter_get_global_i64(interpreter: &interpreter::Interpreter, idx: usize) -> i64 {
+    interpreter.globals[idx].as_i64()
+}
+
+#[no_mangle]
+pub fn interpreter_get_global_f64(interpreter: &interpreter::Interpreter, idx: usize) -> f64 {
+    interpreter.globals[idx].as_f64()
+}
+
+#[no_mangle]
+pub fn interpreter_set_global_i32(interpreter: &mut interpreter::Interpreter, idx: usize, x: i32) {
+    interpreter.globals[idx] = interpreter::Value::I32(x);
+}
+
+#[no_mangle]
+pub fn interpreter_set_global_i64(interpreter: &mut interpreter::Interpreter, idx: usize, x: i64) {
+    interpreter.globals[idx] = interpreter::Value::I64(x);
+}
+
+#[no_mangle]
+pub fn interpreter_set_global_f64(interpreter: &mut interpreter::Interpreter, idx: usize, x: f64) {
+    interpreter.globals[idx] = interpreter::Value::F64(x);
 }
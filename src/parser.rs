@@ -1,133 +1,495 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+
 use nom::{
     branch::alt,
-    combinator::{eof, map},
-    error::ParseError,
+    combinator::{cut, map, opt, peek},
+    error::{ErrorKind, ParseError},
     multi::{many0, separated_list0},
     sequence::{preceded, terminated, tuple},
     IResult,
 };
 
 use crate::ast::*;
+use crate::error::CompileError;
+use crate::span::Span;
 use crate::token;
+use crate::types::{Ty, Type};
+
+#[derive(Debug, PartialEq, Clone, Eq)]
+struct Error<'a> {
+    input: &'a [token::SpannedToken],
+    expected: Vec<&'static str>,
+}
+
+impl<'a> Error<'a> {
+    fn new(input: &'a [token::SpannedToken], expected: &'static str) -> Self {
+        Error {
+            input,
+            expected: vec![expected],
+        }
+    }
+}
+
+impl<'a> ParseError<&'a [token::SpannedToken]> for Error<'a> {
+    fn from_error_kind(input: &'a [token::SpannedToken], _kind: ErrorKind) -> Self {
+        Error {
+            input,
+            expected: Vec::new(),
+        }
+    }
+
+    fn append(_input: &'a [token::SpannedToken], _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    // 一番先まで進めた方のエラーを残す
+    fn or(mut self, other: Self) -> Self {
+        match self.input.len().cmp(&other.input.len()) {
+            Ordering::Less => self,
+            Ordering::Greater => other,
+            Ordering::Equal => {
+                for expected in other.expected {
+                    if !self.expected.contains(&expected) {
+                        self.expected.push(expected);
+                    }
+                }
+                self
+            }
+        }
+    }
+}
+
+type PResult<'a, O> = IResult<&'a [token::SpannedToken], O, Error<'a>>;
+
+#[derive(Debug, PartialEq, Clone, Eq)]
+struct SyntaxError {
+    // エラー位置以降のトークン数
+    remaining: usize,
+    expected: Vec<&'static str>,
+}
+
+// 回復した構文エラーを集める。解析関数に引き回す
+type Errors = RefCell<Vec<SyntaxError>>;
+
+fn report(errors: &Errors, error: Error) {
+    let mut errors = errors.borrow_mut();
+    if errors.iter().all(|e| e.remaining != error.input.len()) {
+        errors.push(SyntaxError {
+            remaining: error.input.len(),
+            expected: error.expected,
+        });
+    }
+}
+
+fn consumed_span(before: &[token::SpannedToken], after: &[token::SpannedToken]) -> Span {
+    let consumed = &before[..before.len() - after.len()];
+    consumed[0].span.merge(&consumed[consumed.len() - 1].span)
+}
 
 fn satisfy_opt<O>(
+    expected: &'static str,
     f: impl Fn(&token::Token) -> Option<O>,
-) -> impl Fn(&[token::Token]) -> IResult<&[token::Token], O> {
-    move |input1: &[token::Token]| {
-        let (token, input2) =
-            input1
-                .split_first()
-                .ok_or(nom::Err::Error(nom::error::Error::new(
-                    input1,
-                    nom::error::ErrorKind::Eof,
-                )))?;
-        match f(token) {
-            Some(result) => Ok((&input2, result)),
-            None => Err(nom::Err::Error(nom::error::Error::from_error_kind(
-                input1,
-                nom::error::ErrorKind::Fail,
-            ))),
+) -> impl Fn(&[token::SpannedToken]) -> PResult<'_, O> {
+    move |input1: &[token::SpannedToken]| {
+        let (token, input2) = input1
+            .split_first()
+            .ok_or(nom::Err::Error(Error::new(input1, expected)))?;
+        match f(&token.token) {
+            Some(result) => Ok((input2, result)),
+            None => Err(nom::Err::Error(Error::new(input1, expected))),
         }
     }
 }
 
-fn int_literal(input: &[token::Token]) -> IResult<&[token::Token], i32> {
-    satisfy_opt(|token| match token {
-        &token::Token::IntLiteral(value) => Some(value),
+// 何も読み進められずに失敗した場合、期待するものをまとめて`expected`とする
+fn expected<'a, O>(
+    expected: &'static str,
+    mut f: impl FnMut(&'a [token::SpannedToken]) -> PResult<'a, O>,
+) -> impl FnMut(&'a [token::SpannedToken]) -> PResult<'a, O> {
+    move |input: &'a [token::SpannedToken]| {
+        f(input).map_err(|e| match e {
+            nom::Err::Error(e) if e.input.len() == input.len() => {
+                nom::Err::Error(Error::new(input, expected))
+            }
+            e => e,
+        })
+    }
+}
+
+fn int_literal(input: &[token::SpannedToken]) -> PResult<'_, (u64, Option<Type>)> {
+    satisfy_opt("integer literal", |token| match token {
+        &token::Token::IntLiteral(value, ty) => Some((value, ty)),
+        _ => None,
+    })(input)
+}
+
+fn float_literal(input: &[token::SpannedToken]) -> PResult<'_, u64> {
+    satisfy_opt("float literal", |token| match token {
+        &token::Token::FloatLiteral(value) => Some(value),
+        _ => None,
+    })(input)
+}
+
+fn string_literal(input: &[token::SpannedToken]) -> PResult<'_, String> {
+    satisfy_opt("string literal", |token| match token {
+        token::Token::StringLiteral(s) => Some(s.clone()),
+        _ => None,
+    })(input)
+}
+
+fn scalar_ty(input: &[token::SpannedToken]) -> PResult<'_, Type> {
+    satisfy_opt("type", |token| match token {
+        token::Token::Ident(ident) => match ident.as_str() {
+            "i32" => Some(Type::I32),
+            "i64" => Some(Type::I64),
+            "f64" => Some(Type::F64),
+            _ => None,
+        },
         _ => None,
     })(input)
 }
 
-fn ident(input: &[token::Token]) -> IResult<&[token::Token], String> {
-    satisfy_opt(|token| match token {
+fn ty(input: &[token::SpannedToken]) -> PResult<'_, Ty> {
+    alt((
+        map(scalar_ty, Ty::Scalar),
+        satisfy_opt("type", |token| match token {
+            token::Token::Ident(ident) if ident == "str" => Some(Ty::Str),
+            _ => None,
+        }),
+        map(
+            tuple((
+                satisfy_opt("type", |token| match token {
+                    token::Token::OpenBracket => Some(()),
+                    _ => None,
+                }),
+                cut(ty),
+                cut(satisfy_opt("`]`", |token| match token {
+                    token::Token::CloseBracket => Some(()),
+                    _ => None,
+                })),
+            )),
+            |(_, elem, _)| Ty::Array(Box::new(elem)),
+        ),
+        map(func_ty, |(params, ret)| Ty::Func(params, Box::new(ret))),
+        map(qualified_ident, Ty::Struct),
+    ))(input)
+}
+
+// `func(i32, i64): f64`。戻り値の型を省略した場合はi32
+fn func_ty(input: &[token::SpannedToken]) -> PResult<'_, (Vec<Ty>, Ty)> {
+    let (input, _) = satisfy_opt("type", |token| match token {
+        token::Token::Reserved(token::Reserved::Func) => Some(()),
+        _ => None,
+    })(input)?;
+    let (input, _) = cut(satisfy_opt("`(`", |token| match token {
+        token::Token::OpenParen => Some(()),
+        _ => None,
+    }))(input)?;
+    let (input, params) = separated_list0(
+        satisfy_opt("`,`", |token| match token {
+            token::Token::Comma => Some(()),
+            _ => None,
+        }),
+        ty,
+    )(input)?;
+    let (input, _) = cut(satisfy_opt("`)`", |token| match token {
+        token::Token::CloseParen => Some(()),
+        _ => None,
+    }))(input)?;
+    let (input, ret) = opt(type_annotation)(input)?;
+    Ok((input, (params, ret.unwrap_or(Ty::I32))))
+}
+
+fn type_annotation(input: &[token::SpannedToken]) -> PResult<'_, Ty> {
+    preceded(
+        satisfy_opt("`:`", |token| match token {
+            token::Token::Colon => Some(()),
+            _ => None,
+        }),
+        cut(ty),
+    )(input)
+}
+
+fn ident(input: &[token::SpannedToken]) -> PResult<'_, String> {
+    satisfy_opt("identifier", |token| match token {
         token::Token::Ident(value) => Some(value.clone()),
         _ => None,
     })(input)
 }
 
-fn paren_expr(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    let (input, _) = satisfy_opt(|token| match token {
+// `m.Point`のようにimportしたファイルの名前で修飾できる
+fn qualified_ident(input: &[token::SpannedToken]) -> PResult<'_, String> {
+    let (input, name) = ident(input)?;
+    let (input, item) = opt(preceded(
+        satisfy_opt("`.`", |token| match token {
+            token::Token::Dot => Some(()),
+            _ => None,
+        }),
+        cut(ident),
+    ))(input)?;
+    Ok((
+        input,
+        match item {
+            Some(item) => format!("{}.{}", name, item),
+            None => name,
+        },
+    ))
+}
+
+fn paren_expr<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let (input, _) = satisfy_opt("`(`", |token| match token {
         token::Token::OpenParen => Some(()),
         _ => None,
     })(input)?;
-    let (input, expr) = expr(input)?;
-    let (input, _) = satisfy_opt(|token| match token {
+    let (input, expr) = cut(|input| expr(errors, input))(input)?;
+    let (input, _) = cut(satisfy_opt("`)`", |token| match token {
         token::Token::CloseParen => Some(()),
         _ => None,
-    })(input)?;
+    }))(input)?;
     Ok((input, expr))
 }
 
-fn expr0(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    alt((
-        map(int_literal, Expr::IntLiteral),
-        paren_expr,
-        map(ident, Expr::Ident),
-    ))(input)
+fn field_init<'a>(
+    errors: &Errors,
+    input: &'a [token::SpannedToken],
+) -> PResult<'a, (String, Expr)> {
+    let (input, name) = ident(input)?;
+    let (input, _) = satisfy_opt("`:`", |token| match token {
+        token::Token::Colon => Some(()),
+        _ => None,
+    })(input)?;
+    let (input, expr) = cut(|input| expr(errors, input))(input)?;
+    Ok((input, (name, expr)))
 }
 
-fn call_params(input: &[token::Token]) -> IResult<&[token::Token], Vec<Expr>> {
-    let (input, _) = satisfy_opt(|token| match token {
-        token::Token::OpenParen => Some(()),
+fn struct_literal<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let input1 = input;
+    let (input, name) = qualified_ident(input)?;
+    let (input, _) = satisfy_opt("`{`", |token| match token {
+        token::Token::OpenBrace => Some(()),
         _ => None,
     })(input)?;
-    let (input, params) = separated_list0(
-        satisfy_opt(|token| match token {
+    // `{`の後が`}`か`ident:`の場合のみ構造体リテラルとみなす
+    let (input, _) = peek(alt((
+        map(
+            satisfy_opt("`}`", |token| match token {
+                token::Token::CloseBrace => Some(()),
+                _ => None,
+            }),
+            |_| (),
+        ),
+        map(
+            tuple((
+                ident,
+                satisfy_opt("`:`", |token| match token {
+                    token::Token::Colon => Some(()),
+                    _ => None,
+                }),
+            )),
+            |_| (),
+        ),
+    )))(input)?;
+    let (input, fields) = separated_list0(
+        satisfy_opt("`,`", |token| match token {
             token::Token::Comma => Some(()),
             _ => None,
         }),
-        expr,
+        |input| field_init(errors, input),
     )(input)?;
-    let (input, _) = satisfy_opt(|token| match token {
+    let (input, _) = opt(satisfy_opt("`,`", |token| match token {
+        token::Token::Comma => Some(()),
+        _ => None,
+    }))(input)?;
+    let (input, _) = cut(satisfy_opt("`}`", |token| match token {
+        token::Token::CloseBrace => Some(()),
+        _ => None,
+    }))(input)?;
+    Ok((
+        input,
+        Expr::new(
+            ExprKind::StructLiteral(name, fields),
+            consumed_span(input1, input),
+        ),
+    ))
+}
+
+fn lambda<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let input1 = input;
+    let (input, _) = satisfy_opt("`fn`", |token| match token {
+        token::Token::Reserved(token::Reserved::Fn) => Some(()),
+        _ => None,
+    })(input)?;
+
+    let (input, args) = cut(params)(input)?;
+
+    let (input, ret) = opt(type_annotation)(input)?;
+
+    let (input, body) = cut(|input| block(errors, input))(input)?;
+
+    Ok((
+        input,
+        Expr::new(
+            ExprKind::Lambda(args, ret.unwrap_or(Ty::I32), Box::new(body)),
+            consumed_span(input1, input),
+        ),
+    ))
+}
+
+fn expr0<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let input1 = input;
+    expected(
+        "expression",
+        alt((
+            map(int_literal, |(x, ty)| {
+                Expr::new(ExprKind::IntLiteral(x, ty), input1[0].span)
+            }),
+            map(float_literal, |x| {
+                Expr::new(ExprKind::FloatLiteral(x), input1[0].span)
+            }),
+            map(string_literal, |s| {
+                Expr::new(ExprKind::StringLiteral(s), input1[0].span)
+            }),
+            |input| paren_expr(errors, input),
+            |input| lambda(errors, input),
+            |input| struct_literal(errors, input),
+            map(ident, |x| Expr::new(ExprKind::Ident(x), input1[0].span)),
+        )),
+    )(input)
+}
+
+fn call_params<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Vec<Expr>> {
+    let (input, _) = satisfy_opt("`(`", |token| match token {
+        token::Token::OpenParen => Some(()),
+        _ => None,
+    })(input)?;
+    let (input, params) = opt(tuple((
+        |input| expr(errors, input),
+        many0(preceded(
+            satisfy_opt("`,`", |token| match token {
+                token::Token::Comma => Some(()),
+                _ => None,
+            }),
+            cut(|input| expr(errors, input)),
+        )),
+    )))(input)?;
+    let (input, _) = cut(satisfy_opt("`)`", |token| match token {
         token::Token::CloseParen => Some(()),
         _ => None,
+    }))(input)?;
+    Ok((
+        input,
+        match params {
+            Some((param, mut params)) => {
+                params.insert(0, param);
+                params
+            }
+            None => Vec::new(),
+        },
+    ))
+}
+
+fn index<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let (input, _) = satisfy_opt("`[`", |token| match token {
+        token::Token::OpenBracket => Some(()),
+        _ => None,
     })(input)?;
-    Ok((input, params))
+    let (input, expr) = cut(|input| expr(errors, input))(input)?;
+    let (input, _) = cut(satisfy_opt("`]`", |token| match token {
+        token::Token::CloseBracket => Some(()),
+        _ => None,
+    }))(input)?;
+    Ok((input, expr))
+}
+
+fn field(input: &[token::SpannedToken]) -> PResult<'_, String> {
+    preceded(
+        satisfy_opt("`.`", |token| match token {
+            token::Token::Dot => Some(()),
+            _ => None,
+        }),
+        cut(ident),
+    )(input)
+}
+
+enum Postfix {
+    Call(Vec<Expr>),
+    Index(Expr),
+    Field(String),
 }
 
-fn expr1(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
+fn expr1<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
     let input1 = input;
-    let (input, expr) = expr0(input)?;
+    let (input, expr) = expr0(errors, input)?;
 
-    let (input, params) = many0(call_params)(input)?;
+    let (input, postfixes) = many0(alt((
+        map(|input| call_params(errors, input), Postfix::Call),
+        map(|input| index(errors, input), Postfix::Index),
+        map(field, Postfix::Field),
+    )))(input)?;
+    let span = consumed_span(input1, input);
 
     Ok((
         input,
-        params
+        postfixes
             .into_iter()
-            .try_fold(expr, |expr, params| match expr {
-                Expr::Ident(ident) => Ok(Expr::Call(ident, params)),
-                _ => Err(nom::Err::Error(nom::error::Error::from_error_kind(
-                    input1,
-                    nom::error::ErrorKind::Fail,
-                ))),
+            .try_fold(expr, |expr, postfix| match postfix {
+                Postfix::Call(params) => {
+                    Ok(Expr::new(ExprKind::Call(Box::new(expr), params), span))
+                }
+                Postfix::Index(index) => Ok(Expr::new(
+                    ExprKind::Index(Box::new(expr), Box::new(index)),
+                    span,
+                )),
+                Postfix::Field(name) => Ok(Expr::new(ExprKind::Field(Box::new(expr), name), span)),
             })?,
     ))
 }
 
-fn expr2(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    let (input, prefix_ops) = many0(satisfy_opt(|token| match token {
+fn prefix_op(input: &[token::SpannedToken]) -> PResult<'_, (PrefixOp, Span)> {
+    let input1 = input;
+    let (input, op) = satisfy_opt("operator", |token| match token {
         token::Token::Operator(op) => match op.as_str() {
             "-" => Some(PrefixOp::Minus),
             "!" => Some(PrefixOp::Not),
             _ => None,
         },
         _ => None,
-    }))(input)?;
-    let (input, expr) = expr1(input)?;
+    })(input)?;
+    Ok((input, (op, input1[0].span)))
+}
+
+fn cast(input: &[token::SpannedToken]) -> PResult<'_, (Type, Span)> {
+    let (input, _) = satisfy_opt("`as`", |token| match token {
+        token::Token::Reserved(token::Reserved::As) => Some(()),
+        _ => None,
+    })(input)?;
+    let input1 = input;
+    let (input, ty) = cut(scalar_ty)(input)?;
+    Ok((input, (ty, input1[0].span)))
+}
+
+fn expr2<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let (input, prefix_ops) = many0(prefix_op)(input)?;
+    let (input, expr) = expr1(errors, input)?;
+    let expr = prefix_ops.into_iter().rev().fold(expr, |expr, (op, span)| {
+        let span = span.merge(&expr.span);
+        Expr::new(ExprKind::PrefixOp(op, Box::new(expr)), span)
+    });
+    let (input, casts) = many0(cast)(input)?;
     Ok((
         input,
-        prefix_ops
-            .into_iter()
-            .rev()
-            .fold(expr, |expr, op| Expr::PrefixOp(op, Box::new(expr))),
+        casts.into_iter().fold(expr, |expr, (ty, span)| {
+            let span = expr.span.merge(&span);
+            Expr::new(ExprKind::Cast(Box::new(expr), ty), span)
+        }),
     ))
 }
 
-fn expr3(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    let (input, expr) = expr2(input)?;
+fn expr3<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let (input, expr) = expr2(errors, input)?;
     let (input, binary_ops) = many0(tuple((
-        satisfy_opt(|token| match token {
+        satisfy_opt("operator", |token| match token {
             token::Token::Operator(op) => match op.as_str() {
                 "*" => Some(BinaryOp::Mul),
                 "/" => Some(BinaryOp::Div),
@@ -136,20 +498,24 @@ fn expr3(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
             },
             _ => None,
         }),
-        expr2,
+        cut(|input| expr2(errors, input)),
     )))(input)?;
     Ok((
         input,
         binary_ops.into_iter().fold(expr, |expr1, (op, expr2)| {
-            Expr::BinaryOp(Box::new(expr1), op, Box::new(expr2))
+            let span = expr1.span.merge(&expr2.span);
+            Expr::new(
+                ExprKind::BinaryOp(Box::new(expr1), op, Box::new(expr2)),
+                span,
+            )
         }),
     ))
 }
 
-fn expr4(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    let (input, expr) = expr3(input)?;
+fn expr4<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let (input, expr) = expr3(errors, input)?;
     let (input, binary_ops) = many0(tuple((
-        satisfy_opt(|token| match token {
+        satisfy_opt("operator", |token| match token {
             token::Token::Operator(op) => match op.as_str() {
                 "+" => Some(BinaryOp::Add),
                 "-" => Some(BinaryOp::Sub),
@@ -157,20 +523,24 @@ fn expr4(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
             },
             _ => None,
         }),
-        expr3,
+        cut(|input| expr3(errors, input)),
     )))(input)?;
     Ok((
         input,
         binary_ops.into_iter().fold(expr, |expr1, (op, expr2)| {
-            Expr::BinaryOp(Box::new(expr1), op, Box::new(expr2))
+            let span = expr1.span.merge(&expr2.span);
+            Expr::new(
+                ExprKind::BinaryOp(Box::new(expr1), op, Box::new(expr2)),
+                span,
+            )
         }),
     ))
 }
 
-fn expr5(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    let (input, expr) = expr4(input)?;
+fn expr5<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let (input, expr) = expr4(errors, input)?;
     let (input, binary_ops) = many0(tuple((
-        satisfy_opt(|token| match token {
+        satisfy_opt("operator", |token| match token {
             token::Token::Operator(op) => match op.as_str() {
                 "<" => Some(BinaryOp::Lt),
                 ">" => Some(BinaryOp::Gt),
@@ -180,20 +550,24 @@ fn expr5(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
             },
             _ => None,
         }),
-        expr4,
+        cut(|input| expr4(errors, input)),
     )))(input)?;
     Ok((
         input,
         binary_ops.into_iter().fold(expr, |expr1, (op, expr2)| {
-            Expr::BinaryOp(Box::new(expr1), op, Box::new(expr2))
+            let span = expr1.span.merge(&expr2.span);
+            Expr::new(
+                ExprKind::BinaryOp(Box::new(expr1), op, Box::new(expr2)),
+                span,
+            )
         }),
     ))
 }
 
-fn expr6(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    let (input, expr) = expr5(input)?;
+fn expr6<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let (input, expr) = expr5(errors, input)?;
     let (input, binary_ops) = many0(tuple((
-        satisfy_opt(|token| match token {
+        satisfy_opt("operator", |token| match token {
             token::Token::Operator(op) => match op.as_str() {
                 "==" => Some(BinaryOp::Eq),
                 "!=" => Some(BinaryOp::Ne),
@@ -201,239 +575,1062 @@ fn expr6(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
             },
             _ => None,
         }),
-        expr5,
+        cut(|input| expr5(errors, input)),
     )))(input)?;
     Ok((
         input,
         binary_ops.into_iter().fold(expr, |expr1, (op, expr2)| {
-            Expr::BinaryOp(Box::new(expr1), op, Box::new(expr2))
+            let span = expr1.span.merge(&expr2.span);
+            Expr::new(
+                ExprKind::BinaryOp(Box::new(expr1), op, Box::new(expr2)),
+                span,
+            )
         }),
     ))
 }
 
-fn expr7(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    let (input, expr) = expr6(input)?;
+fn expr7<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let (input, expr) = expr6(errors, input)?;
     let (input, binary_ops) = many0(tuple((
-        satisfy_opt(|token| match token {
+        satisfy_opt("operator", |token| match token {
             token::Token::Operator(op) => match op.as_str() {
                 "&&" => Some(BinaryOp::And),
                 _ => None,
             },
             _ => None,
         }),
-        expr6,
+        cut(|input| expr6(errors, input)),
     )))(input)?;
     Ok((
         input,
         binary_ops.into_iter().fold(expr, |expr1, (op, expr2)| {
-            Expr::BinaryOp(Box::new(expr1), op, Box::new(expr2))
+            let span = expr1.span.merge(&expr2.span);
+            Expr::new(
+                ExprKind::BinaryOp(Box::new(expr1), op, Box::new(expr2)),
+                span,
+            )
         }),
     ))
 }
 
-fn expr8(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    let (input, expr) = expr7(input)?;
+fn expr8<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let (input, expr) = expr7(errors, input)?;
     let (input, binary_ops) = many0(tuple((
-        satisfy_opt(|token| match token {
+        satisfy_opt("operator", |token| match token {
             token::Token::Operator(op) => match op.as_str() {
                 "||" => Some(BinaryOp::Or),
                 _ => None,
             },
             _ => None,
         }),
-        expr7,
+        cut(|input| expr7(errors, input)),
     )))(input)?;
     Ok((
         input,
         binary_ops.into_iter().fold(expr, |expr1, (op, expr2)| {
-            Expr::BinaryOp(Box::new(expr1), op, Box::new(expr2))
+            let span = expr1.span.merge(&expr2.span);
+            Expr::new(
+                ExprKind::BinaryOp(Box::new(expr1), op, Box::new(expr2)),
+                span,
+            )
         }),
     ))
 }
 
-fn expr9(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
+// 複合代入演算子の場合は対応する二項演算子を返す
+fn assign_op(input: &[token::SpannedToken]) -> PResult<'_, Option<BinaryOp>> {
+    satisfy_opt("`=`", |token| match token {
+        token::Token::ReservedOp(op) => match op {
+            token::ReservedOp::Assign => Some(None),
+            token::ReservedOp::AddAssign => Some(Some(BinaryOp::Add)),
+            token::ReservedOp::SubAssign => Some(Some(BinaryOp::Sub)),
+            token::ReservedOp::MulAssign => Some(Some(BinaryOp::Mul)),
+            token::ReservedOp::DivAssign => Some(Some(BinaryOp::Div)),
+            token::ReservedOp::ModAssign => Some(Some(BinaryOp::Mod)),
+            token::ReservedOp::FatArrow => None,
+        },
+        _ => None,
+    })(input)
+}
+
+fn expr9<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
     let input1 = input;
-    let (input, expr) = expr8(input)?;
-    let (input, exprs) = many0(preceded(
-        satisfy_opt(|token| match token {
-            token::Token::ReservedOp(token::ReservedOp::Assign) => Some(()),
-            _ => None,
-        }),
-        expr8,
-    ))(input)?;
-    let (last, init) = match exprs.split_last() {
-        Some((last, init)) => (last.clone(), {
-            let mut exprs = init.to_vec();
-            exprs.insert(0, expr);
-            exprs
-        }),
-        None => return Ok((input, expr)),
-    };
+    let (input, expr) = expr8(errors, input)?;
+    let (input, rhss) = many0(tuple((assign_op, cut(|input| expr8(errors, input)))))(input)?;
+    let mut last = expr;
+    let mut init = Vec::new();
+    for (op, rhs) in rhss {
+        init.push((last, op));
+        last = rhs;
+    }
     Ok((
         input,
         init.into_iter()
             .rev()
-            .try_fold(last, |rhs, lhs| match lhs {
-                Expr::Ident(ident) => Ok(Expr::Assign(ident.clone(), Box::new(rhs))),
-                _ => Err(nom::Err::Error(nom::error::Error::from_error_kind(
+            .try_fold(last, |rhs, (lhs, op)| match lhs.kind {
+                ExprKind::Ident(_) | ExprKind::Index(..) | ExprKind::Field(..) => {
+                    let span = lhs.span.merge(&rhs.span);
+                    Ok(match op {
+                        Some(op) => compound_assign(lhs, op, rhs, span),
+                        None => Expr::new(ExprKind::Assign(Box::new(lhs), Box::new(rhs)), span),
+                    })
+                }
+                _ => Err(nom::Err::Failure(Error::new(
                     input1,
-                    nom::error::ErrorKind::Fail,
+                    "assignable expression",
                 ))),
             })?,
     ))
 }
 
-fn if_(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    let (input, _) = satisfy_opt(|token| match token {
+// `x += e` は `x = x + e` にする
+// 配列や構造体は一時変数に入れて、添字などの副作用が1回だけ起きるようにする
+fn compound_assign(lhs: Expr, op: BinaryOp, rhs: Expr, span: Span) -> Expr {
+    let temp = |name: &str, span| Expr::new(ExprKind::Ident(name.to_string()), span);
+    let mut temps = Vec::new();
+    let lhs_span = lhs.span;
+    let lhs = match lhs.kind {
+        ExprKind::Index(array, idx) => {
+            let (array_span, idx_span) = (array.span, idx.span);
+            temps.push(("%array", *array));
+            temps.push(("%idx", *idx));
+            Expr::new(
+                ExprKind::Index(
+                    Box::new(temp("%array", array_span)),
+                    Box::new(temp("%idx", idx_span)),
+                ),
+                lhs_span,
+            )
+        }
+        // 識別子は評価しても副作用がない。`m.x`のようにimportした名前の修飾にもなるのでそのまま使う
+        ExprKind::Field(operand, name) if !matches!(operand.kind, ExprKind::Ident(_)) => {
+            let operand_span = operand.span;
+            temps.push(("%operand", *operand));
+            Expr::new(
+                ExprKind::Field(Box::new(temp("%operand", operand_span)), name),
+                lhs_span,
+            )
+        }
+        kind => Expr::new(kind, lhs_span),
+    };
+    let rhs = Expr::new(
+        ExprKind::BinaryOp(Box::new(lhs.clone()), op, Box::new(rhs)),
+        span,
+    );
+    let assign = Expr::new(ExprKind::Assign(Box::new(lhs), Box::new(rhs)), span);
+    temps.into_iter().rev().fold(assign, |body, (name, init)| {
+        Expr::new(
+            ExprKind::Var(name.to_string(), None, Box::new(init), Box::new(body)),
+            span,
+        )
+    })
+}
+
+fn if_<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let input1 = input;
+    let (input, _) = satisfy_opt("`if`", |token| match token {
         token::Token::Reserved(token::Reserved::If) => Some(()),
         _ => None,
     })(input)?;
 
-    let (input, _) = satisfy_opt(|token| match token {
+    let (input, _) = cut(satisfy_opt("`(`", |token| match token {
         token::Token::OpenParen => Some(()),
         _ => None,
-    })(input)?;
+    }))(input)?;
 
-    let (input, expr1) = expr(input)?;
+    let (input, expr1) = cut(|input| expr(errors, input))(input)?;
 
-    let (input, _) = satisfy_opt(|token| match token {
+    let (input, _) = cut(satisfy_opt("`)`", |token| match token {
         token::Token::CloseParen => Some(()),
         _ => None,
-    })(input)?;
+    }))(input)?;
+
+    let (input, expr2) = cut(|input| expr(errors, input))(input)?;
+
+    let (input, expr3) = opt(preceded(
+        satisfy_opt("`else`", |token| match token {
+            token::Token::Reserved(token::Reserved::Else) => Some(()),
+            _ => None,
+        }),
+        cut(|input| expr(errors, input)),
+    ))(input)?;
+
+    Ok((
+        input,
+        Expr::new(
+            ExprKind::If(Box::new(expr1), Box::new(expr2), expr3.map(Box::new)),
+            consumed_span(input1, input),
+        ),
+    ))
+}
+
+// 符号付きの整数リテラル
+fn int_pattern(input: &[token::SpannedToken]) -> PResult<'_, Expr> {
+    let input1 = input;
+    let (input, minus) = opt(satisfy_opt("`-`", |token| match token {
+        token::Token::Operator(op) if op == "-" => Some(()),
+        _ => None,
+    }))(input)?;
+    let input2 = input;
+    let (input, (x, ty)) = if minus.is_some() {
+        cut(int_literal)(input)?
+    } else {
+        int_literal(input)?
+    };
+    let literal = Expr::new(ExprKind::IntLiteral(x, ty), input2[0].span);
+    Ok((
+        input,
+        if minus.is_some() {
+            Expr::new(
+                ExprKind::PrefixOp(PrefixOp::Minus, Box::new(literal)),
+                consumed_span(input1, input),
+            )
+        } else {
+            literal
+        },
+    ))
+}
+
+fn match_arm<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, (Expr, Expr)> {
+    let (input, pattern) = int_pattern(input)?;
+    let (input, _) = cut(fat_arrow)(input)?;
+    let (input, body) = cut(|input| expr(errors, input))(input)?;
+    Ok((input, (pattern, body)))
+}
 
-    let (input, expr2) = expr(input)?;
+fn fat_arrow(input: &[token::SpannedToken]) -> PResult<'_, ()> {
+    satisfy_opt("`=>`", |token| match token {
+        token::Token::ReservedOp(token::ReservedOp::FatArrow) => Some(()),
+        _ => None,
+    })(input)
+}
 
-    let (input, _) = satisfy_opt(|token| match token {
-        token::Token::Reserved(token::Reserved::Else) => Some(()),
+fn match_<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let input1 = input;
+    let (input, _) = satisfy_opt("`match`", |token| match token {
+        token::Token::Reserved(token::Reserved::Match) => Some(()),
         _ => None,
     })(input)?;
 
-    let (input, expr3) = expr(input)?;
+    let (input, _) = cut(satisfy_opt("`(`", |token| match token {
+        token::Token::OpenParen => Some(()),
+        _ => None,
+    }))(input)?;
+
+    let (input, scrutinee) = cut(|input| expr(errors, input))(input)?;
+
+    let (input, _) = cut(satisfy_opt("`)`", |token| match token {
+        token::Token::CloseParen => Some(()),
+        _ => None,
+    }))(input)?;
+
+    let (input, _) = cut(satisfy_opt("`{`", |token| match token {
+        token::Token::OpenBrace => Some(()),
+        _ => None,
+    }))(input)?;
+
+    let (input, arms) = many0(terminated(
+        |input| match_arm(errors, input),
+        cut(satisfy_opt("`,`", |token| match token {
+            token::Token::Comma => Some(()),
+            _ => None,
+        })),
+    ))(input)?;
+
+    let (input, _) = cut(satisfy_opt("`_`", |token| match token {
+        token::Token::Underscore => Some(()),
+        _ => None,
+    }))(input)?;
+
+    let (input, _) = cut(fat_arrow)(input)?;
+
+    let (input, default) = cut(|input| expr(errors, input))(input)?;
+
+    let (input, _) = opt(satisfy_opt("`,`", |token| match token {
+        token::Token::Comma => Some(()),
+        _ => None,
+    }))(input)?;
+
+    let (input, _) = cut(satisfy_opt("`}`", |token| match token {
+        token::Token::CloseBrace => Some(()),
+        _ => None,
+    }))(input)?;
 
     Ok((
         input,
-        Expr::If(Box::new(expr1), Box::new(expr2), Box::new(expr3)),
+        Expr::new(
+            ExprKind::Match(Box::new(scrutinee), arms, Box::new(default)),
+            consumed_span(input1, input),
+        ),
     ))
 }
 
-fn while_(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    let (input, _) = satisfy_opt(|token| match token {
+fn while_<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let input1 = input;
+    let (input, _) = satisfy_opt("`while`", |token| match token {
         token::Token::Reserved(token::Reserved::While) => Some(()),
         _ => None,
     })(input)?;
 
-    let (input, _) = satisfy_opt(|token| match token {
+    let (input, _) = cut(satisfy_opt("`(`", |token| match token {
         token::Token::OpenParen => Some(()),
         _ => None,
-    })(input)?;
+    }))(input)?;
 
-    let (input, expr1) = expr(input)?;
+    let (input, expr1) = cut(|input| expr(errors, input))(input)?;
 
-    let (input, _) = satisfy_opt(|token| match token {
+    let (input, _) = cut(satisfy_opt("`)`", |token| match token {
         token::Token::CloseParen => Some(()),
         _ => None,
+    }))(input)?;
+
+    let (input, expr2) = cut(|input| expr(errors, input))(input)?;
+
+    Ok((
+        input,
+        Expr::new(
+            ExprKind::While(Box::new(expr1), Box::new(expr2)),
+            consumed_span(input1, input),
+        ),
+    ))
+}
+
+fn for_init<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, ForInit> {
+    alt((
+        map(
+            tuple((
+                satisfy_opt("`var`", |token| match token {
+                    token::Token::Reserved(token::Reserved::Var) => Some(()),
+                    _ => None,
+                }),
+                cut(ident),
+                opt(type_annotation),
+                cut(satisfy_opt("`=`", |token| match token {
+                    token::Token::ReservedOp(token::ReservedOp::Assign) => Some(()),
+                    _ => None,
+                })),
+                cut(|input| expr(errors, input)),
+            )),
+            |(_, ident, ty, _, expr)| ForInit::Var(ident, ty, expr),
+        ),
+        map(|input| expr(errors, input), ForInit::Expr),
+    ))(input)
+}
+
+enum ForInit {
+    Var(String, Option<Ty>, Expr),
+    Expr(Expr),
+}
+
+// for (init; cond; step) body を
+// var first = 1 in while ({ if (first) first = 0 else step; cond }) body
+// に脱糖する。continueでstepが飛ばされないようにstepは条件式の中で評価する
+fn for_<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let input1 = input;
+    let (input, _) = satisfy_opt("`for`", |token| match token {
+        token::Token::Reserved(token::Reserved::For) => Some(()),
+        _ => None,
     })(input)?;
 
-    let (input, expr2) = expr(input)?;
+    let (input, _) = cut(satisfy_opt("`(`", |token| match token {
+        token::Token::OpenParen => Some(()),
+        _ => None,
+    }))(input)?;
+
+    let (input, init) = opt(|input| for_init(errors, input))(input)?;
 
-    Ok((input, Expr::While(Box::new(expr1), Box::new(expr2))))
+    let (input, _) = cut(satisfy_opt("`;`", |token| match token {
+        token::Token::SemiColon => Some(()),
+        _ => None,
+    }))(input)?;
+
+    let (input, cond) = cut(|input| expr(errors, input))(input)?;
+
+    let (input, _) = cut(satisfy_opt("`;`", |token| match token {
+        token::Token::SemiColon => Some(()),
+        _ => None,
+    }))(input)?;
+
+    let (input, step) = opt(|input| expr(errors, input))(input)?;
+
+    let (input, _) = cut(satisfy_opt("`)`", |token| match token {
+        token::Token::CloseParen => Some(()),
+        _ => None,
+    }))(input)?;
+
+    let (input, body) = cut(|input| expr(errors, input))(input)?;
+
+    let span = consumed_span(input1, input);
+    // 識別子として書けない名前にして衝突を避ける
+    let first = "%first".to_string();
+    let cond_span = cond.span;
+    let cond = Expr::new(
+        ExprKind::Block(vec![
+            Expr::new(
+                ExprKind::If(
+                    Box::new(Expr::new(ExprKind::Ident(first.clone()), span)),
+                    Box::new(Expr::new(
+                        ExprKind::Assign(
+                            Box::new(Expr::new(ExprKind::Ident(first.clone()), span)),
+                            Box::new(Expr::new(ExprKind::IntLiteral(0, None), span)),
+                        ),
+                        span,
+                    )),
+                    // 条件式の型はi32にそろえるので、stepの値は捨てる
+                    step.map(|step| {
+                        let step_span = step.span;
+                        Box::new(Expr::new(
+                            ExprKind::Block(vec![
+                                step,
+                                Expr::new(ExprKind::IntLiteral(0, None), step_span),
+                            ]),
+                            step_span,
+                        ))
+                    }),
+                ),
+                span,
+            ),
+            cond,
+        ]),
+        cond_span,
+    );
+    let while_ = Expr::new(
+        ExprKind::Var(
+            first,
+            None,
+            Box::new(Expr::new(ExprKind::IntLiteral(1, None), span)),
+            Box::new(Expr::new(
+                ExprKind::While(Box::new(cond), Box::new(body)),
+                span,
+            )),
+        ),
+        span,
+    );
+
+    Ok((
+        input,
+        match init {
+            Some(ForInit::Var(ident, ty, expr)) => Expr::new(
+                ExprKind::Var(ident, ty, Box::new(expr), Box::new(while_)),
+                span,
+            ),
+            Some(ForInit::Expr(expr)) => Expr::new(ExprKind::Block(vec![expr, while_]), span),
+            None => while_,
+        },
+    ))
+}
+
+// `;`か対応する`}`、または次の`func`までトークンを読み飛ばす
+fn skip_to_sync_point(input: &[token::SpannedToken]) -> &[token::SpannedToken] {
+    let mut depth = 0;
+    let mut input = input;
+    while let Some((token, rest)) = input.split_first() {
+        match token.token {
+            token::Token::OpenBrace => depth += 1,
+            token::Token::CloseBrace => {
+                if depth == 0 {
+                    return input;
+                }
+                depth -= 1;
+            }
+            token::Token::SemiColon if depth == 0 => return rest,
+            token::Token::Reserved(token::Reserved::Func)
+            | token::Token::Reserved(token::Reserved::Global)
+            | token::Token::Reserved(token::Reserved::Struct)
+            | token::Token::Reserved(token::Reserved::Import)
+            | token::Token::DocComment(_) => return input,
+            _ => {}
+        }
+        input = rest;
+    }
+    input
 }
 
-fn block(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    let (input, _) = satisfy_opt(|token| match token {
+fn block<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let input1 = input;
+    let (mut input, _) = satisfy_opt("`{`", |token| match token {
         token::Token::OpenBrace => Some(()),
         _ => None,
     })(input)?;
 
-    let (input, exprs) = many0(terminated(
-        expr,
-        satisfy_opt(|token| match token {
-            token::Token::SemiColon => Some(()),
+    let mut exprs = Vec::new();
+    loop {
+        if let Ok((rest, _)) = satisfy_opt("`}`", |token| match token {
+            token::Token::CloseBrace => Some(()),
             _ => None,
-        }),
-    ))(input)?;
+        })(input)
+        {
+            input = rest;
+            break;
+        }
 
-    let (input, _) = satisfy_opt(|token| match token {
-        token::Token::CloseBrace => Some(()),
-        _ => None,
-    })(input)?;
+        // 閉じられていないブロックはここで打ち切って、次の関数から解析を続ける
+        if matches!(
+            input.first().map(|token| &token.token),
+            None | Some(token::Token::Reserved(token::Reserved::Func))
+                | Some(token::Token::Reserved(token::Reserved::Global))
+                | Some(token::Token::Reserved(token::Reserved::Struct))
+                | Some(token::Token::Reserved(token::Reserved::Import))
+                | Some(token::Token::DocComment(_))
+        ) {
+            report(errors, Error::new(input, "`}`"));
+            break;
+        }
 
-    Ok((input, Expr::Block(exprs)))
+        match terminated(
+            |input| expr(errors, input),
+            cut(satisfy_opt("`;`", |token| match token {
+                token::Token::SemiColon => Some(()),
+                _ => None,
+            })),
+        )(input)
+        {
+            Ok((rest, expr)) => {
+                exprs.push(expr);
+                input = rest;
+            }
+            Err(nom::Err::Error(mut e)) => {
+                if e.input.len() == input.len() {
+                    e.expected.push("`}`");
+                }
+                input = skip_to_sync_point(e.input);
+                report(errors, e);
+            }
+            Err(nom::Err::Failure(e)) => {
+                input = skip_to_sync_point(e.input);
+                report(errors, e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((
+        input,
+        Expr::new(ExprKind::Block(exprs), consumed_span(input1, input)),
+    ))
 }
 
-fn var(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    let (input, _) = satisfy_opt(|token| match token {
+fn var<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let input1 = input;
+    let (input, _) = satisfy_opt("`var`", |token| match token {
         token::Token::Reserved(token::Reserved::Var) => Some(()),
         _ => None,
     })(input)?;
 
-    let (input, ident) = ident(input)?;
+    let (input, ident) = cut(ident)(input)?;
+
+    let (input, ty) = opt(type_annotation)(input)?;
 
-    let (input, _) = satisfy_opt(|token| match token {
+    let (input, _) = cut(satisfy_opt("`=`", |token| match token {
         token::Token::ReservedOp(token::ReservedOp::Assign) => Some(()),
         _ => None,
-    })(input)?;
+    }))(input)?;
 
-    let (input, expr1) = expr(input)?;
+    let (input, expr1) = cut(|input| expr(errors, input))(input)?;
 
-    let (input, _) = satisfy_opt(|token| match token {
+    let (input, _) = cut(satisfy_opt("`in`", |token| match token {
         token::Token::Reserved(token::Reserved::In) => Some(()),
         _ => None,
+    }))(input)?;
+
+    let (input, expr2) = cut(|input| expr(errors, input))(input)?;
+
+    Ok((
+        input,
+        Expr::new(
+            ExprKind::Var(ident, ty, Box::new(expr1), Box::new(expr2)),
+            consumed_span(input1, input),
+        ),
+    ))
+}
+
+fn return_<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    let input1 = input;
+    let (input, _) = satisfy_opt("`return`", |token| match token {
+        token::Token::Reserved(token::Reserved::Return) => Some(()),
+        _ => None,
     })(input)?;
 
-    let (input, expr2) = expr(input)?;
+    let (input, expr) = cut(|input| expr(errors, input))(input)?;
 
-    Ok((input, Expr::Var(ident, Box::new(expr1), Box::new(expr2))))
+    Ok((
+        input,
+        Expr::new(
+            ExprKind::Return(Box::new(expr)),
+            consumed_span(input1, input),
+        ),
+    ))
 }
 
-fn expr(input: &[token::Token]) -> IResult<&[token::Token], Expr> {
-    alt((expr9, if_, while_, block, var))(input)
+fn break_(input: &[token::SpannedToken]) -> PResult<'_, Expr> {
+    let input1 = input;
+    let (input, _) = satisfy_opt("`break`", |token| match token {
+        token::Token::Reserved(token::Reserved::Break) => Some(()),
+        _ => None,
+    })(input)?;
+
+    Ok((input, Expr::new(ExprKind::Break, input1[0].span)))
 }
 
-fn func(input: &[token::Token]) -> IResult<&[token::Token], Func> {
-    let (input, _) = satisfy_opt(|token| match token {
-        token::Token::Reserved(token::Reserved::Func) => Some(()),
+fn continue_(input: &[token::SpannedToken]) -> PResult<'_, Expr> {
+    let input1 = input;
+    let (input, _) = satisfy_opt("`continue`", |token| match token {
+        token::Token::Reserved(token::Reserved::Continue) => Some(()),
         _ => None,
     })(input)?;
 
-    let (input, func_ident) = ident(input)?;
+    Ok((input, Expr::new(ExprKind::Continue, input1[0].span)))
+}
+
+fn expr<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Expr> {
+    expected(
+        "expression",
+        alt((
+            |input| expr9(errors, input),
+            |input| if_(errors, input),
+            |input| match_(errors, input),
+            |input| while_(errors, input),
+            |input| for_(errors, input),
+            |input| block(errors, input),
+            |input| var(errors, input),
+            |input| return_(errors, input),
+            break_,
+            continue_,
+        )),
+    )(input)
+}
+
+fn doc_comment(input: &[token::SpannedToken]) -> PResult<'_, String> {
+    satisfy_opt("doc comment", |token| match token {
+        token::Token::DocComment(doc) => Some(doc.clone()),
+        _ => None,
+    })(input)
+}
 
-    let (input, _) = satisfy_opt(|token| match token {
+// 型を省略した場合はi32
+fn params(input: &[token::SpannedToken]) -> PResult<'_, Vec<(String, Ty)>> {
+    let (input, _) = satisfy_opt("`(`", |token| match token {
         token::Token::OpenParen => Some(()),
         _ => None,
     })(input)?;
 
     let (input, params) = separated_list0(
-        satisfy_opt(|token| match token {
+        satisfy_opt("`,`", |token| match token {
             token::Token::Comma => Some(()),
             _ => None,
         }),
-        ident,
+        tuple((ident, opt(type_annotation))),
     )(input)?;
 
-    let (input, _) = satisfy_opt(|token| match token {
+    let (input, _) = cut(satisfy_opt("`)`", |token| match token {
         token::Token::CloseParen => Some(()),
         _ => None,
+    }))(input)?;
+
+    Ok((
+        input,
+        params
+            .into_iter()
+            .map(|(param, ty)| (param, ty.unwrap_or(Ty::I32)))
+            .collect(),
+    ))
+}
+
+fn func<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Func> {
+    let (input, docs) = many0(doc_comment)(input)?;
+
+    let input1 = input;
+    let (input, _) = satisfy_opt("`func`", |token| match token {
+        token::Token::Reserved(token::Reserved::Func) => Some(()),
+        _ => None,
     })(input)?;
 
-    let (input, expr) = expr(input)?;
+    let (input, func_ident) = cut(ident)(input)?;
+
+    let (input, args) = cut(params)(input)?;
+
+    let (input, ret) = opt(type_annotation)(input)?;
+
+    let (input, expr) = cut(|input| expr(errors, input))(input)?;
 
     Ok((
         input,
         Func {
             name: func_ident,
-            args: params,
+            args,
+            ret: ret.unwrap_or(Ty::I32),
             body: expr,
+            doc: if docs.is_empty() {
+                None
+            } else {
+                Some(docs.join("\n"))
+            },
+            span: consumed_span(input1, input),
         },
     ))
 }
 
-fn module(input: &[token::Token]) -> IResult<&[token::Token], Module> {
-    let (input, funcs) = many0(func)(input)?;
+fn import(input: &[token::SpannedToken]) -> PResult<'_, Import> {
+    let input1 = input;
+    let (input, _) = satisfy_opt("`import`", |token| match token {
+        token::Token::Reserved(token::Reserved::Import) => Some(()),
+        _ => None,
+    })(input)?;
+
+    let (input, path) = cut(string_literal)(input)?;
+
+    let (input, _) = cut(satisfy_opt("`as`", |token| match token {
+        token::Token::Reserved(token::Reserved::As) => Some(()),
+        _ => None,
+    }))(input)?;
+
+    let (input, name) = cut(ident)(input)?;
+
+    let (input, _) = cut(satisfy_opt("`;`", |token| match token {
+        token::Token::SemiColon => Some(()),
+        _ => None,
+    }))(input)?;
+
+    Ok((
+        input,
+        Import {
+            path,
+            name,
+            span: consumed_span(input1, input),
+        },
+    ))
+}
+
+fn global<'a>(errors: &Errors, input: &'a [token::SpannedToken]) -> PResult<'a, Global> {
+    let input1 = input;
+    let (input, _) = satisfy_opt("`global`", |token| match token {
+        token::Token::Reserved(token::Reserved::Global) => Some(()),
+        _ => None,
+    })(input)?;
+
+    let (input, ident) = cut(ident)(input)?;
+
+    let (input, ty) = opt(type_annotation)(input)?;
+
+    let (input, _) = cut(satisfy_opt("`=`", |token| match token {
+        token::Token::ReservedOp(token::ReservedOp::Assign) => Some(()),
+        _ => None,
+    }))(input)?;
+
+    let (input, init) = cut(|input| expr(errors, input))(input)?;
 
-    Ok((input, Module { funcs }))
+    let (input, _) = cut(satisfy_opt("`;`", |token| match token {
+        token::Token::SemiColon => Some(()),
+        _ => None,
+    }))(input)?;
+
+    Ok((
+        input,
+        Global {
+            name: ident,
+            ty,
+            init,
+            span: consumed_span(input1, input),
+        },
+    ))
 }
 
-pub fn parse(input: &[token::Token]) -> IResult<&[token::Token], Module> {
-    terminated(module, eof)(input)
+fn struct_(input: &[token::SpannedToken]) -> PResult<'_, Struct> {
+    let input1 = input;
+    let (input, _) = satisfy_opt("`struct`", |token| match token {
+        token::Token::Reserved(token::Reserved::Struct) => Some(()),
+        _ => None,
+    })(input)?;
+
+    let (input, name) = cut(ident)(input)?;
+
+    let (input, _) = cut(satisfy_opt("`{`", |token| match token {
+        token::Token::OpenBrace => Some(()),
+        _ => None,
+    }))(input)?;
+
+    let (input, fields) = separated_list0(
+        satisfy_opt("`,`", |token| match token {
+            token::Token::Comma => Some(()),
+            _ => None,
+        }),
+        tuple((ident, opt(type_annotation))),
+    )(input)?;
+
+    let (input, _) = opt(satisfy_opt("`,`", |token| match token {
+        token::Token::Comma => Some(()),
+        _ => None,
+    }))(input)?;
+
+    let (input, _) = cut(satisfy_opt("`}`", |token| match token {
+        token::Token::CloseBrace => Some(()),
+        _ => None,
+    }))(input)?;
+
+    Ok((
+        input,
+        Struct {
+            name,
+            fields: fields
+                .into_iter()
+                .map(|(name, ty)| (name, ty.unwrap_or(Ty::I32)))
+                .collect(),
+            span: consumed_span(input1, input),
+        },
+    ))
+}
+
+enum Item {
+    Import(Import),
+    Func(Func),
+    Global(Global),
+    Struct(Struct),
+}
+
+fn module(errors: &Errors, input: &[token::SpannedToken]) -> Module {
+    let mut imports = Vec::new();
+    let mut structs = Vec::new();
+    let mut globals = Vec::new();
+    let mut funcs = Vec::new();
+    let mut input = input;
+    while !input.is_empty() {
+        match alt((
+            map(import, Item::Import),
+            map(|input| func(errors, input), Item::Func),
+            map(|input| global(errors, input), Item::Global),
+            map(struct_, Item::Struct),
+        ))(input)
+        {
+            Ok((rest, item)) => {
+                match item {
+                    Item::Import(import) => imports.push(import),
+                    Item::Func(func) => funcs.push(func),
+                    Item::Global(global) => globals.push(global),
+                    Item::Struct(struct_) => structs.push(struct_),
+                }
+                input = rest;
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                let rest = if e.input.len() == input.len() {
+                    &input[1..]
+                } else {
+                    e.input
+                };
+                let skip = rest
+                    .iter()
+                    .position(|token| {
+                        matches!(
+                            token.token,
+                            token::Token::Reserved(token::Reserved::Func)
+                                | token::Token::Reserved(token::Reserved::Global)
+                                | token::Token::Reserved(token::Reserved::Struct)
+                                | token::Token::Reserved(token::Reserved::Import)
+                                | token::Token::DocComment(_)
+                        )
+                    })
+                    .unwrap_or(rest.len());
+                input = &rest[skip..];
+                report(errors, e);
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    Module {
+        imports,
+        structs,
+        globals,
+        funcs,
+    }
+}
+
+fn syntax_error(all: &[token::SpannedToken], error: SyntaxError) -> CompileError {
+    let expected = error.expected.iter().map(|s| s.to_string()).collect();
+    match all.get(all.len() - error.remaining) {
+        Some(token) => CompileError::Parse {
+            found: Some(token.token.clone()),
+            expected,
+            span: token.span,
+        },
+        None => CompileError::Parse {
+            found: None,
+            expected,
+            span: match all.last() {
+                Some(token) => Span {
+                    file: token.span.file,
+                    start: token.span.end,
+                    end: token.span.end,
+                    line: token.span.line,
+                    column: token.span.column + (token.span.end - token.span.start),
+                },
+                None => Span {
+                    file: 0,
+                    start: 0,
+                    end: 0,
+                    line: 1,
+                    column: 1,
+                },
+            },
+        },
+    }
+}
+
+// 関数の直前にあるもの以外のドキュメントコメントは普通のコメントとして扱う
+fn strip_detached_doc_comments(input: &[token::SpannedToken]) -> Vec<token::SpannedToken> {
+    let mut tokens = Vec::new();
+    for (i, token) in input.iter().enumerate() {
+        if let token::Token::DocComment(_) = token.token {
+            let next = input[i + 1..]
+                .iter()
+                .find(|token| !matches!(token.token, token::Token::DocComment(_)));
+            if !matches!(
+                next.map(|token| &token.token),
+                Some(token::Token::Reserved(token::Reserved::Func))
+            ) {
+                continue;
+            }
+        }
+        tokens.push(token.clone());
+    }
+    tokens
+}
+
+// 構文エラーがあっても解析できた部分のModuleを返す
+pub fn parse(input: &[token::SpannedToken]) -> (Module, Vec<CompileError>) {
+    let input = strip_detached_doc_comments(input);
+    let input = input.as_slice();
+    let errors = RefCell::new(Vec::new());
+    let module = module(&errors, input);
+    let mut errors = errors.into_inner();
+    errors.sort_by_key(|error| std::cmp::Reverse(error.remaining));
+    (
+        module,
+        errors
+            .into_iter()
+            .map(|error| syntax_error(input, error))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::tokenize;
+
+    fn parse_str(input: &str) -> (Module, Vec<CompileError>) {
+        parse(&tokenize(input, 0).unwrap())
+    }
+
+    #[test]
+    fn syntax_errors_are_not_shared_between_parses() {
+        let (_, errors) = parse_str("func f() { 1 + ; } func g() { ) ; }");
+        assert_eq!(errors.len(), 2);
+        let (module, errors) = parse_str("func f() { 1; }");
+        assert!(errors.is_empty());
+        assert_eq!(module.funcs.len(), 1);
+    }
+
+    #[test]
+    fn syntax_errors_are_reported_in_source_order() {
+        let (module, errors) = parse_str("func f() { 1 + ; } func g() { 2; } func h() { ) ; }");
+        assert_eq!(module.funcs.len(), 3);
+        let columns = errors.iter().map(|e| e.span().column).collect::<Vec<_>>();
+        assert!(columns.windows(2).all(|w| w[0] < w[1]), "{:?}", columns);
+    }
+
+    #[test]
+    fn ast_nodes_have_spans() {
+        let (module, errors) = parse_str("func f() {\n  1 + 23;\n}");
+        assert!(errors.is_empty());
+        assert_eq!(module.funcs[0].span.line, 1);
+        let span = body(&module).span;
+        assert_eq!(
+            (span.start, span.end, span.line, span.column),
+            (13, 19, 2, 3)
+        );
+        match &body(&module).kind {
+            ExprKind::BinaryOp(lhs, BinaryOp::Add, rhs) => {
+                assert_eq!((lhs.span.start, lhs.span.end), (13, 14));
+                assert_eq!((rhs.span.start, rhs.span.end), (17, 19));
+            }
+            kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test]
+    fn doc_comments_attach_to_functions() {
+        let (module, errors) = parse_str("## first\n## second\nfunc f() { 1; }\nfunc g() { 2; }");
+        assert!(errors.is_empty());
+        assert_eq!(module.funcs[0].doc.as_deref(), Some("first\nsecond"));
+        assert_eq!(module.funcs[1].doc, None);
+    }
+
+    fn body(module: &Module) -> &Expr {
+        match &module.funcs[0].body.kind {
+            ExprKind::Block(exprs) => &exprs[0],
+            _ => &module.funcs[0].body,
+        }
+    }
+
+    #[test]
+    fn compound_assign_evaluates_index_once() {
+        let (module, errors) = parse_str("func g(a: [i32]) { a[f()] += 1; }");
+        assert!(errors.is_empty());
+        let body = body(&module);
+        assert_eq!(format!("{:?}", body).matches("Call(").count(), 1);
+        match &body.kind {
+            ExprKind::Var(array, None, _, body) => match &body.kind {
+                ExprKind::Var(idx, None, _, _) => {
+                    assert_eq!((array.as_str(), idx.as_str()), ("%array", "%idx"))
+                }
+                kind => panic!("{:?}", kind),
+            },
+            kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test]
+    fn compound_assign_to_variable_has_no_temporaries() {
+        let (module, errors) = parse_str("func g(x: i32) { x -= 1; }");
+        assert!(errors.is_empty());
+        assert!(matches!(body(&module).kind, ExprKind::Assign(..)));
+    }
+
+    #[test]
+    fn compound_assign_to_qualified_name_has_no_temporaries() {
+        let (module, errors) = parse_str("func g() { m.x += 1; }");
+        assert!(errors.is_empty());
+        match &body(&module).kind {
+            ExprKind::Assign(lhs, _) => assert!(matches!(lhs.kind, ExprKind::Field(..))),
+            kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test]
+    fn for_step_value_is_discarded() {
+        let (module, errors) = parse_str("func g() { for (var i: i64 = 0; i < 3; i += 1) {}; }");
+        assert!(errors.is_empty());
+        let cond = match &body(&module).kind {
+            ExprKind::Var(_, _, _, body) => match &body.kind {
+                ExprKind::Var(_, _, _, body) => match &body.kind {
+                    ExprKind::While(cond, _) => cond,
+                    kind => panic!("{:?}", kind),
+                },
+                kind => panic!("{:?}", kind),
+            },
+            kind => panic!("{:?}", kind),
+        };
+        match &cond.kind {
+            ExprKind::Block(exprs) => match &exprs[0].kind {
+                ExprKind::If(_, _, Some(step)) => match &step.kind {
+                    ExprKind::Block(exprs) => {
+                        assert!(matches!(exprs[1].kind, ExprKind::IntLiteral(0, None)))
+                    }
+                    kind => panic!("{:?}", kind),
+                },
+                kind => panic!("{:?}", kind),
+            },
+            kind => panic!("{:?}", kind),
+        }
+    }
 }
@@ -0,0 +1,23 @@
+#[derive(Debug, PartialEq, Clone, Copy, Hash, Eq)]
+pub struct Span {
+    // 読み込んだファイルの番号
+    pub file: usize,
+    // byte offset
+    pub start: usize,
+    pub end: usize,
+    // 1-based, position of `start`
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn merge(&self, other: &Span) -> Span {
+        Span {
+            file: self.file,
+            start: self.start,
+            end: other.end,
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
@@ -1,17 +1,58 @@
+use std::fmt;
+
+use crate::span::Span;
+use crate::types::Type;
+
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
 pub enum Token {
     Operator(String),
     Ident(String),
-    IntLiteral(i32),
+    IntLiteral(u64, Option<Type>),
+    // f64のビット列
+    FloatLiteral(u64),
+    // エスケープは解釈済み
+    StringLiteral(String),
     Reserved(Reserved),
     ReservedOp(ReservedOp),
     Dot,
+    Colon,
+    Underscore,
     Comma,
     OpenParen,
     CloseParen,
     OpenBrace,
     CloseBrace,
+    OpenBracket,
+    CloseBracket,
     SemiColon,
+    DocComment(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Operator(op) => write!(f, "{}", op),
+            Token::Ident(ident) => write!(f, "{}", ident),
+            Token::IntLiteral(x, None) => write!(f, "{}", x),
+            Token::IntLiteral(x, Some(ty)) => write!(f, "{}{}", x, ty),
+            Token::FloatLiteral(x) => write!(f, "{:?}", f64::from_bits(*x)),
+            Token::StringLiteral(s) => write!(f, "{:?}", s),
+            Token::Reserved(reserved) => write!(f, "{}", reserved),
+            Token::ReservedOp(reserved_op) => write!(f, "{}", reserved_op),
+            Token::Dot => write!(f, "."),
+            Token::Colon => write!(f, ":"),
+            Token::Underscore => write!(f, "_"),
+            Token::Comma => write!(f, ","),
+            Token::OpenParen => write!(f, "("),
+            Token::CloseParen => write!(f, ")"),
+            Token::OpenBrace => write!(f, "{{"),
+            Token::CloseBrace => write!(f, "}}"),
+            Token::OpenBracket => write!(f, "["),
+            Token::CloseBracket => write!(f, "]"),
+            Token::SemiColon => write!(f, ";"),
+            Token::DocComment(doc) => write!(f, "## {}", doc),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
@@ -22,10 +63,68 @@ pub enum Reserved {
     Var,
     Func,
     In,
+    Return,
+    Break,
+    Continue,
+    For,
+    Global,
+    As,
+    Struct,
+    Fn,
+    Match,
+    Import,
 }
 
-#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+impl fmt::Display for Reserved {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reserved::If => write!(f, "if"),
+            Reserved::Else => write!(f, "else"),
+            Reserved::While => write!(f, "while"),
+            Reserved::Var => write!(f, "var"),
+            Reserved::Func => write!(f, "func"),
+            Reserved::In => write!(f, "in"),
+            Reserved::Return => write!(f, "return"),
+            Reserved::Break => write!(f, "break"),
+            Reserved::Continue => write!(f, "continue"),
+            Reserved::For => write!(f, "for"),
+            Reserved::Global => write!(f, "global"),
+            Reserved::As => write!(f, "as"),
+            Reserved::Struct => write!(f, "struct"),
+            Reserved::Fn => write!(f, "fn"),
+            Reserved::Match => write!(f, "match"),
+            Reserved::Import => write!(f, "import"),
+        }
+    }
+}
 
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
 pub enum ReservedOp {
     Assign,
+    AddAssign,
+    SubAssign,
+    MulAssign,
+    DivAssign,
+    ModAssign,
+    FatArrow,
+}
+
+impl fmt::Display for ReservedOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReservedOp::Assign => write!(f, "="),
+            ReservedOp::AddAssign => write!(f, "+="),
+            ReservedOp::SubAssign => write!(f, "-="),
+            ReservedOp::MulAssign => write!(f, "*="),
+            ReservedOp::DivAssign => write!(f, "/="),
+            ReservedOp::ModAssign => write!(f, "%="),
+            ReservedOp::FatArrow => write!(f, "=>"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
 }
@@ -1,11 +1,14 @@
+use crate::error::CompileError;
+use crate::span::Span;
 use crate::token::*;
+use crate::types::Type;
 use nom::{
     branch::alt,
     bytes::complete::{is_a, tag, take_while, take_while1},
     character::complete::{char, satisfy},
-    combinator::{eof, map, map_opt, value},
+    combinator::{map, opt, recognize, value},
     multi::many0,
-    sequence::terminated,
+    sequence::pair,
     IResult,
 };
 
@@ -16,17 +19,139 @@ fn letters(input: &str) -> IResult<&str, String> {
 }
 
 fn symbols(input: &str) -> IResult<&str, String> {
-    map(is_a("!$%&*+-/<=>?@^"), |s: &str| s.to_string())(input)
+    map(is_a("!$%&*+-/<=>?@^|"), |s: &str| s.to_string())(input)
 }
 
 fn spaces(input: &str) -> IResult<&str, ()> {
     value((), take_while1(|c: char| c.is_ascii_whitespace()))(input)
 }
 
-fn digits(input: &str) -> IResult<&str, i32> {
-    map_opt(take_while1(|c: char| c.is_ascii_digit()), |s: &str| {
-        s.parse::<i32>().ok()
-    })(input)
+fn radix_prefix(text: &str) -> Option<u32> {
+    match text.get(..2) {
+        Some("0x") | Some("0X") => Some(16),
+        Some("0b") | Some("0B") => Some(2),
+        Some("0o") | Some("0O") => Some(8),
+        _ => None,
+    }
+}
+
+fn digits(input: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        satisfy(|c: char| c.is_ascii_digit()),
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+    ))(input)
+}
+
+// 10進数の場合のみ小数部と指数部の符号を含める
+fn number_literal(input: &str) -> IResult<&str, &str> {
+    let (mut rest, _) = digits(input)?;
+    if radix_prefix(input).is_none() {
+        if let Ok((rest2, _)) = pair(char('.'), digits)(rest) {
+            rest = rest2;
+        }
+        if input[..input.len() - rest.len()].ends_with(['e', 'E']) {
+            if let Ok((rest2, _)) = pair(is_a("+-"), digits)(rest) {
+                rest = rest2;
+            }
+        }
+    }
+    Ok((rest, &input[..input.len() - rest.len()]))
+}
+
+#[derive(Debug, PartialEq, Clone, Eq)]
+enum NumberLiteralError {
+    InvalidInt,
+    InvalidFloat,
+    IntOutOfRange,
+    FloatOutOfRange,
+}
+
+fn number_literal_value(text: &str) -> Result<Token, NumberLiteralError> {
+    let (text, suffix) = if let Some(text) = text.strip_suffix("i32") {
+        (text, Some(Type::I32))
+    } else if let Some(text) = text.strip_suffix("i64") {
+        (text, Some(Type::I64))
+    } else if let Some(text) = text
+        .strip_suffix("f64")
+        .filter(|_| radix_prefix(text).is_none())
+    {
+        (text, Some(Type::F64))
+    } else {
+        (text, None)
+    };
+
+    let is_float = match suffix {
+        Some(ty) => ty == Type::F64,
+        None => radix_prefix(text).is_none() && text.contains(['.', 'e', 'E']),
+    };
+    if is_float {
+        let value = text
+            .replace('_', "")
+            .parse::<f64>()
+            .map_err(|_| NumberLiteralError::InvalidFloat)?;
+        if value.is_infinite() {
+            return Err(NumberLiteralError::FloatOutOfRange);
+        }
+        return Ok(Token::FloatLiteral(value.to_bits()));
+    }
+
+    int_literal_value(text).map(|value| Token::IntLiteral(value, suffix))
+}
+
+fn int_literal_value(text: &str) -> Result<u64, NumberLiteralError> {
+    let (radix, prefix_len) = match radix_prefix(text) {
+        Some(radix) => (radix, 2),
+        None => (10, 0),
+    };
+    let mut value: u64 = 0;
+    let mut has_digit = false;
+    for c in text[prefix_len..].chars() {
+        if c == '_' {
+            continue;
+        }
+        let digit = c.to_digit(radix).ok_or(NumberLiteralError::InvalidInt)?;
+        value = value
+            .checked_mul(radix as u64)
+            .and_then(|value| value.checked_add(digit as u64))
+            .ok_or(NumberLiteralError::IntOutOfRange)?;
+        has_digit = true;
+    }
+    if !has_digit {
+        return Err(NumberLiteralError::InvalidInt);
+    }
+    Ok(value)
+}
+
+#[derive(Debug, PartialEq, Clone, Eq)]
+enum StringLiteralError {
+    Unterminated,
+    // エスケープの開始位置と長さ
+    InvalidEscape(usize, usize),
+}
+
+// 先頭の`"`から閉じる`"`までを読み、その長さとエスケープを解釈した文字列を返す
+fn string_literal(input: &str) -> Result<(usize, String), StringLiteralError> {
+    let mut chars = input.char_indices().skip(1);
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((i + 1, value)),
+            '\\' => {
+                let (_, escape) = chars.next().ok_or(StringLiteralError::Unterminated)?;
+                value.push(match escape {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    '0' => '\0',
+                    '\\' => '\\',
+                    '"' => '"',
+                    _ => return Err(StringLiteralError::InvalidEscape(i, 1 + escape.len_utf8())),
+                });
+            }
+            c => value.push(c),
+        }
+    }
+    Err(StringLiteralError::Unterminated)
 }
 
 fn line_comment(input: &str) -> IResult<&str, ()> {
@@ -35,6 +160,38 @@ fn line_comment(input: &str) -> IResult<&str, ()> {
     Ok((input, ()))
 }
 
+fn doc_comment(input: &str) -> IResult<&str, String> {
+    let (input, _) = tag("##")(input)?;
+    let (input, _) = opt(char(' '))(input)?;
+    let (input, doc) = take_while(|c: char| c != '\n')(input)?;
+    Ok((input, doc.to_string()))
+}
+
+// ネスト可能。閉じられていない場合はFailureを返す
+fn block_comment(input: &str) -> IResult<&str, ()> {
+    let (mut input, _) = tag("/*")(input)?;
+    let mut depth = 1;
+    while depth > 0 {
+        if let Some(rest) = input.strip_prefix("/*") {
+            depth += 1;
+            input = rest;
+        } else if let Some(rest) = input.strip_prefix("*/") {
+            depth -= 1;
+            input = rest;
+        } else {
+            let mut chars = input.chars();
+            if chars.next().is_none() {
+                return Err(nom::Err::Failure(nom::error::Error::new(
+                    input,
+                    nom::error::ErrorKind::Eof,
+                )));
+            }
+            input = chars.as_str();
+        }
+    }
+    Ok((input, ()))
+}
+
 fn token(input: &str) -> IResult<&str, Token> {
     alt((
         map(letters, |s| match s.as_str() {
@@ -44,34 +201,277 @@ fn token(input: &str) -> IResult<&str, Token> {
             "var" => Token::Reserved(Reserved::Var),
             "func" => Token::Reserved(Reserved::Func),
             "in" => Token::Reserved(Reserved::In),
+            "return" => Token::Reserved(Reserved::Return),
+            "break" => Token::Reserved(Reserved::Break),
+            "continue" => Token::Reserved(Reserved::Continue),
+            "for" => Token::Reserved(Reserved::For),
+            "global" => Token::Reserved(Reserved::Global),
+            "as" => Token::Reserved(Reserved::As),
+            "struct" => Token::Reserved(Reserved::Struct),
+            "fn" => Token::Reserved(Reserved::Fn),
+            "match" => Token::Reserved(Reserved::Match),
+            "import" => Token::Reserved(Reserved::Import),
             _ => Token::Ident(s),
         }),
         map(symbols, |s| match s.as_str() {
             "=" => Token::ReservedOp(ReservedOp::Assign),
+            "+=" => Token::ReservedOp(ReservedOp::AddAssign),
+            "-=" => Token::ReservedOp(ReservedOp::SubAssign),
+            "*=" => Token::ReservedOp(ReservedOp::MulAssign),
+            "/=" => Token::ReservedOp(ReservedOp::DivAssign),
+            "%=" => Token::ReservedOp(ReservedOp::ModAssign),
+            "=>" => Token::ReservedOp(ReservedOp::FatArrow),
             _ => Token::Operator(s),
         }),
-        map(digits, Token::IntLiteral),
         value(Token::Dot, char('.')),
+        value(Token::Colon, char(':')),
+        value(Token::Underscore, char('_')),
         value(Token::Comma, char(',')),
         value(Token::OpenParen, char('(')),
         value(Token::CloseParen, char(')')),
         value(Token::OpenBrace, char('{')),
         value(Token::CloseBrace, char('}')),
+        value(Token::OpenBracket, char('[')),
+        value(Token::CloseBracket, char(']')),
         value(Token::SemiColon, char(';')),
     ))(input)
 }
 
-fn tokens(input: &str) -> IResult<&str, Vec<Token>> {
-    map(
-        many0(alt((
-            value(None, spaces),
-            value(None, line_comment),
-            map(token, Some),
-        ))),
-        |xs| xs.into_iter().filter_map(|x| x).collect::<Vec<_>>(),
-    )(input)
+#[derive(Debug, PartialEq, Clone, Copy, Eq)]
+struct Pos {
+    file: usize,
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Pos {
+    fn advance(&mut self, s: &str) {
+        self.offset += s.len();
+        for c in s.chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+    }
+
+    fn span_to(&self, end: &Pos) -> Span {
+        Span {
+            file: self.file,
+            start: self.offset,
+            end: end.offset,
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+pub fn tokenize(input: &str, file: usize) -> Result<Vec<SpannedToken>, CompileError> {
+    let mut pos = Pos {
+        file,
+        offset: 0,
+        line: 1,
+        column: 1,
+    };
+    let mut tokens = Vec::new();
+    let mut input = input;
+    while let Some(found) = input.chars().next() {
+        let start = pos;
+        let (rest, token) = if let Ok((rest, literal)) = number_literal(input) {
+            pos.advance(literal);
+            let token = number_literal_value(literal).map_err(|e| match e {
+                NumberLiteralError::InvalidInt => CompileError::InvalidIntLiteral {
+                    literal: literal.to_string(),
+                    span: start.span_to(&pos),
+                },
+                NumberLiteralError::InvalidFloat => CompileError::InvalidFloatLiteral {
+                    literal: literal.to_string(),
+                    span: start.span_to(&pos),
+                },
+                NumberLiteralError::IntOutOfRange => CompileError::IntLiteralOutOfRange {
+                    span: start.span_to(&pos),
+                },
+                NumberLiteralError::FloatOutOfRange => CompileError::FloatLiteralOutOfRange {
+                    span: start.span_to(&pos),
+                },
+            })?;
+            (rest, Some(token))
+        } else if input.starts_with('"') {
+            let (len, value) = string_literal(input).map_err(|e| match e {
+                StringLiteralError::Unterminated => CompileError::UnterminatedStringLiteral {
+                    span: start.span_to(&{
+                        let mut end = pos;
+                        end.advance(input);
+                        end
+                    }),
+                },
+                StringLiteralError::InvalidEscape(offset, len) => {
+                    let mut escape_start = pos;
+                    escape_start.advance(&input[..offset]);
+                    let mut escape_end = escape_start;
+                    escape_end.advance(&input[offset..offset + len]);
+                    CompileError::InvalidEscape {
+                        escape: input[offset..offset + len].to_string(),
+                        span: escape_start.span_to(&escape_end),
+                    }
+                }
+            })?;
+            pos.advance(&input[..len]);
+            (&input[len..], Some(Token::StringLiteral(value)))
+        } else {
+            let (rest, token) = alt((
+                value(None, spaces),
+                map(doc_comment, |doc| Some(Token::DocComment(doc))),
+                value(None, line_comment),
+                value(None, block_comment),
+                map(token, Some),
+            ))(input)
+            .map_err(|e| match e {
+                nom::Err::Failure(_) => CompileError::UnterminatedBlockComment {
+                    span: start.span_to(&{
+                        let mut end = pos;
+                        end.advance(input);
+                        end
+                    }),
+                },
+                _ => CompileError::Lex {
+                    found,
+                    span: Span {
+                        file: pos.file,
+                        start: pos.offset,
+                        end: pos.offset + found.len_utf8(),
+                        line: pos.line,
+                        column: pos.column,
+                    },
+                },
+            })?;
+            pos.advance(&input[..input.len() - rest.len()]);
+            (rest, token)
+        };
+        if let Some(token) = token {
+            tokens.push(SpannedToken {
+                token,
+                span: start.span_to(&pos),
+            });
+        }
+        input = rest;
+    }
+    Ok(tokens)
 }
 
-pub fn tokenize(input: &str) -> IResult<&str, Vec<Token>> {
-    terminated(tokens, eof)(input)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        tokenize(input, 0)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.token)
+            .collect()
+    }
+
+    #[test]
+    fn tokens_have_spans() {
+        let tokens = tokenize("func f()\n  { 12; }", 3).unwrap();
+        let span = |i: usize| {
+            let span = tokens[i].span;
+            (span.file, span.start, span.end, span.line, span.column)
+        };
+        assert_eq!(tokens[0].token, Token::Reserved(Reserved::Func));
+        assert_eq!(span(0), (3, 0, 4, 1, 1));
+        assert_eq!(span(1), (3, 5, 6, 1, 6));
+        assert_eq!(tokens[4].token, Token::OpenBrace);
+        assert_eq!(span(4), (3, 11, 12, 2, 3));
+        assert_eq!(tokens[5].token, Token::IntLiteral(12, None));
+        assert_eq!(span(5), (3, 13, 15, 2, 5));
+    }
+
+    #[test]
+    fn int_literals_with_radix_and_separators() {
+        assert_eq!(
+            tokens("0xff 0b1010 0o17 1_000_000 5i64 7i32"),
+            vec![
+                Token::IntLiteral(255, None),
+                Token::IntLiteral(10, None),
+                Token::IntLiteral(15, None),
+                Token::IntLiteral(1_000_000, None),
+                Token::IntLiteral(5, Some(Type::I64)),
+                Token::IntLiteral(7, Some(Type::I32)),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_int_literals() {
+        assert!(matches!(
+            tokenize("0b102", 0),
+            Err(CompileError::InvalidIntLiteral { .. })
+        ));
+        assert!(matches!(
+            tokenize("0x", 0),
+            Err(CompileError::InvalidIntLiteral { .. })
+        ));
+        assert!(matches!(
+            tokenize("18446744073709551616", 0),
+            Err(CompileError::IntLiteralOutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn block_comments_nest() {
+        assert_eq!(
+            tokens("1 /* a /* b */ c */ 2 # line\n3"),
+            vec![
+                Token::IntLiteral(1, None),
+                Token::IntLiteral(2, None),
+                Token::IntLiteral(3, None),
+            ]
+        );
+        assert!(matches!(
+            tokenize("1 /* a /* b */", 0),
+            Err(CompileError::UnterminatedBlockComment { .. })
+        ));
+    }
+
+    #[test]
+    fn doc_comments_are_tokens() {
+        assert_eq!(
+            tokens("## adds one\nfunc"),
+            vec![
+                Token::DocComment("adds one".to_string()),
+                Token::Reserved(Reserved::Func),
+            ]
+        );
+    }
+
+    #[test]
+    fn string_literals() {
+        assert_eq!(
+            tokens(r#""a\n\"b\"" """#),
+            vec![
+                Token::StringLiteral("a\n\"b\"".to_string()),
+                Token::StringLiteral(String::new()),
+            ]
+        );
+        assert!(matches!(
+            tokenize(r#""abc"#, 0),
+            Err(CompileError::UnterminatedStringLiteral { .. })
+        ));
+        assert!(matches!(
+            tokenize(r#""\q""#, 0),
+            Err(CompileError::InvalidEscape { .. })
+        ));
+    }
+
+    #[test]
+    fn lex_error_has_a_span() {
+        let error = tokenize("x\n  `", 0).unwrap_err();
+        assert_eq!(error.span().line, 2);
+        assert_eq!(error.span().column, 3);
+        assert_eq!(tokens("x"), vec![Token::Ident("x".to_string())]);
+    }
 }
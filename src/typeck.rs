@@ -0,0 +1,827 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::*;
+use crate::error::CompileError;
+use crate::ir::HostFunc;
+use crate::span::Span;
+use crate::types::{Ty, Type};
+
+#[derive(Debug, PartialEq, Clone, Eq)]
+struct Signature {
+    params: Vec<Ty>,
+    ret: Ty,
+}
+
+#[derive(Debug, PartialEq, Clone, Eq)]
+struct TypeChecker {
+    structs: HashMap<String, Vec<(String, Ty)>>,
+    funcs: HashMap<String, Signature>,
+    // 埋め込み側が登録した関数。値としては使えない
+    host_funcs: HashMap<String, Signature>,
+    globals: HashMap<String, Ty>,
+}
+
+#[derive(Debug, PartialEq, Clone, Eq)]
+struct CheckFuncState {
+    locals: HashMap<String, Ty>,
+    // クロージャが捕捉した外側のローカル変数
+    captured: HashSet<String>,
+    ret: Ty,
+}
+
+// 型検査の結果。Noneは値を返さない式(return, break, continue)
+type Inferred = Option<Ty>;
+
+impl TypeChecker {
+    // 型に含まれる構造体が定義されているか
+    fn check_ty(&self, ty: &Ty, span: &Span) -> Result<(), CompileError> {
+        match ty {
+            Ty::Scalar(_) | Ty::Str => Ok(()),
+            Ty::Array(elem) => self.check_ty(elem, span),
+            Ty::Func(params, ret) => {
+                for param in params {
+                    self.check_ty(param, span)?;
+                }
+                self.check_ty(ret, span)
+            }
+            Ty::Struct(name) if self.structs.contains_key(name) => Ok(()),
+            Ty::Struct(name) => Err(CompileError::UnknownStruct {
+                name: name.clone(),
+                span: *span,
+            }),
+        }
+    }
+
+    fn check_struct(&self, struct_: &Struct) -> Result<(), CompileError> {
+        for (i, (name, ty)) in struct_.fields.iter().enumerate() {
+            if struct_.fields[..i].iter().any(|(x, _)| x == name) {
+                return Err(CompileError::DuplicateField {
+                    name: name.clone(),
+                    span: struct_.span,
+                });
+            }
+            self.check_ty(ty, &struct_.span)?;
+        }
+        Ok(())
+    }
+
+    fn check_global(&mut self, global: &mut Global) -> Result<(), CompileError> {
+        let mut state = CheckFuncState {
+            locals: HashMap::new(),
+            captured: HashSet::new(),
+            ret: Ty::I32,
+        };
+        let ty = match &global.ty {
+            Some(ty) => {
+                self.check_ty(ty, &global.span)?;
+                self.check(&mut state, &mut global.init, ty)?;
+                ty.clone()
+            }
+            None => self
+                .infer(&mut state, &mut global.init, None)?
+                .unwrap_or(Ty::I32),
+        };
+        self.globals.insert(global.name.clone(), ty);
+        Ok(())
+    }
+
+    fn check_func(&self, func: &mut Func) -> Result<(), CompileError> {
+        for (_, ty) in &func.args {
+            self.check_ty(ty, &func.span)?;
+        }
+        self.check_ty(&func.ret, &func.span)?;
+        // 埋め込み側はmainの戻り値をi32として受け取る
+        if func.name == "main" && (!func.args.is_empty() || func.ret != Ty::I32) {
+            return Err(CompileError::InvalidMainSignature { span: func.span });
+        }
+        let mut state = CheckFuncState {
+            locals: func.args.iter().cloned().collect(),
+            captured: HashSet::new(),
+            ret: func.ret.clone(),
+        };
+        self.check(&mut state, &mut func.body, &func.ret)
+    }
+
+    fn check(
+        &self,
+        state: &mut CheckFuncState,
+        expr: &mut Expr,
+        expected: &Ty,
+    ) -> Result<(), CompileError> {
+        match self.infer(state, expr, Some(expected))? {
+            Some(found) if found != *expected => Err(CompileError::TypeMismatch {
+                expected: expected.clone(),
+                found,
+                span: expr.span,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_scalar(
+        &self,
+        state: &mut CheckFuncState,
+        expr: &mut Expr,
+    ) -> Result<(), CompileError> {
+        match self.infer(state, expr, None)? {
+            Some(found) if found.scalar().is_none() => Err(CompileError::UnexpectedType {
+                expected: "number".to_string(),
+                found,
+                span: expr.span,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // 同じ型の2つのオペランド
+    fn infer_operands(
+        &self,
+        state: &mut CheckFuncState,
+        expr1: &mut Expr,
+        expr2: &mut Expr,
+        hint: Option<&Ty>,
+    ) -> Result<Inferred, CompileError> {
+        // `1 + x`のように左辺が型のないリテラルなら右辺の型に合わせる
+        if is_untyped_int_literal(expr1) {
+            let ty = self.infer(state, expr2, hint)?;
+            let ty2 = self.infer(state, expr1, ty.as_ref().or(hint))?;
+            unify(ty, ty2, &expr1.span)
+        } else {
+            let ty = self.infer(state, expr1, hint)?;
+            let ty2 = self.infer(state, expr2, ty.as_ref().or(hint))?;
+            unify(ty, ty2, &expr2.span)
+        }
+    }
+
+    // 要素の型を返す
+    fn check_array(
+        &self,
+        state: &mut CheckFuncState,
+        expr: &mut Expr,
+    ) -> Result<Inferred, CompileError> {
+        match self.infer(state, expr, None)? {
+            Some(Ty::Array(elem)) => Ok(Some(*elem)),
+            Some(found) => Err(CompileError::UnexpectedType {
+                expected: "array".to_string(),
+                found,
+                span: expr.span,
+            }),
+            None => Ok(None),
+        }
+    }
+
+    // フィールドの型を返す
+    fn check_field(
+        &self,
+        state: &mut CheckFuncState,
+        expr: &mut Expr,
+        name: &str,
+        span: &Span,
+    ) -> Result<Inferred, CompileError> {
+        match self.infer(state, expr, None)? {
+            Some(Ty::Struct(struct_name)) => self.structs[&struct_name]
+                .iter()
+                .find(|(x, _)| x == name)
+                .map(|(_, ty)| Some(ty.clone()))
+                .ok_or_else(|| CompileError::UnknownField {
+                    struct_name,
+                    name: name.to_string(),
+                    span: *span,
+                }),
+            Some(found) => Err(CompileError::UnexpectedType {
+                expected: "struct".to_string(),
+                found,
+                span: expr.span,
+            }),
+            None => Ok(None),
+        }
+    }
+
+    // hintは型を指定していない整数リテラルや配列の要素の型を決めるのに使う
+    fn infer(
+        &self,
+        state: &mut CheckFuncState,
+        expr: &mut Expr,
+        hint: Option<&Ty>,
+    ) -> Result<Inferred, CompileError> {
+        let span = expr.span;
+        let ty = match &mut expr.kind {
+            ExprKind::IntLiteral(x, ty) => {
+                let ty = int_literal_type(*ty, hint);
+                check_int_range(*x as i128, ty, &span)?;
+                Some(Ty::Scalar(ty))
+            }
+            ExprKind::FloatLiteral(_) => Some(Ty::Scalar(Type::F64)),
+            ExprKind::StringLiteral(_) => Some(Ty::Str),
+            ExprKind::Ident(name) => Some(self.var_type(state, name, &span)?),
+            ExprKind::BinaryOp(expr1, BinaryOp::And | BinaryOp::Or, expr2) => {
+                self.check(state, expr1, &Ty::I32)?;
+                self.check(state, expr2, &Ty::I32)?;
+                Some(Ty::I32)
+            }
+            ExprKind::BinaryOp(expr1, op, expr2) => {
+                let is_arith = matches!(
+                    op,
+                    BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod
+                );
+                let hint = if is_arith { hint } else { None };
+                let ty = self.infer_operands(state, expr1, expr2, hint)?;
+                // 配列はアドレスの比較のみできる。文字列は表現が実行方法ごとに異なるので比較できない
+                let supported = match (&op, &ty) {
+                    (_, None) => true,
+                    (_, Some(Ty::Str)) => false,
+                    (BinaryOp::Eq | BinaryOp::Ne, _) => true,
+                    (BinaryOp::Mod, Some(ty)) => {
+                        ty.scalar().is_some() && *ty != Ty::Scalar(Type::F64)
+                    }
+                    (_, Some(ty)) => ty.scalar().is_some(),
+                };
+                if !supported {
+                    return Err(CompileError::UnsupportedOperandType {
+                        op: binary_op_name(op).to_string(),
+                        ty: ty.unwrap(),
+                        span,
+                    });
+                }
+                if is_arith {
+                    ty
+                } else {
+                    Some(Ty::I32)
+                }
+            }
+            ExprKind::PrefixOp(PrefixOp::Not, operand) => {
+                self.check(state, operand, &Ty::I32)?;
+                Some(Ty::I32)
+            }
+            ExprKind::PrefixOp(PrefixOp::Minus, operand) => {
+                // 負の整数リテラルは畳み込むので範囲検査もここで行う
+                if let ExprKind::IntLiteral(x, ty) = operand.kind {
+                    let ty = int_literal_type(ty, hint);
+                    check_int_range(-(x as i128), ty, &span)?;
+                    operand.ty = Some(Ty::Scalar(ty));
+                    Some(Ty::Scalar(ty))
+                } else {
+                    match self.infer(state, operand, hint)? {
+                        Some(ty) if ty.scalar().is_none() => {
+                            return Err(CompileError::UnsupportedOperandType {
+                                op: "-".to_string(),
+                                ty,
+                                span,
+                            });
+                        }
+                        ty => ty,
+                    }
+                }
+            }
+            ExprKind::Cast(operand, ty) => {
+                self.check_scalar(state, operand)?;
+                Some(Ty::Scalar(*ty))
+            }
+            ExprKind::Assign(lhs, rhs) => {
+                // 関数には代入できない
+                if let ExprKind::Ident(name) = &lhs.kind {
+                    if !self.is_var(state, name) {
+                        return Err(CompileError::UnknownIdent {
+                            name: name.clone(),
+                            span: lhs.span,
+                        });
+                    }
+                    // 捕捉した変数はクロージャにコピーされるので代入しても外側に反映されない
+                    if state.captured.contains(name) {
+                        return Err(CompileError::AssignToCapturedVar {
+                            name: name.clone(),
+                            span: lhs.span,
+                        });
+                    }
+                }
+                match self.infer(state, lhs, None)? {
+                    Some(ty) => self.check(state, rhs, &ty)?,
+                    None => {
+                        self.infer(state, rhs, None)?;
+                    }
+                }
+                Some(Ty::I32)
+            }
+            ExprKind::Call(callee, args) => match &callee.kind {
+                ExprKind::Ident(name)
+                    if !self.is_var(state, name)
+                        && !self.funcs.contains_key(name)
+                        && is_builtin(name) =>
+                {
+                    let name = name.clone();
+                    self.check_builtin_call(state, &name, args, hint, &span)?
+                }
+                _ => self.check_call(state, callee, args, &span)?,
+            },
+            ExprKind::Index(array, index) => {
+                let elem = self.check_array(state, array)?;
+                self.check(state, index, &Ty::I32)?;
+                elem
+            }
+            ExprKind::StructLiteral(name, fields) => {
+                let struct_fields =
+                    self.structs
+                        .get(name.as_str())
+                        .ok_or_else(|| CompileError::UnknownStruct {
+                            name: name.clone(),
+                            span,
+                        })?;
+                for i in 0..fields.len() {
+                    let (field, value) = &fields[i];
+                    if fields[..i].iter().any(|(x, _)| x == field) {
+                        return Err(CompileError::DuplicateField {
+                            name: field.clone(),
+                            span: value.span,
+                        });
+                    }
+                    let ty = struct_fields
+                        .iter()
+                        .find(|(x, _)| x == field)
+                        .map(|(_, ty)| ty)
+                        .ok_or_else(|| CompileError::UnknownField {
+                            struct_name: name.clone(),
+                            name: field.clone(),
+                            span: value.span,
+                        })?;
+                    self.check(state, &mut fields[i].1, ty)?;
+                }
+                if let Some((missing, _)) = struct_fields
+                    .iter()
+                    .find(|(x, _)| fields.iter().all(|(field, _)| field != x))
+                {
+                    return Err(CompileError::MissingField {
+                        struct_name: name.clone(),
+                        name: missing.clone(),
+                        span,
+                    });
+                }
+                Some(Ty::Struct(name.clone()))
+            }
+            ExprKind::Field(operand, name) => self.check_field(state, operand, name, &span)?,
+            ExprKind::Lambda(args, ret, body) => {
+                for (_, ty) in args.iter() {
+                    self.check_ty(ty, &span)?;
+                }
+                self.check_ty(ret, &span)?;
+                let mut lambda_state = CheckFuncState {
+                    locals: state.locals.clone(),
+                    captured: state.locals.keys().cloned().collect(),
+                    ret: ret.clone(),
+                };
+                for (name, ty) in args.iter() {
+                    lambda_state.locals.insert(name.clone(), ty.clone());
+                    lambda_state.captured.remove(name);
+                }
+                self.check(&mut lambda_state, body, ret)?;
+                Some(Ty::Func(
+                    args.iter().map(|(_, ty)| ty.clone()).collect(),
+                    Box::new(ret.clone()),
+                ))
+            }
+            ExprKind::While(cond, body) => {
+                self.check(state, cond, &Ty::I32)?;
+                self.infer(state, body, None)?;
+                Some(Ty::I32)
+            }
+            ExprKind::If(cond, body, None) => {
+                self.check(state, cond, &Ty::I32)?;
+                self.infer(state, body, None)?;
+                Some(Ty::I32)
+            }
+            ExprKind::If(cond, body, Some(else_body)) => {
+                self.check(state, cond, &Ty::I32)?;
+                let ty = self.infer(state, body, hint)?;
+                let ty2 = self.infer(state, else_body, ty.as_ref().or(hint))?;
+                unify(ty, ty2, &else_body.span)?
+            }
+            ExprKind::Match(scrutinee, arms, default) => {
+                let scrutinee_ty = match self.infer(state, scrutinee, None)? {
+                    Some(Ty::Scalar(ty @ (Type::I32 | Type::I64))) => ty,
+                    Some(found) => {
+                        return Err(CompileError::UnexpectedType {
+                            expected: "integer".to_string(),
+                            found,
+                            span: scrutinee.span,
+                        });
+                    }
+                    None => Type::I32,
+                };
+                let mut patterns = Vec::new();
+                let mut ty = None;
+                for (pattern, body) in arms.iter_mut() {
+                    self.check(state, pattern, &Ty::Scalar(scrutinee_ty))?;
+                    let value = pattern_value(pattern);
+                    if patterns.contains(&value) {
+                        return Err(CompileError::DuplicatePattern {
+                            pattern: value,
+                            span: pattern.span,
+                        });
+                    }
+                    patterns.push(value);
+                    let ty2 = self.infer(state, body, ty.as_ref().or(hint))?;
+                    ty = unify(ty, ty2, &body.span)?;
+                }
+                let ty2 = self.infer(state, default, ty.as_ref().or(hint))?;
+                unify(ty, ty2, &default.span)?
+            }
+            ExprKind::Block(exprs) => match exprs.split_last_mut() {
+                Some((last, init)) => {
+                    for expr in init {
+                        self.infer(state, expr, None)?;
+                    }
+                    self.infer(state, last, hint)?
+                }
+                None => Some(Ty::I32),
+            },
+            ExprKind::Var(name, ty, init, body) => {
+                let ty = match ty {
+                    Some(ty) => {
+                        self.check_ty(ty, &span)?;
+                        self.check(state, init, ty)?;
+                        ty.clone()
+                    }
+                    None => self.infer(state, init, None)?.unwrap_or(Ty::I32),
+                };
+                let prev_locals = state.locals.clone();
+                let prev_captured = state.captured.clone();
+                state.locals.insert(name.clone(), ty);
+                state.captured.remove(name);
+                let ty = self.infer(state, body, hint)?;
+                state.locals = prev_locals;
+                state.captured = prev_captured;
+                ty
+            }
+            ExprKind::Return(ret_val) => {
+                let ret = state.ret.clone();
+                self.check(state, ret_val, &ret)?;
+                None
+            }
+            ExprKind::Break | ExprKind::Continue => None,
+        };
+        expr.ty = Some(ty.clone().or_else(|| hint.cloned()).unwrap_or(Ty::I32));
+        Ok(ty)
+    }
+
+    fn check_builtin_call(
+        &self,
+        state: &mut CheckFuncState,
+        name: &str,
+        args: &mut [Expr],
+        hint: Option<&Ty>,
+        span: &Span,
+    ) -> Result<Inferred, CompileError> {
+        let expected = match name {
+            "min" | "max" => 2,
+            _ => 1,
+        };
+        if args.len() != expected {
+            return Err(CompileError::ArityMismatch {
+                name: name.to_string(),
+                expected,
+                actual: args.len(),
+                span: *span,
+            });
+        }
+        let ty = match name {
+            "println" => {
+                self.check_scalar(state, &mut args[0])?;
+                Some(Ty::I32)
+            }
+            // 要素の型は`var a: [i64] = array(n)`のように文脈から決める
+            "array" => {
+                self.check(state, &mut args[0], &Ty::I32)?;
+                match hint {
+                    Some(ty @ Ty::Array(_)) => Some(ty.clone()),
+                    _ => Some(Ty::Array(Box::new(Ty::I32))),
+                }
+            }
+            "len" => {
+                self.check_array(state, &mut args[0])?;
+                Some(Ty::I32)
+            }
+            "abs" => {
+                let ty = self.infer(state, &mut args[0], hint)?;
+                number(ty, &args[0].span)?
+            }
+            "min" | "max" => {
+                let [x, y] = args else { unreachable!() };
+                let ty = self.infer_operands(state, x, y, hint)?;
+                number(ty, span)?
+            }
+            "assert" => {
+                self.check(state, &mut args[0], &Ty::I32)?;
+                Some(Ty::I32)
+            }
+            _ => unreachable!(),
+        };
+        Ok(ty)
+    }
+
+    // 変数でない識別子は関数を直接呼び出し、それ以外は関数の値を呼び出す
+    fn check_call(
+        &self,
+        state: &mut CheckFuncState,
+        callee: &mut Expr,
+        args: &mut [Expr],
+        span: &Span,
+    ) -> Result<Inferred, CompileError> {
+        let host_func = match &callee.kind {
+            ExprKind::Ident(name)
+                if !self.is_var(state, name) && !self.funcs.contains_key(name) =>
+            {
+                Some(self.host_funcs.get(name).cloned().ok_or_else(|| {
+                    CompileError::UnknownFunc {
+                        name: name.clone(),
+                        span: *span,
+                    }
+                })?)
+            }
+            _ => None,
+        };
+        let callee_ty = match host_func {
+            Some(signature) => {
+                let ty = Ty::Func(signature.params, Box::new(signature.ret));
+                callee.ty = Some(ty.clone());
+                Some(ty)
+            }
+            None => self.infer(state, callee, None)?,
+        };
+        let (params, ret) = match callee_ty {
+            Some(Ty::Func(params, ret)) => (params, ret),
+            Some(found) => {
+                return Err(CompileError::UnexpectedType {
+                    expected: "function".to_string(),
+                    found,
+                    span: callee.span,
+                });
+            }
+            None => {
+                for arg in args {
+                    self.infer(state, arg, None)?;
+                }
+                return Ok(None);
+            }
+        };
+        if args.len() != params.len() {
+            return Err(CompileError::ArityMismatch {
+                name: match &callee.kind {
+                    ExprKind::Ident(name) => name.clone(),
+                    _ => Ty::Func(params.clone(), ret.clone()).to_string(),
+                },
+                expected: params.len(),
+                actual: args.len(),
+                span: *span,
+            });
+        }
+        for (arg, ty) in args.iter_mut().zip(&params) {
+            self.check(state, arg, ty)?;
+        }
+        Ok(Some(*ret))
+    }
+
+    fn is_var(&self, state: &CheckFuncState, name: &str) -> bool {
+        state.locals.contains_key(name) || self.globals.contains_key(name)
+    }
+
+    // ローカル変数はグローバル変数をシャドウイングし、変数でなければ関数を参照する
+    fn var_type(
+        &self,
+        state: &CheckFuncState,
+        name: &str,
+        span: &Span,
+    ) -> Result<Ty, CompileError> {
+        state
+            .locals
+            .get(name)
+            .or_else(|| self.globals.get(name))
+            .cloned()
+            .or_else(|| {
+                self.funcs.get(name).map(|signature| {
+                    Ty::Func(signature.params.clone(), Box::new(signature.ret.clone()))
+                })
+            })
+            .ok_or_else(|| CompileError::UnknownIdent {
+                name: name.to_string(),
+                span: *span,
+            })
+    }
+}
+
+pub fn is_builtin(name: &str) -> bool {
+    matches!(
+        name,
+        "println" | "array" | "len" | "abs" | "min" | "max" | "assert"
+    )
+}
+
+fn binary_op_name(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Mod => "%",
+        BinaryOp::Lt => "<",
+        BinaryOp::Gt => ">",
+        BinaryOp::Le => "<=",
+        BinaryOp::Ge => ">=",
+        BinaryOp::Eq => "==",
+        BinaryOp::Ne => "!=",
+        BinaryOp::And => "&&",
+        BinaryOp::Or => "||",
+    }
+}
+
+fn int_literal_type(ty: Option<Type>, hint: Option<&Ty>) -> Type {
+    match (ty, hint) {
+        (Some(ty), _) => ty,
+        (None, Some(Ty::Scalar(Type::I64))) => Type::I64,
+        (None, _) => Type::I32,
+    }
+}
+
+fn check_int_range(x: i128, ty: Type, span: &Span) -> Result<(), CompileError> {
+    let in_range = match ty {
+        Type::I32 => i32::try_from(x).is_ok(),
+        Type::I64 => i64::try_from(x).is_ok(),
+        Type::F64 => unreachable!(),
+    };
+    if in_range {
+        Ok(())
+    } else {
+        Err(CompileError::IntLiteralOutOfRange { span: *span })
+    }
+}
+
+fn is_untyped_int_literal(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::IntLiteral(_, ty) => ty.is_none(),
+        ExprKind::PrefixOp(PrefixOp::Minus, operand) => is_untyped_int_literal(operand),
+        _ => false,
+    }
+}
+
+fn pattern_value(pattern: &Expr) -> i128 {
+    match &pattern.kind {
+        &ExprKind::IntLiteral(x, _) => x as i128,
+        ExprKind::PrefixOp(PrefixOp::Minus, operand) => -pattern_value(operand),
+        // 構文解析で弾いている
+        _ => unreachable!(),
+    }
+}
+
+fn number(ty: Inferred, span: &Span) -> Result<Inferred, CompileError> {
+    match ty {
+        Some(found) if found.scalar().is_none() => Err(CompileError::UnexpectedType {
+            expected: "number".to_string(),
+            found,
+            span: *span,
+        }),
+        ty => Ok(ty),
+    }
+}
+
+fn unify(ty1: Inferred, ty2: Inferred, span: &Span) -> Result<Inferred, CompileError> {
+    match (ty1, ty2) {
+        (Some(expected), Some(found)) if expected != found => Err(CompileError::TypeMismatch {
+            expected,
+            found,
+            span: *span,
+        }),
+        (Some(ty), _) | (_, Some(ty)) => Ok(Some(ty)),
+        (None, None) => Ok(None),
+    }
+}
+
+pub fn check(module: &mut Module, host_funcs: &[HostFunc]) -> Result<(), CompileError> {
+    let mut structs = HashMap::new();
+    for struct_ in &module.structs {
+        if structs
+            .insert(struct_.name.clone(), struct_.fields.clone())
+            .is_some()
+        {
+            return Err(CompileError::DuplicateStruct {
+                name: struct_.name.clone(),
+                span: struct_.span,
+            });
+        }
+    }
+
+    let mut checker = TypeChecker {
+        structs,
+        funcs: module
+            .funcs
+            .iter()
+            .map(|func| {
+                (
+                    func.name.clone(),
+                    Signature {
+                        params: func.args.iter().map(|(_, ty)| ty.clone()).collect(),
+                        ret: func.ret.clone(),
+                    },
+                )
+            })
+            .collect(),
+        host_funcs: host_funcs
+            .iter()
+            .map(|host_func| {
+                (
+                    host_func.name.clone(),
+                    Signature {
+                        params: host_func.params.clone(),
+                        ret: Ty::Scalar(host_func.ret),
+                    },
+                )
+            })
+            .collect(),
+        globals: HashMap::new(),
+    };
+
+    for struct_ in &module.structs {
+        checker.check_struct(struct_)?;
+    }
+
+    for global in &mut module.globals {
+        checker.check_global(global)?;
+    }
+
+    for func in &mut module.funcs {
+        checker.check_func(func)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::tokenizer::tokenize;
+
+    fn check_str(input: &str) -> Result<Module, CompileError> {
+        let (mut module, errors) = parse(&tokenize(input, 0).unwrap());
+        assert!(errors.is_empty(), "{:?}", errors);
+        check(&mut module, &[])?;
+        Ok(module)
+    }
+
+    #[test]
+    fn main_must_take_nothing_and_return_i32() {
+        assert!(check_str("func main() { 1; }").is_ok());
+        assert!(check_str("func main(): i32 { 1; }").is_ok());
+        for src in [
+            "func main(): i64 { 5i64; }",
+            "func main(): f64 { 0.5; }",
+            "func main(x: i32) { x; }",
+        ] {
+            assert!(matches!(
+                check_str(src),
+                Err(CompileError::InvalidMainSignature { .. })
+            ));
+        }
+        // main以外の関数は自由に宣言できる
+        assert!(check_str("func f(x: i32): f64 { 0.5; }").is_ok());
+    }
+
+    #[test]
+    fn return_type_defaults_to_i32() {
+        assert!(check_str("func f() { 1; }").is_ok());
+        assert!(check_str("func f() { 1.5; }").is_err());
+    }
+
+    #[test]
+    fn literals_take_the_expected_type() {
+        let module = check_str("func f(): i64 { var x: i64 = 1 in x + 2; }").unwrap();
+        assert_eq!(module.funcs[0].body.ty(), &Ty::Scalar(Type::I64));
+        let module = check_str("func f(): f64 { 1.5 * 2.0; }").unwrap();
+        assert_eq!(module.funcs[0].body.ty(), &Ty::Scalar(Type::F64));
+    }
+
+    #[test]
+    fn mixed_operand_types_are_rejected() {
+        assert!(matches!(
+            check_str("func f() { 1i64 + 1i32; }"),
+            Err(CompileError::TypeMismatch {
+                expected: Ty::Scalar(Type::I64),
+                found: Ty::Scalar(Type::I32),
+                ..
+            })
+        ));
+        assert!(matches!(
+            check_str("func f(x: f64) { var y: i32 = x in y; }"),
+            Err(CompileError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn casts_convert_between_types() {
+        assert!(check_str("func f(x: f64): i64 { (x as i64); }").is_ok());
+        assert!(check_str("func f(x: f64): i64 { x; }").is_err());
+    }
+
+    #[test]
+    fn condition_must_be_i32() {
+        assert!(check_str("func f() { if (1.0) { 1; } else { 2; }; }").is_err());
+        assert!(check_str("func f() { while (1i64) { 1; }; }").is_err());
+    }
+}
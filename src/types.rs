@@ -0,0 +1,79 @@
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy, Hash, Eq)]
+pub enum Type {
+    I32,
+    I64,
+    F64,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::I32 => write!(f, "i32"),
+            Type::I64 => write!(f, "i64"),
+            Type::F64 => write!(f, "f64"),
+        }
+    }
+}
+
+impl Type {
+    pub fn size(&self) -> u32 {
+        match self {
+            Type::I32 => 4,
+            Type::I64 | Type::F64 => 8,
+        }
+    }
+}
+
+// 言語上の型。スカラー以外は実行時にはi32で表す
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+pub enum Ty {
+    Scalar(Type),
+    Array(Box<Ty>),
+    // 文字列リテラルのみ。wasmではアドレス、インタプリタではコンスタントプールのindex
+    Str,
+    // ヒープ上のアドレス
+    Struct(String),
+    // クロージャのアドレス。クロージャは先頭に関数のテーブル上のindexを持ち、その後に捕捉した変数が並ぶ
+    Func(Vec<Ty>, Box<Ty>),
+}
+
+impl Ty {
+    pub const I32: Ty = Ty::Scalar(Type::I32);
+
+    pub fn repr(&self) -> Type {
+        match self {
+            Ty::Scalar(ty) => *ty,
+            Ty::Array(_) | Ty::Str | Ty::Struct(_) | Ty::Func(..) => Type::I32,
+        }
+    }
+
+    pub fn scalar(&self) -> Option<Type> {
+        match self {
+            Ty::Scalar(ty) => Some(*ty),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ty::Scalar(ty) => write!(f, "{}", ty),
+            Ty::Array(elem) => write!(f, "[{}]", elem),
+            Ty::Str => write!(f, "str"),
+            Ty::Struct(name) => write!(f, "{}", name),
+            Ty::Func(params, ret) => {
+                write!(f, "func(")?;
+                for (i, param) in params.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", param)?;
+                }
+                write!(f, "): {}", ret)
+            }
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::host::{HostFuncError, HostFuncs};
 use crate::interpreter;
 use crate::ir::*;
 
@@ -17,41 +18,40 @@ pub struct Guard {
     // stackのサイズ、call_stackのサイズ
 }
 
-#[derive(Debug, PartialEq, Clone, Eq)]
+#[derive(Debug)]
 pub struct Vm<'a> {
     module: &'a Module,
-    interpreter: interpreter::Interpreter<'a, interpreter::WasmBuiltin>,
+    interpreter: interpreter::Interpreter<'a>,
     loop_states: Vec<Vec<LoopState>>,
 }
 
 impl<'a> Vm<'a> {
-    pub fn new(module: &'a Module) -> Self {
-        let interpreter = interpreter::Interpreter::new(module, interpreter::WasmBuiltin);
+    pub fn new(module: &'a Module, host_funcs: HostFuncs) -> Result<Self, HostFuncError> {
+        let interpreter = interpreter::Interpreter::new(module, host_funcs)?;
         let loop_states = module
             .funcs
             .iter()
             .map(|f| std::vec::from_elem(LoopState::Profiling { count: 0 }, f.loop_infos.len()))
             .collect();
 
-        Vm {
+        Ok(Vm {
             module,
             interpreter,
             loop_states,
-        }
+        })
     }
 
-    pub fn step(&mut self) {
-        match &self.module.funcs[self.interpreter.pc.func].instrs[self.interpreter.pc.instr] {
-            &Instr::Loop(idx) => {
-                let loop_state = &mut self.loop_states[self.interpreter.pc.func][idx];
-                match loop_state {
-                    LoopState::Profiling { count } => {
-                        *count += 1;
-                    }
+    pub fn step(&mut self) -> Result<(), interpreter::Trap> {
+        if let &Instr::Loop(idx) =
+            &self.module.funcs[self.interpreter.pc.func].instrs[self.interpreter.pc.instr]
+        {
+            let loop_state = &mut self.loop_states[self.interpreter.pc.func][idx];
+            match loop_state {
+                LoopState::Profiling { count } => {
+                    *count += 1;
                 }
             }
-            _ => {}
         }
-        self.interpreter.step();
+        self.interpreter.step()
     }
 }
@@ -1,12 +1,53 @@
 use std::collections::HashMap;
 
+use crate::interpreter::Trap;
 use crate::ir;
-use parity_wasm::elements::{BlockType, Instruction, ValueType};
+use crate::types::Type;
+use parity_wasm::elements::{BlockType, BrTableData, Instruction, ValueType};
 
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
 
 pub enum BuiltinFunc {
-    Println,
+    // ir::Module::host_funcsのindex
+    Host(usize),
+    // (size: i32) -> i32
+    Alloc,
+}
+
+pub fn value_type(ty: Type) -> ValueType {
+    match ty {
+        Type::I32 => ValueType::I32,
+        Type::I64 => ValueType::I64,
+        Type::F64 => ValueType::F64,
+    }
+}
+
+// 配列の操作で値を一時的に退避するために関数のローカル変数の後ろに追加するローカル変数
+pub const SCRATCH_LOCALS: [Type; 8] = [
+    Type::I32,
+    Type::I32,
+    Type::I32,
+    Type::I64,
+    Type::F64,
+    Type::I32,
+    Type::I64,
+    Type::F64,
+];
+
+fn load_instr(ty: Type, offset: u32) -> Instruction {
+    match ty {
+        Type::I32 => Instruction::I32Load(2, offset),
+        Type::I64 => Instruction::I64Load(3, offset),
+        Type::F64 => Instruction::F64Load(3, offset),
+    }
+}
+
+fn store_instr(ty: Type, offset: u32) -> Instruction {
+    match ty {
+        Type::I32 => Instruction::I32Store(2, offset),
+        Type::I64 => Instruction::I64Store(3, offset),
+        Type::F64 => Instruction::F64Store(3, offset),
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
@@ -16,19 +57,59 @@ pub enum FuncRef {
 }
 
 pub struct InstrsGenerator {
-    // args_count -> type_id
+    // func -> type_id
     pub types: HashMap<usize, u32>,
     pub func_refs: HashMap<usize, FuncRef>,
     pub builtin_func_refs: HashMap<BuiltinFunc, FuncRef>,
+    // コンスタントプールのindex -> データセグメント上のアドレス
+    pub string_addrs: Vec<u32>,
+    // Module::closuresのindex -> データセグメント上のアドレス
+    pub closure_addrs: Vec<u32>,
+    // 関数の値の呼び出しに使う
+    pub signature_types: HashMap<ir::Signature, u32>,
+    // 燃料を持つi64のグローバル変数。Noneなら計測しない
+    pub fuel_global: Option<u32>,
+    // trapの理由を書き込むi32のグローバル変数。Noneなら書き込まない
+    pub trap_global: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Clone, Hash, Eq)]
+enum Label {
+    If,
+    // ループ全体を囲むblock。breakの飛び先
+    LoopBlock(ir::LoopId),
+    // continueの飛び先
+    Loop(ir::LoopId),
+    // matchの結果を受け取るblock
+    SwitchEnd(ir::SwitchId),
+    // matchの各腕の直前で終わるblock
+    Case,
 }
 
 struct InstrsGeneratorState {
     instrs: Vec<Instruction>,
+    labels: Vec<Label>,
+    fuel_costs: ir::FuelCosts,
 }
 
 impl InstrsGeneratorState {
-    fn new() -> Self {
-        InstrsGeneratorState { instrs: Vec::new() }
+    fn new(fuel_costs: ir::FuelCosts) -> Self {
+        InstrsGeneratorState {
+            instrs: Vec::new(),
+            labels: Vec::new(),
+            fuel_costs,
+        }
+    }
+
+    fn label_depth(&self, label: &Label) -> u32 {
+        let idx = self.labels.iter().rposition(|x| x == label).unwrap();
+        (self.labels.len() - 1 - idx) as u32
+    }
+}
+
+impl Default for InstrsGenerator {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -38,63 +119,290 @@ impl InstrsGenerator {
             types: HashMap::new(),
             func_refs: HashMap::new(),
             builtin_func_refs: HashMap::new(),
+            string_addrs: Vec::new(),
+            closure_addrs: Vec::new(),
+            signature_types: HashMap::new(),
+            fuel_global: None,
+            trap_global: None,
         }
     }
 
-    pub fn gen_instrs(&self, instrs: &Vec<ir::Instr>) -> Vec<Instruction> {
-        let mut state = InstrsGeneratorState::new();
-        for instr in instrs {
-            self.gen_instr(&mut state, instr);
+    // IRの各命令から生成した命令列の先頭のindexも返す
+    pub fn gen_instrs(&self, func: &ir::Func) -> (Vec<Instruction>, Vec<usize>) {
+        let instrs = &func.instrs;
+        let mut state = InstrsGeneratorState::new(func.fuel_costs());
+        let mut starts = Vec::new();
+        for (i, instr) in instrs.iter().enumerate() {
+            starts.push(state.instrs.len());
+            // 関数の入口での燃料の消費は最初の命令に含める
+            if i == 0 {
+                let amount = state.fuel_costs.entry;
+                self.gen_consume_fuel(&mut state, amount);
+            }
+            self.gen_instr(&mut state, func, instr);
         }
         state.instrs.push(Instruction::End);
-        state.instrs
+        (state.instrs, starts)
     }
 
-    fn gen_instr(&self, state: &mut InstrsGeneratorState, instr: &ir::Instr) {
+    fn gen_instr(&self, state: &mut InstrsGeneratorState, func: &ir::Func, instr: &ir::Instr) {
         match instr {
             ir::Instr::NonControl(non_control) => match non_control {
-                ir::NonControlInstr::IntConst(x) => {
-                    state.instrs.push(Instruction::I32Const(*x));
+                &ir::NonControlInstr::Const(x) => {
+                    state.instrs.push(match x {
+                        ir::Const::I32(x) => Instruction::I32Const(x),
+                        ir::Const::I64(x) => Instruction::I64Const(x),
+                        ir::Const::F64(x) => Instruction::F64Const(x),
+                    });
                 }
                 ir::NonControlInstr::VarRef(idx) => {
                     state.instrs.push(Instruction::GetLocal(*idx as u32));
                 }
-                ir::NonControlInstr::Add => state.instrs.push(Instruction::I32Add),
-                ir::NonControlInstr::Sub => state.instrs.push(Instruction::I32Sub),
-                ir::NonControlInstr::Mul => state.instrs.push(Instruction::I32Mul),
-                ir::NonControlInstr::Div => state.instrs.push(Instruction::I32DivS),
-                ir::NonControlInstr::Mod => state.instrs.push(Instruction::I32RemS),
-                ir::NonControlInstr::Lt => state.instrs.push(Instruction::I32LtS),
-                ir::NonControlInstr::Gt => state.instrs.push(Instruction::I32GtS),
-                ir::NonControlInstr::Le => state.instrs.push(Instruction::I32LeS),
-                ir::NonControlInstr::Ge => state.instrs.push(Instruction::I32GeS),
-                ir::NonControlInstr::Eq => state.instrs.push(Instruction::I32Eq),
-                ir::NonControlInstr::Ne => state.instrs.push(Instruction::I32Ne),
-                ir::NonControlInstr::And => state.instrs.push(Instruction::I32And),
-                ir::NonControlInstr::Or => state.instrs.push(Instruction::I32Or),
+                ir::NonControlInstr::Add(ty) => state.instrs.push(match ty {
+                    Type::I32 => Instruction::I32Add,
+                    Type::I64 => Instruction::I64Add,
+                    Type::F64 => Instruction::F64Add,
+                }),
+                ir::NonControlInstr::Sub(ty) => state.instrs.push(match ty {
+                    Type::I32 => Instruction::I32Sub,
+                    Type::I64 => Instruction::I64Sub,
+                    Type::F64 => Instruction::F64Sub,
+                }),
+                ir::NonControlInstr::Mul(ty) => state.instrs.push(match ty {
+                    Type::I32 => Instruction::I32Mul,
+                    Type::I64 => Instruction::I64Mul,
+                    Type::F64 => Instruction::F64Mul,
+                }),
+                &ir::NonControlInstr::Div(ty) => match ty {
+                    Type::I32 => {
+                        self.gen_div_check(state, func, ty, true);
+                        state.instrs.push(Instruction::I32DivS);
+                    }
+                    Type::I64 => {
+                        self.gen_div_check(state, func, ty, true);
+                        state.instrs.push(Instruction::I64DivS);
+                    }
+                    Type::F64 => state.instrs.push(Instruction::F64Div),
+                },
+                // MIN % -1はwasmでも0になる
+                &ir::NonControlInstr::Mod(ty) => {
+                    self.gen_div_check(state, func, ty, false);
+                    state.instrs.push(match ty {
+                        Type::I32 => Instruction::I32RemS,
+                        Type::I64 => Instruction::I64RemS,
+                        Type::F64 => unreachable!(),
+                    });
+                }
+                ir::NonControlInstr::Lt(ty) => state.instrs.push(match ty {
+                    Type::I32 => Instruction::I32LtS,
+                    Type::I64 => Instruction::I64LtS,
+                    Type::F64 => Instruction::F64Lt,
+                }),
+                ir::NonControlInstr::Gt(ty) => state.instrs.push(match ty {
+                    Type::I32 => Instruction::I32GtS,
+                    Type::I64 => Instruction::I64GtS,
+                    Type::F64 => Instruction::F64Gt,
+                }),
+                ir::NonControlInstr::Le(ty) => state.instrs.push(match ty {
+                    Type::I32 => Instruction::I32LeS,
+                    Type::I64 => Instruction::I64LeS,
+                    Type::F64 => Instruction::F64Le,
+                }),
+                ir::NonControlInstr::Ge(ty) => state.instrs.push(match ty {
+                    Type::I32 => Instruction::I32GeS,
+                    Type::I64 => Instruction::I64GeS,
+                    Type::F64 => Instruction::F64Ge,
+                }),
+                ir::NonControlInstr::Eq(ty) => state.instrs.push(match ty {
+                    Type::I32 => Instruction::I32Eq,
+                    Type::I64 => Instruction::I64Eq,
+                    Type::F64 => Instruction::F64Eq,
+                }),
+                ir::NonControlInstr::Ne(ty) => state.instrs.push(match ty {
+                    Type::I32 => Instruction::I32Ne,
+                    Type::I64 => Instruction::I64Ne,
+                    Type::F64 => Instruction::F64Ne,
+                }),
                 ir::NonControlInstr::Not => state.instrs.push(Instruction::I32Eqz),
-                ir::NonControlInstr::Minus => {
+                ir::NonControlInstr::Minus(ty) => match ty {
+                    Type::I32 => {
+                        state.instrs.push(Instruction::I32Const(-1));
+                        state.instrs.push(Instruction::I32Mul);
+                    }
+                    Type::I64 => {
+                        state.instrs.push(Instruction::I64Const(-1));
+                        state.instrs.push(Instruction::I64Mul);
+                    }
+                    Type::F64 => state.instrs.push(Instruction::F64Neg),
+                },
+                // select(-x, x, x < 0)
+                &ir::NonControlInstr::Abs(ty) => match ty {
+                    Type::I32 => {
+                        let x = Self::scratch_value_local(func, ty);
+                        state.instrs.push(Instruction::SetLocal(x));
+                        state.instrs.push(Instruction::I32Const(0));
+                        state.instrs.push(Instruction::GetLocal(x));
+                        state.instrs.push(Instruction::I32Sub);
+                        state.instrs.push(Instruction::GetLocal(x));
+                        state.instrs.push(Instruction::GetLocal(x));
+                        state.instrs.push(Instruction::I32Const(0));
+                        state.instrs.push(Instruction::I32LtS);
+                        state.instrs.push(Instruction::Select);
+                    }
+                    Type::I64 => {
+                        let x = Self::scratch_value_local(func, ty);
+                        state.instrs.push(Instruction::SetLocal(x));
+                        state.instrs.push(Instruction::I64Const(0));
+                        state.instrs.push(Instruction::GetLocal(x));
+                        state.instrs.push(Instruction::I64Sub);
+                        state.instrs.push(Instruction::GetLocal(x));
+                        state.instrs.push(Instruction::GetLocal(x));
+                        state.instrs.push(Instruction::I64Const(0));
+                        state.instrs.push(Instruction::I64LtS);
+                        state.instrs.push(Instruction::Select);
+                    }
+                    Type::F64 => state.instrs.push(Instruction::F64Abs),
+                },
+                &ir::NonControlInstr::Min(ty) => match ty {
+                    Type::I32 => Self::gen_select_by(state, func, ty, Instruction::I32LtS),
+                    Type::I64 => Self::gen_select_by(state, func, ty, Instruction::I64LtS),
+                    Type::F64 => state.instrs.push(Instruction::F64Min),
+                },
+                &ir::NonControlInstr::Max(ty) => match ty {
+                    Type::I32 => Self::gen_select_by(state, func, ty, Instruction::I32GtS),
+                    Type::I64 => Self::gen_select_by(state, func, ty, Instruction::I64GtS),
+                    Type::F64 => state.instrs.push(Instruction::F64Max),
+                },
+                ir::NonControlInstr::Assert => {
+                    state.instrs.push(Instruction::I32Eqz);
+                    self.gen_trap_if(state, Trap::AssertionFailed);
                     state.instrs.push(Instruction::I32Const(0));
-                    state.instrs.push(Instruction::I32Sub);
+                }
+                &ir::NonControlInstr::Convert { from, to } => {
+                    if from == Type::F64 {
+                        self.gen_truncate_check(state, func, to);
+                    }
+                    state.instrs.push(match (from, to) {
+                        (Type::I32, Type::I64) => Instruction::I64ExtendSI32,
+                        (Type::I32, Type::F64) => Instruction::F64ConvertSI32,
+                        (Type::I64, Type::I32) => Instruction::I32WrapI64,
+                        (Type::I64, Type::F64) => Instruction::F64ConvertSI64,
+                        (Type::F64, Type::I32) => Instruction::I32TruncSF64,
+                        (Type::F64, Type::I64) => Instruction::I64TruncSF64,
+                        _ => unreachable!(),
+                    });
                 }
                 ir::NonControlInstr::Assign(idx) => {
                     state.instrs.push(Instruction::SetLocal(*idx as u32));
                 }
-                ir::NonControlInstr::Println => {
-                    let func_ref = &self.builtin_func_refs[&BuiltinFunc::Println];
-                    self.gen_func_refs(state, func_ref, 1);
+                // グローバル変数はindex順にimportしている
+                ir::NonControlInstr::GlobalRef(idx) => {
+                    state.instrs.push(Instruction::GetGlobal(*idx as u32));
+                }
+                ir::NonControlInstr::GlobalAssign(idx) => {
+                    state.instrs.push(Instruction::SetGlobal(*idx as u32));
+                }
+                ir::NonControlInstr::FuncRef(idx) => {
+                    state
+                        .instrs
+                        .push(Instruction::I32Const(ir::table_idx(*idx) as i32));
+                }
+                &ir::NonControlInstr::CallHost(idx) => {
+                    let func_ref = &self.builtin_func_refs[&BuiltinFunc::Host(idx)];
+                    self.gen_func_refs(state, func_ref, 0);
+                }
+                &ir::NonControlInstr::ArrayNew(ty) => {
+                    let len = Self::scratch_local(func, 0);
+                    let array = Self::scratch_local(func, 1);
+                    state.instrs.push(Instruction::SetLocal(len));
+                    // 負の長さも符号なしで比較すれば弾ける
+                    state.instrs.push(Instruction::GetLocal(len));
+                    state
+                        .instrs
+                        .push(Instruction::I32Const(ir::max_array_len(ty) as i32));
+                    state.instrs.push(Instruction::I32GtU);
+                    self.gen_trap_if(state, Trap::InvalidArrayLength);
+                    state.instrs.push(Instruction::GetLocal(len));
+                    state.instrs.push(Instruction::I32Const(ty.size() as i32));
+                    state.instrs.push(Instruction::I32Mul);
+                    state
+                        .instrs
+                        .push(Instruction::I32Const(ir::ARRAY_HEADER_SIZE as i32));
+                    state.instrs.push(Instruction::I32Add);
+                    let func_ref = &self.builtin_func_refs[&BuiltinFunc::Alloc];
+                    self.gen_func_refs(state, func_ref, 0);
+                    state.instrs.push(Instruction::TeeLocal(array));
+                    state.instrs.push(Instruction::GetLocal(len));
+                    state.instrs.push(store_instr(Type::I32, 0));
+                    state.instrs.push(Instruction::GetLocal(array));
+                }
+                &ir::NonControlInstr::ArrayGet(ty) => {
+                    let idx = Self::scratch_local(func, 1);
+                    state.instrs.push(Instruction::SetLocal(idx));
+                    self.gen_array_elem_addr(state, func, ty);
+                    state.instrs.push(load_instr(ty, ir::ARRAY_HEADER_SIZE));
+                }
+                &ir::NonControlInstr::ArraySet(ty) => {
+                    let x = Self::scratch_value_local(func, ty);
+                    let idx = Self::scratch_local(func, 1);
+                    state.instrs.push(Instruction::SetLocal(x));
+                    state.instrs.push(Instruction::SetLocal(idx));
+                    self.gen_array_elem_addr(state, func, ty);
+                    state.instrs.push(Instruction::GetLocal(x));
+                    state.instrs.push(store_instr(ty, ir::ARRAY_HEADER_SIZE));
+                }
+                ir::NonControlInstr::ArrayLen => {
+                    state.instrs.push(load_instr(Type::I32, 0));
+                }
+                &ir::NonControlInstr::StringConst(idx) => {
+                    state
+                        .instrs
+                        .push(Instruction::I32Const(self.string_addrs[idx] as i32));
+                }
+                &ir::NonControlInstr::ClosureConst(idx) => {
+                    state
+                        .instrs
+                        .push(Instruction::I32Const(self.closure_addrs[idx] as i32));
+                }
+                &ir::NonControlInstr::Alloc(size) => {
+                    state.instrs.push(Instruction::I32Const(size as i32));
+                    let func_ref = &self.builtin_func_refs[&BuiltinFunc::Alloc];
+                    self.gen_func_refs(state, func_ref, 0);
+                }
+                &ir::NonControlInstr::Load { ty, offset } => {
+                    self.gen_null_check(state, func);
+                    state.instrs.push(load_instr(ty, offset));
+                }
+                &ir::NonControlInstr::Store { ty, offset } => {
+                    let x = Self::scratch_value_local(func, ty);
+                    state.instrs.push(Instruction::SetLocal(x));
+                    self.gen_null_check(state, func);
+                    state.instrs.push(Instruction::GetLocal(x));
+                    state.instrs.push(store_instr(ty, offset));
                 }
                 ir::NonControlInstr::Drop => {
                     state.instrs.push(Instruction::Drop);
                 }
             },
-            ir::Instr::Call { func, args_count } => {
+            ir::Instr::Call { func, .. } => {
                 let func_ref = &self.func_refs[func];
-                self.gen_func_refs(state, func_ref, *args_count);
+                self.gen_func_refs(state, func_ref, *func);
+            }
+            // テーブルの型検査でシグネチャの異なる関数やnullの呼び出しはtrapする
+            ir::Instr::CallIndirect(signature) => {
+                state.instrs.push(Instruction::CallIndirect(
+                    self.signature_types[signature],
+                    0,
+                ));
             }
-            ir::Instr::Loop(_) => {
+            &ir::Instr::Loop(loop_id) => {
                 state.instrs.push(Instruction::Block(BlockType::NoResult));
+                state.labels.push(Label::LoopBlock(loop_id));
                 state.instrs.push(Instruction::Loop(BlockType::NoResult));
+                state.labels.push(Label::Loop(loop_id));
+                // continueで戻ってくるたびに消費する
+                let amount = state.fuel_costs.loops[loop_id];
+                self.gen_consume_fuel(state, amount);
             }
             ir::Instr::LoopThen(_) => {
                 state.instrs.push(Instruction::I32Eqz);
@@ -103,18 +411,89 @@ impl InstrsGenerator {
             ir::Instr::LoopEnd(_) => {
                 state.instrs.push(Instruction::Br(0));
                 state.instrs.push(Instruction::End);
+                state.labels.pop();
                 state.instrs.push(Instruction::End);
+                state.labels.pop();
+            }
+            &ir::Instr::Break(loop_id) => {
+                let depth = state.label_depth(&Label::LoopBlock(loop_id));
+                state.instrs.push(Instruction::Br(depth));
+            }
+            &ir::Instr::Continue(loop_id) => {
+                let depth = state.label_depth(&Label::Loop(loop_id));
+                state.instrs.push(Instruction::Br(depth));
+            }
+            &ir::Instr::If(if_id) => {
+                let ty = func.if_infos[if_id].ty;
+                state
+                    .instrs
+                    .push(Instruction::If(BlockType::Value(value_type(ty))));
+                state.labels.push(Label::If);
             }
-            ir::Instr::If(_) => {
+            // 内側のblockほど前の腕に対応し、br_tableで腕の直前に飛ぶ
+            &ir::Instr::Switch(switch_id) => {
+                let switch_info = &func.switch_infos[switch_id];
+                let table_len = switch_info.table.len() as i32;
+                match switch_info.scrutinee_ty {
+                    Type::I32 => {
+                        state
+                            .instrs
+                            .push(Instruction::I32Const(switch_info.min as i32));
+                        state.instrs.push(Instruction::I32Sub);
+                    }
+                    // 範囲外の値がi32に切り詰められて範囲内にならないようにする
+                    Type::I64 => {
+                        let x = Self::scratch_value_local(func, Type::I64);
+                        state.instrs.push(Instruction::I64Const(switch_info.min));
+                        state.instrs.push(Instruction::I64Sub);
+                        state.instrs.push(Instruction::TeeLocal(x));
+                        state.instrs.push(Instruction::I32WrapI64);
+                        state.instrs.push(Instruction::I32Const(table_len));
+                        state.instrs.push(Instruction::GetLocal(x));
+                        state.instrs.push(Instruction::I64Const(table_len as i64));
+                        state.instrs.push(Instruction::I64LtU);
+                        state.instrs.push(Instruction::Select);
+                    }
+                    Type::F64 => unreachable!(),
+                }
+                let idx = Self::scratch_value_local(func, Type::I32);
+                state.instrs.push(Instruction::SetLocal(idx));
                 state
                     .instrs
-                    .push(Instruction::If(BlockType::Value(ValueType::I32)));
+                    .push(Instruction::Block(BlockType::Value(value_type(
+                        switch_info.ty,
+                    ))));
+                state.labels.push(Label::SwitchEnd(switch_id));
+                for _ in &switch_info.cases {
+                    state.instrs.push(Instruction::Block(BlockType::NoResult));
+                    state.labels.push(Label::Case);
+                }
+                state.instrs.push(Instruction::GetLocal(idx));
+                state
+                    .instrs
+                    .push(Instruction::BrTable(Box::new(BrTableData {
+                        table: switch_info.table.iter().map(|&case| case as u32).collect(),
+                        default: switch_info.default_case() as u32,
+                    })));
+            }
+            &ir::Instr::Case(switch_id, case) => {
+                if case != 0 {
+                    let depth = state.label_depth(&Label::SwitchEnd(switch_id));
+                    state.instrs.push(Instruction::Br(depth));
+                }
+                state.instrs.push(Instruction::End);
+                state.labels.pop();
+            }
+            ir::Instr::SwitchEnd(_) => {
+                state.instrs.push(Instruction::End);
+                state.labels.pop();
             }
             ir::Instr::Else(_) => {
                 state.instrs.push(Instruction::Else);
             }
             ir::Instr::IfEnd(_) => {
                 state.instrs.push(Instruction::End);
+                state.labels.pop();
             }
             ir::Instr::Return => {
                 state.instrs.push(Instruction::Return);
@@ -122,12 +501,179 @@ impl InstrsGenerator {
         }
     }
 
-    fn gen_func_refs(
+    fn scratch_local(func: &ir::Func, idx: usize) -> u32 {
+        (func.locals.len() + idx) as u32
+    }
+
+    // 値を退避するための一時変数。インデックスなどと重ならないようにする
+    fn scratch_value_local(func: &ir::Func, ty: Type) -> u32 {
+        Self::scratch_local(
+            func,
+            match ty {
+                Type::I32 => 2,
+                Type::I64 => 3,
+                Type::F64 => 4,
+            },
+        )
+    }
+
+    // min, maxで2つ目の値を退避するための一時変数
+    fn scratch_value_local2(func: &ir::Func, ty: Type) -> u32 {
+        Self::scratch_local(
+            func,
+            match ty {
+                Type::I32 => 5,
+                Type::I64 => 6,
+                Type::F64 => 7,
+            },
+        )
+    }
+
+    // スタックの先頭のアドレスが0ならtrapする
+    fn gen_null_check(&self, state: &mut InstrsGeneratorState, func: &ir::Func) {
+        let addr = Self::scratch_local(func, 0);
+        state.instrs.push(Instruction::TeeLocal(addr));
+        state.instrs.push(Instruction::I32Eqz);
+        self.gen_trap_if(state, Trap::NullPointer);
+        state.instrs.push(Instruction::GetLocal(addr));
+    }
+
+    // [x, y] -> [x, y]
+    // wasm自身にtrapさせるとインタプリタと理由が区別できないので、先に調べてtrapする
+    fn gen_div_check(
         &self,
         state: &mut InstrsGeneratorState,
-        func_ref: &FuncRef,
-        args_count: usize,
+        func: &ir::Func,
+        ty: Type,
+        check_overflow: bool,
+    ) {
+        let x = Self::scratch_value_local(func, ty);
+        let y = Self::scratch_value_local2(func, ty);
+        state.instrs.push(Instruction::SetLocal(y));
+        state.instrs.push(Instruction::SetLocal(x));
+        state.instrs.push(Instruction::GetLocal(y));
+        state.instrs.push(match ty {
+            Type::I32 => Instruction::I32Eqz,
+            Type::I64 => Instruction::I64Eqz,
+            Type::F64 => unreachable!(),
+        });
+        self.gen_trap_if(state, Trap::DivideByZero);
+        if check_overflow {
+            state.instrs.push(Instruction::GetLocal(x));
+            match ty {
+                Type::I32 => {
+                    state.instrs.push(Instruction::I32Const(i32::MIN));
+                    state.instrs.push(Instruction::I32Eq);
+                    state.instrs.push(Instruction::GetLocal(y));
+                    state.instrs.push(Instruction::I32Const(-1));
+                    state.instrs.push(Instruction::I32Eq);
+                }
+                Type::I64 => {
+                    state.instrs.push(Instruction::I64Const(i64::MIN));
+                    state.instrs.push(Instruction::I64Eq);
+                    state.instrs.push(Instruction::GetLocal(y));
+                    state.instrs.push(Instruction::I64Const(-1));
+                    state.instrs.push(Instruction::I64Eq);
+                }
+                Type::F64 => unreachable!(),
+            }
+            state.instrs.push(Instruction::I32And);
+            self.gen_trap_if(state, Trap::IntegerOverflow);
+        }
+        state.instrs.push(Instruction::GetLocal(x));
+        state.instrs.push(Instruction::GetLocal(y));
+    }
+
+    // [x] -> [x]。NaNと、切り捨てるとtoの範囲に入らない値でtrapする
+    fn gen_truncate_check(&self, state: &mut InstrsGeneratorState, func: &ir::Func, to: Type) {
+        let x = Self::scratch_value_local(func, Type::F64);
+        state.instrs.push(Instruction::TeeLocal(x));
+        state.instrs.push(Instruction::GetLocal(x));
+        state.instrs.push(Instruction::F64Ne);
+        self.gen_trap_if(state, Trap::InvalidConversion);
+        // MIN - 1 < x < MAX + 1。i64のMIN - 1はf64ではMINに丸められるのでMIN <= xで調べる
+        let (min, min_cmp, max) = match to {
+            Type::I32 => (
+                i32::MIN as f64 - 1.0,
+                Instruction::F64Gt,
+                i32::MAX as f64 + 1.0,
+            ),
+            Type::I64 => (i64::MIN as f64, Instruction::F64Ge, -(i64::MIN as f64)),
+            Type::F64 => unreachable!(),
+        };
+        state.instrs.push(Instruction::GetLocal(x));
+        state.instrs.push(Instruction::F64Const(min.to_bits()));
+        state.instrs.push(min_cmp);
+        state.instrs.push(Instruction::GetLocal(x));
+        state.instrs.push(Instruction::F64Const(max.to_bits()));
+        state.instrs.push(Instruction::F64Lt);
+        state.instrs.push(Instruction::I32And);
+        state.instrs.push(Instruction::I32Eqz);
+        self.gen_trap_if(state, Trap::IntegerOverflow);
+        state.instrs.push(Instruction::GetLocal(x));
+    }
+
+    // [x, y] -> [select(x, y, cmp(x, y))]
+    fn gen_select_by(
+        state: &mut InstrsGeneratorState,
+        func: &ir::Func,
+        ty: Type,
+        cmp: Instruction,
     ) {
+        let x = Self::scratch_value_local(func, ty);
+        let y = Self::scratch_value_local2(func, ty);
+        state.instrs.push(Instruction::SetLocal(y));
+        state.instrs.push(Instruction::TeeLocal(x));
+        state.instrs.push(Instruction::GetLocal(y));
+        state.instrs.push(Instruction::GetLocal(x));
+        state.instrs.push(Instruction::GetLocal(y));
+        state.instrs.push(cmp);
+        state.instrs.push(Instruction::Select);
+    }
+
+    // 埋め込み側がインタプリタと同じメッセージを出せるように、trapする前に理由を書き込む
+    fn gen_trap_if(&self, state: &mut InstrsGeneratorState, trap: Trap) {
+        state.instrs.push(Instruction::If(BlockType::NoResult));
+        if let Some(trap_global) = self.trap_global {
+            state.instrs.push(Instruction::I32Const(trap.code()));
+            state.instrs.push(Instruction::SetGlobal(trap_global));
+        }
+        state.instrs.push(Instruction::Unreachable);
+        state.instrs.push(Instruction::End);
+    }
+
+    // インタプリタと同じく燃料をamountだけ減らし、負になったらtrapする
+    fn gen_consume_fuel(&self, state: &mut InstrsGeneratorState, amount: i64) {
+        if let Some(fuel) = self.fuel_global {
+            state.instrs.push(Instruction::GetGlobal(fuel));
+            state.instrs.push(Instruction::I64Const(amount));
+            state.instrs.push(Instruction::I64Sub);
+            state.instrs.push(Instruction::SetGlobal(fuel));
+            state.instrs.push(Instruction::GetGlobal(fuel));
+            state.instrs.push(Instruction::I64Const(0));
+            state.instrs.push(Instruction::I64LtS);
+            self.gen_trap_if(state, Trap::OutOfFuel);
+        }
+    }
+
+    // 配列はスタックに、インデックスは1番目の一時変数に入っているものとする
+    // 範囲外ならtrapする
+    fn gen_array_elem_addr(&self, state: &mut InstrsGeneratorState, func: &ir::Func, ty: Type) {
+        let array = Self::scratch_local(func, 0);
+        let idx = Self::scratch_local(func, 1);
+        state.instrs.push(Instruction::TeeLocal(array));
+        state.instrs.push(load_instr(Type::I32, 0));
+        state.instrs.push(Instruction::GetLocal(idx));
+        state.instrs.push(Instruction::I32LeU);
+        self.gen_trap_if(state, Trap::ArrayIndexOutOfBounds);
+        state.instrs.push(Instruction::GetLocal(array));
+        state.instrs.push(Instruction::GetLocal(idx));
+        state.instrs.push(Instruction::I32Const(ty.size() as i32));
+        state.instrs.push(Instruction::I32Mul);
+        state.instrs.push(Instruction::I32Add);
+    }
+
+    fn gen_func_refs(&self, state: &mut InstrsGeneratorState, func_ref: &FuncRef, func: usize) {
         match func_ref {
             FuncRef::Direct(idx) => {
                 state.instrs.push(Instruction::Call(*idx));
@@ -136,7 +682,7 @@ impl InstrsGenerator {
                 state.instrs.push(Instruction::I32Const(*idx as i32));
                 state
                     .instrs
-                    .push(Instruction::CallIndirect(self.types[&args_count], 0));
+                    .push(Instruction::CallIndirect(self.types[&func], 0));
             }
         }
     }
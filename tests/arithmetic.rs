@@ -0,0 +1,78 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn integer_arithmetic_wraps() {
+    let src = r#"
+func main() {
+  println(2147483647 + 1);
+  println(65536 * 65536 + 3);
+  println(0 - (0 - 2147483647 - 1));
+  println_i64(9223372036854775807i64 + 1i64);
+  println(0 - 7 / 2);
+  println((0 - 7) % 3);
+  println((0 - 2147483647 - 1) % (0 - 1));
+}
+"#;
+    assert_eq!(
+        output(src),
+        "-2147483648\n3\n-2147483648\n-9223372036854775808\n-3\n-1\n0\n"
+    );
+}
+
+#[test]
+fn division_traps() {
+    let cases = [
+        ("var x = 0 in 1 / x", "integer divide by zero"),
+        ("var x = 0 in 1 % x", "integer divide by zero"),
+        ("var x = 1 in x /= 0", "integer divide by zero"),
+        ("var x = 0i64 in 1i64 % x", "integer divide by zero"),
+        ("var x = 0i64 in 1i64 / x", "integer divide by zero"),
+        (
+            "var x = 0 - 1 in (0 - 2147483647 - 1) / x",
+            "integer overflow",
+        ),
+        (
+            "var x = 0i64 - 1i64 in (0i64 - 9223372036854775807i64 - 1i64) / x",
+            "integer overflow",
+        ),
+    ];
+    for (body, message) in cases {
+        let src = format!("func main() {{\n  println(1);\n  {};\n  0;\n}}\n", body);
+        let run = run(&src);
+        assert_eq!(run.output, "1\n");
+        assert_eq!(run.trap.as_deref(), Some(message), "{}", body);
+    }
+}
+
+#[test]
+fn float_to_int_conversion() {
+    assert_eq!(
+        output("func main() { println((0.0 - 2.9) as i32); println_i64(1e18 as i64); }\n"),
+        "-2\n1000000000000000000\n"
+    );
+    // 切り捨てて範囲に入る値の端
+    assert_eq!(
+        output(concat!(
+            "func main() {\n",
+            "  println((0.0 - 2147483648.9) as i32);\n",
+            "  println(2147483647.9 as i32);\n",
+            "  println_i64((0.0 - 9223372036854775808.0) as i64);\n",
+            "}\n"
+        )),
+        "-2147483648\n2147483647\n-9223372036854775808\n"
+    );
+    let cases = [
+        ("(0.0 / 0.0) as i32", "invalid conversion to integer"),
+        ("2147483648.0 as i32", "integer overflow"),
+        ("1e19 as i64", "integer overflow"),
+        ("(0.0 - 2147483649.0) as i32", "integer overflow"),
+        ("9223372036854775808.0 as i64", "integer overflow"),
+        ("(0.0 / 0.0) as i64", "invalid conversion to integer"),
+    ];
+    for (expr, message) in cases {
+        let src = format!("func main() {{\n  {};\n  0;\n}}\n", expr);
+        assert_eq!(run(&src).trap.as_deref(), Some(message), "{}", expr);
+    }
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn arrays_of_each_element_type() {
+    let src = r#"
+func main() {
+  var a: [i32] = array(3) in var b: [i64] = array(2) in var c: [f64] = array(2) in {
+    a[0] = 1; a[2] = 3;
+    b[1] = 5000000000i64;
+    c[0] = 0.25;
+    println(a[0] + a[1] + a[2]);
+    println_i64(b[0] + b[1]);
+    println_f64(c[0] + c[1]);
+    println(len(a) + len(b) + len(c));
+  };
+}
+"#;
+    assert_eq!(output(src), "4\n5000000000\n0.25\n7\n");
+}
+
+#[test]
+fn nested_arrays() {
+    let src = r#"
+func main() {
+  var grid: [[i32]] = array(2) in {
+    grid[0] = array(2);
+    grid[1] = array(3);
+    grid[1][2] = 9;
+    println(len(grid[1]));
+    println(grid[1][2]);
+    println(len(array(0)));
+  };
+}
+"#;
+    assert_eq!(output(src), "3\n9\n0\n");
+}
+
+#[test]
+fn negative_index_is_out_of_bounds() {
+    let src = "func main() {\n  var a: [i32] = array(2) in a[0 - 1];\n}\n";
+    assert_eq!(run(src).trap.as_deref(), Some("array index out of bounds"));
+}
+
+#[test]
+fn indexing_a_non_array_is_an_error() {
+    assert!(compile_error("func main() {\n  var x = 1 in x[0];\n}\n").starts_with("main.wjit:2:"));
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn compound_assignment_operators() {
+    let src = r#"
+func main() {
+  var x = 10 in {
+    x += 5; println(x);
+    x -= 3; println(x);
+    x *= 2; println(x);
+    x /= 5; println(x);
+    x %= 3; println(x);
+  };
+}
+"#;
+    assert_eq!(output(src), "15\n12\n24\n4\n1\n");
+}
+
+#[test]
+fn compound_assignment_evaluates_operands_once() {
+    let src = r#"
+global calls = 0;
+struct P { x: i32 }
+func main() {
+  var a: [i32] = array(3) in var ps: [P] = array(2) in {
+    a[f()] += 5;
+    a[f()] *= 3;
+    ps[1] = P { x: 4 };
+    ps[f()].x += 2;
+    println(a[1]);
+    println(ps[1].x);
+    println(calls);
+  };
+}
+func f() { calls += 1; 1; }
+"#;
+    assert_eq!(output(src), "15\n6\n3\n");
+}
+
+#[test]
+fn for_loops() {
+    let src = r#"
+func main() {
+  var s: i64 = 0 in {
+    for (var i: i64 = 0; i < 5; i += 1) { s += i; };
+    println_i64(s);
+  };
+  var n = 0 in {
+    for (n = 10; n > 0; n -= 3) {
+      if (n == 7) { continue; };
+      println(n);
+    };
+    println(n);
+  };
+}
+"#;
+    assert_eq!(output(src), "10\n10\n4\n1\n-2\n");
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use common::*;
+
+fn frames(run: &Run) -> Vec<String> {
+    run.backtrace
+        .iter()
+        .map(|frame| frame.to_string())
+        .collect()
+}
+
+#[test]
+fn backtrace_has_function_names_and_lines() {
+    let src = r#"func main() {
+  println(f(3));
+}
+func f(n: i32) {
+  if (n == 0) {
+    assert(0);
+  };
+  f(n - 1) + 1;
+}
+"#;
+    let run = run(src);
+    assert_eq!(run.trap.as_deref(), Some("assertion failed"));
+    assert_eq!(
+        frames(&run),
+        vec![
+            "at f (main.wjit:6)",
+            "at f (main.wjit:8)",
+            "at f (main.wjit:8)",
+            "at f (main.wjit:8)",
+            "at main (main.wjit:2)",
+        ]
+    );
+}
+
+#[test]
+fn native_wasm_traps_have_backtraces() {
+    let src = "func main() {\n  div(1, 0);\n}\nfunc div(x: i32, y: i32) {\n  x / y;\n}\n";
+    assert_eq!(
+        frames(&run(src)),
+        vec!["at div (main.wjit:5)", "at main (main.wjit:2)"]
+    );
+}
+
+#[test]
+fn backtrace_shows_the_file_of_each_frame() {
+    let module = compile_files(&[
+        (
+            "main.wjit",
+            "import \"lib.wjit\" as lib;\nfunc main() {\n  lib.get(5);\n}\n",
+        ),
+        (
+            "lib.wjit",
+            "func get(i: i32) {\n  var a: [i32] = array(1) in a[i];\n}\n",
+        ),
+    ])
+    .unwrap();
+    let (result, _) = interpret_module(&module, None);
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "array index out of bounds\n    at lib.wjit::get (lib.wjit:2)\n    at main (main.wjit:3)"
+    );
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn user_functions_shadow_builtins_and_host_funcs() {
+    let src = r#"
+func main() {
+  println(min(3, 4));
+  println(abs(-5));
+  println(max(1, 2));
+  var f = min in println(f(1, 1));
+  println_str("x");
+}
+func min(a: i32, b: i32) { a * 100 + b; }
+func abs(x: i32) { x; }
+func println_str(s: str) { println(7); }
+"#;
+    assert_eq!(output(src), "304\n-5\n2\n101\n7\n");
+}
+
+#[test]
+fn duplicate_user_functions_are_still_rejected() {
+    let src = r#"
+func main() { 0; }
+func len(a: i32) { a; }
+func len(a: i32) { a; }
+"#;
+    assert!(compile_error(src).contains("`len`"));
+}
+
+#[test]
+fn embedder_registered_host_funcs() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use wjit::host::HostValue;
+    use wjit::interpreter::{Interpreter, Value};
+    use wjit::types::{Ty, Type};
+
+    let (mut host, _) = capturing_host("");
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    {
+        let calls = calls.clone();
+        host.register(
+            "mix",
+            vec![Ty::I32, Ty::Scalar(Type::I64), Ty::Scalar(Type::F64), Ty::Str],
+            Type::F64,
+            move |args| {
+                calls.borrow_mut().push(format!("{:?}", args));
+                match args {
+                    [HostValue::I32(a), HostValue::I64(b), HostValue::F64(c), HostValue::Str(s)] => {
+                        Value::F64(*a as f64 + *b as f64 + c + s.len() as f64)
+                    }
+                    _ => unreachable!(),
+                }
+            },
+        );
+    }
+    let src = "func f(): f64 { mix(1, 2i64, 0.5, \"abc\") + mix(0, 0i64, 0.0, \"\"); }\n";
+    let mut loader = MemLoader([("main.wjit".to_string(), src.to_string())].into());
+    let module = wjit::compile("main.wjit", &mut loader, &host.decls()).unwrap();
+    let mut interpreter = Interpreter::new(&module, host).unwrap();
+    assert_eq!(interpreter.call(0, &[]).unwrap(), Value::F64(6.5));
+    assert_eq!(
+        *calls.borrow(),
+        vec![
+            "[I32(1), I64(2), F64(0.5), Str(\"abc\")]",
+            "[I32(0), I64(0), F64(0.0), Str(\"\")]"
+        ]
+    );
+
+    // 登録されていない関数は使えない
+    let mut loader = MemLoader([("main.wjit".to_string(), src.to_string())].into());
+    assert!(wjit::compile("main.wjit", &mut loader, &capturing_host("").0.decls()).is_err());
+}
+
+#[test]
+fn host_func_arguments_are_checked() {
+    assert!(compile_error("func main() { println_str(1); }\n").starts_with("main.wjit:1:"));
+    assert!(compile_error("func main() { read_int(1); }\n").starts_with("main.wjit:1:"));
+}
+
+#[test]
+fn interpreter_checks_host_funcs_against_declarations() {
+    use wjit::host::HostFuncError;
+    use wjit::interpreter::{Interpreter, Trap, Value};
+    use wjit::types::Type;
+
+    let with_get = |ret: Type, value: Value| {
+        let (mut host, _) = capturing_host("");
+        host.register("get", vec![], ret, move |_| value);
+        host
+    };
+    let src = "func main() { get() + 1; }\n";
+    let mut loader = MemLoader([("main.wjit".to_string(), src.to_string())].into());
+    let decls = with_get(Type::I32, Value::I32(0)).decls();
+    let module = wjit::compile("main.wjit", &mut loader, &decls).unwrap();
+
+    let mut interpreter = Interpreter::new(&module, with_get(Type::I32, Value::I32(41))).unwrap();
+    assert_eq!(interpreter.call(0, &[]).unwrap(), Value::I32(42));
+
+    let not_registered = Interpreter::new(&module, capturing_host("").0).unwrap_err();
+    assert_eq!(
+        not_registered,
+        HostFuncError::NotRegistered {
+            name: "get".to_string()
+        }
+    );
+    assert_eq!(
+        not_registered.to_string(),
+        "host function `get` is not registered"
+    );
+    assert_eq!(
+        Interpreter::new(&module, with_get(Type::I64, Value::I64(0))).unwrap_err(),
+        HostFuncError::SignatureMismatch {
+            name: "get".to_string()
+        }
+    );
+
+    // 宣言と違う型の値を返したらtrapする
+    let mut interpreter = Interpreter::new(&module, with_get(Type::I32, Value::F64(1.0))).unwrap();
+    assert_eq!(
+        interpreter.call(0, &[]).unwrap_err().trap,
+        Trap::HostFuncTypeMismatch
+    );
+}
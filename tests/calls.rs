@@ -0,0 +1,53 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn too_many_arguments() {
+    let src = "func main() {\n  f(1, 2);\n}\nfunc f(a: i32) { a; }\n";
+    assert_eq!(
+        compile_error(src),
+        "main.wjit:2:3: function `f` takes 1 argument(s) but 2 were supplied"
+    );
+}
+
+#[test]
+fn too_few_arguments_to_builtin() {
+    let src = "func main() {\n  min(1);\n}\n";
+    assert!(compile_error(src).contains("function `min` takes 2 argument(s) but 1 were supplied"));
+}
+
+#[test]
+fn matching_arity_runs() {
+    let src = "func main() { println(f(1, 2)); }\nfunc f(a: i32, b: i32) { a - b; }\n";
+    assert_eq!(output(src), "-1\n");
+}
+
+#[test]
+fn functions_take_many_parameters() {
+    let src = r#"
+func main() {
+  println_f64(many(1, 2i64, 3.0, 4, 5, 6, 7i64, 8.5));
+  var f = many in println_f64(f(1, 1i64, 1.0, 1, 1, 1, 1i64, 1.0));
+}
+func many(a: i32, b: i64, c: f64, d: i32, e: i32, f: i32, g: i64, h: f64): f64 {
+  ((a + d + e + f) as f64) + ((b + g) as f64) + c + h;
+}
+"#;
+    assert_eq!(output(src), "36.5\n8\n");
+}
+
+#[test]
+fn functions_with_the_same_signature_share_a_type() {
+    let src = r#"
+func main() { f(1, 2) + g(3, 4); }
+func f(a: i32, b: i32) { a; }
+func g(a: i32, b: i32) { b; }
+func h(a: f64) { 0; }
+"#;
+    let module = compile_src(src).unwrap();
+    let skeleton = wjit::compiler::Compiler::new(&module).compile_skeleton();
+    let funcs = skeleton.function_section().unwrap().entries();
+    assert_eq!(funcs[1].type_ref(), funcs[2].type_ref());
+    assert_ne!(funcs[1].type_ref(), funcs[3].type_ref());
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn closures_capture_enclosing_bindings() {
+    let src = r#"
+func main() {
+  var add5 = adder(5) in var add7 = adder(7) in {
+    println(add5(1));
+    println(add7(1));
+    println(twice(add5, 0));
+    var base = 1.5 in var scale = fn(x: f64): f64 { x * base; } in println_f64(scale(3.0));
+  };
+}
+func adder(n: i32): func(i32): i32 { fn(x: i32): i32 { x + n; }; }
+func twice(f: func(i32): i32, x: i32) { f(f(x)); }
+"#;
+    assert_eq!(output(src), "6\n8\n10\n4.5\n");
+}
+
+#[test]
+fn closures_capture_values_when_created() {
+    let src = r#"
+func main() {
+  var i = 0 in var fs: [func(): i32] = array(3) in {
+    while (i < 3) {
+      var j = i * 10 in fs[i] = fn(): i32 { j; };
+      i = i + 1;
+    };
+    println(fs[0]() + fs[1]() + fs[2]());
+  };
+}
+"#;
+    assert_eq!(output(src), "30\n");
+}
+
+#[test]
+fn assigning_to_a_captured_variable_is_an_error() {
+    let src = "func main() {\n  var x = 1 in fn(): i32 { x = 2; };\n}\n";
+    assert!(compile_error(src).ends_with("cannot assign to captured variable `x`"));
+}
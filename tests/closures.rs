@@ -0,0 +1,92 @@
+mod common;
+
+use common::*;
+use wjit::interpreter::Interpreter;
+
+#[test]
+fn function_values_are_identical() {
+    let src = r#"
+func main() {
+  var g = add1 in {
+    println(g == add1);
+    println(add1 == add1);
+    println(g(41));
+    println(apply(add1, 5));
+  };
+}
+func add1(x: i32) { x + 1; }
+func apply(f: func(i32): i32, x: i32) { f(x); }
+"#;
+    assert_eq!(output(src), "1\n1\n42\n6\n");
+}
+
+#[test]
+fn function_values_do_not_allocate() {
+    let src = r#"
+func main() {
+  var g = add1 in {
+    var i = 0 in while (i < 100) { g = add1; i = i + 1; };
+    g(1);
+  };
+}
+func add1(x: i32) { x + 1; }
+"#;
+    let module = compile_src(src).unwrap();
+    let mut interpreter = Interpreter::new(&module, capturing_host("").0).unwrap();
+    let heap = interpreter.memory.heap;
+    assert_eq!(
+        interpreter.call(0, &[]).unwrap(),
+        wjit::interpreter::Value::I32(2)
+    );
+    assert_eq!(interpreter.memory.heap, heap);
+}
+
+#[test]
+fn calling_a_diverging_callee_is_an_error() {
+    let src = r#"
+func main() {
+  (return 1)(2);
+}
+"#;
+    assert!(compile_error(src).contains("expected function"));
+}
+
+#[test]
+fn functions_are_returned_and_called_through_expressions() {
+    let src = r#"
+func main() {
+  var fs: [func(i32): i32] = array(2) in {
+    fs[0] = pick(0);
+    fs[1] = pick(1);
+    println(fs[0](10) + fs[1](10));
+    println(pick(1)(3));
+  };
+}
+func pick(which: i32): func(i32): i32 { if (which) { double; } else { add1; }; }
+func add1(x: i32) { x + 1; }
+func double(x: i32) { x * 2; }
+"#;
+    assert_eq!(output(src), "31\n6\n");
+}
+
+#[test]
+fn calling_a_null_function_traps() {
+    let src = r#"
+func main() {
+  var fs: [func(i32): i32] = array(1) in fs[0](1);
+}
+"#;
+    // 関数値はクロージャのアドレスなので、ヌルはポインタのアクセスで検出される
+    assert_eq!(run(src).trap.as_deref(), Some("null pointer access"));
+}
+
+#[test]
+fn function_value_types_are_checked() {
+    let src = r#"
+func main() {
+  var f = add1 in f(1i64);
+}
+func add1(x: i32) { x + 1; }
+"#;
+    assert!(compile_error(src).starts_with("main.wjit:3:"));
+}
@@ -0,0 +1,265 @@
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use wjit::backtrace::Frame;
+use wjit::host::{HostFuncs, HostValue};
+use wjit::interpreter::{Interpreter, RuntimeError, Trap, Value};
+use wjit::types::{Ty, Type};
+use wjit::*;
+
+// ファイルシステムの代わりにメモリ上のソースを読む
+pub struct MemLoader(pub HashMap<String, String>);
+
+impl loader::Loader for MemLoader {
+    fn load(&mut self, path: &str) -> Option<String> {
+        self.0.get(path).cloned()
+    }
+}
+
+pub fn compile_files(files: &[(&str, &str)]) -> Result<ir::Module, error::CompileErrors> {
+    let mut loader = MemLoader(
+        files
+            .iter()
+            .map(|(path, src)| (path.to_string(), src.to_string()))
+            .collect(),
+    );
+    wjit::compile(files[0].0, &mut loader, &capturing_host("").0.decls())
+}
+
+pub fn compile_src(src: &str) -> Result<ir::Module, error::CompileErrors> {
+    compile_files(&[("main.wjit", src)])
+}
+
+// 出力を文字列にためるホスト関数。read_intはinputを空白区切りで読む
+pub fn capturing_host(input: &str) -> (HostFuncs, Rc<RefCell<String>>) {
+    let output = Rc::new(RefCell::new(String::new()));
+    let mut host = HostFuncs::new();
+    for ty in [Type::I32, Type::I64, Type::F64] {
+        let output = output.clone();
+        host.register(
+            &host::println_name(ty),
+            vec![Ty::Scalar(ty)],
+            Type::I32,
+            move |args| {
+                let line = match args[0] {
+                    HostValue::I32(x) => x.to_string(),
+                    HostValue::I64(x) => x.to_string(),
+                    HostValue::F64(x) => x.to_string(),
+                    HostValue::Str(s) => s.to_string(),
+                };
+                output.borrow_mut().push_str(&format!("{}\n", line));
+                Value::I32(0)
+            },
+        );
+    }
+    for (name, newline) in [("print_str", ""), ("println_str", "\n")] {
+        let output = output.clone();
+        host.register(name, vec![Ty::Str], Type::I32, move |args| {
+            if let HostValue::Str(s) = args[0] {
+                output.borrow_mut().push_str(&format!("{}{}", s, newline));
+            }
+            Value::I32(0)
+        });
+    }
+    {
+        let output = output.clone();
+        host.register("exit", vec![Ty::I32], Type::I32, move |args| {
+            if let HostValue::I32(code) = args[0] {
+                output.borrow_mut().push_str(&format!("exit {}\n", code));
+            }
+            Value::I32(0)
+        });
+    }
+    let mut tokens = input
+        .split_whitespace()
+        .map(|token| token.parse().unwrap_or(0))
+        .collect::<Vec<i32>>()
+        .into_iter();
+    host.register("read_int", vec![], Type::I32, move |_| {
+        Value::I32(tokens.next().unwrap_or(0))
+    });
+    (host, output)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Run {
+    pub output: String,
+    // バックトレースを除いたtrapのメッセージ
+    pub trap: Option<String>,
+    pub backtrace: Vec<Frame>,
+    // 実行後に残った燃料
+    pub fuel: i64,
+}
+
+impl Run {
+    fn new(output: String, result: Result<Value, RuntimeError>, fuel: i64) -> Self {
+        let (trap, backtrace) = match result {
+            Ok(_) => (None, Vec::new()),
+            Err(error) => (Some(error.trap.to_string()), error.backtrace.frames),
+        };
+        Run {
+            output,
+            trap,
+            backtrace,
+            fuel,
+        }
+    }
+}
+
+pub fn interpret_module(
+    module: &ir::Module,
+    fuel: Option<i64>,
+) -> (Result<Value, RuntimeError>, String) {
+    let run = interpret_run(module, fuel);
+    (run.0, run.1)
+}
+
+fn interpret_run(
+    module: &ir::Module,
+    fuel: Option<i64>,
+) -> (Result<Value, RuntimeError>, String, i64) {
+    let (host, output) = capturing_host("");
+    let mut interpreter = Interpreter::new(module, host).unwrap();
+    if let Some(fuel) = fuel {
+        interpreter.fuel = fuel;
+    }
+    let result = interpreter.call(0, &[]);
+    let output = output.borrow().clone();
+    (result, output, interpreter.fuel)
+}
+
+pub fn interpret(src: &str) -> Run {
+    interpret_with_fuel(src, None)
+}
+
+pub fn interpret_with_fuel(src: &str, fuel: Option<i64>) -> Run {
+    let module = compile_src(src).unwrap_or_else(|errors| panic!("{}", errors));
+    let (result, output, fuel) = interpret_run(&module, fuel);
+    Run::new(output, result, fuel)
+}
+
+fn temp_dir() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "wjit-test-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ))
+}
+
+// 全関数をあらかじめwasmにしてnodeで実行する。nodeがなければNone
+pub fn jit_module(module: &ir::Module, fuel: Option<i64>) -> Option<Run> {
+    let dir = temp_dir();
+    std::fs::create_dir_all(&dir).unwrap();
+    let compiler = compiler::Compiler::new(module);
+    std::fs::write(
+        dir.join("skeleton.wasm"),
+        parity_wasm::serialize(compiler.compile_skeleton()).unwrap(),
+    )
+    .unwrap();
+    for idx in 0..module.funcs.len() {
+        std::fs::write(
+            dir.join(format!("{}.wasm", idx)),
+            parity_wasm::serialize(compiler.compile_func_module(idx)).unwrap(),
+        )
+        .unwrap();
+    }
+
+    let mut command = Command::new("node");
+    command
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/jit.js"))
+        .arg(&dir)
+        .env(
+            "STACK_OVERFLOW_TRAP",
+            Trap::StackOverflow.code().to_string(),
+        )
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(fuel) = fuel {
+        command.arg(fuel.to_string());
+    }
+    let result = command.spawn().and_then(|mut child| {
+        child.stdin.take().unwrap().write_all(b"")?;
+        child.wait_with_output()
+    });
+    std::fs::remove_dir_all(&dir).unwrap();
+    let result = match result {
+        Ok(result) => result,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(error) => panic!("{}", error),
+    };
+
+    // _trapに書き込まれた理由をインタプリタと同じメッセージにする
+    // 書き込まれずにwasm自身がtrapした場合はそのメッセージのままにして、比較で失敗させる
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    let mut lines = stderr.lines();
+    let trap = match lines.next().and_then(|line| line.strip_prefix("trap: ")) {
+        Some(trap) => {
+            let (code, message) = trap.split_once(' ').unwrap();
+            Some(match Trap::from_code(code.parse().unwrap()) {
+                Some(trap) => trap.to_string(),
+                None => format!("wasm: {}", message),
+            })
+        }
+        None if result.status.success() => None,
+        None => panic!("{}", stderr),
+    };
+    let fuel = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("fuel: "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    let backtrace = lines
+        .filter_map(|line| line.strip_prefix("frame: "))
+        .map(|frame| {
+            let (idx, offset) = frame.split_once(' ').unwrap();
+            compiler.backtrace_frame(idx.parse().unwrap(), offset.parse().unwrap())
+        })
+        .collect();
+    Some(Run {
+        output: String::from_utf8(result.stdout).unwrap(),
+        trap,
+        backtrace,
+        fuel,
+    })
+}
+
+// インタプリタとJITの両方で実行し、出力とtrapが一致することを確かめる
+pub fn run(src: &str) -> Run {
+    run_with_fuel(src, None)
+}
+
+pub fn run_with_fuel(src: &str, fuel: Option<i64>) -> Run {
+    let module = compile_src(src).unwrap_or_else(|errors| panic!("{}", errors));
+    let (result, output, fuel_left) = interpret_run(&module, fuel);
+    let run = Run::new(output, result, fuel_left);
+    if let Some(jit) = jit_module(&module, fuel) {
+        assert_eq!(run.output, jit.output, "jit output differs");
+        assert_eq!(run.trap, jit.trap, "jit trap differs");
+        // スタックが溢れる深さはJITではV8が決めるので、そこまでの履歴と燃料は比べられない
+        if run.trap != Some(Trap::StackOverflow.to_string()) {
+            assert_eq!(run.backtrace, jit.backtrace, "jit backtrace differs");
+            assert_eq!(run.fuel, jit.fuel, "jit fuel differs");
+        }
+    }
+    run
+}
+
+pub fn output(src: &str) -> String {
+    let run = run(src);
+    assert_eq!(run.trap, None);
+    run.output
+}
+
+pub fn compile_error(src: &str) -> String {
+    compile_src(src).unwrap_err().to_string()
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn if_without_else_is_zero_when_not_taken() {
+    let src = r#"
+func main() {
+  println(if (0) { 5; });
+  println(if (1) { 5; });
+  println(if (1) { 1.5; });
+  var x = 0 in {
+    if (x == 0) { x = 3; };
+    println(x);
+  };
+}
+"#;
+    assert_eq!(output(src), "0\n5\n0\n3\n");
+}
+
+#[test]
+fn if_else_branches_must_agree() {
+    let src = "func main() {\n  if (1) { 1; } else { 1.5; };\n}\n";
+    assert!(compile_error(src).starts_with("main.wjit:2:"));
+}
+
+#[test]
+fn return_break_and_continue() {
+    let src = r#"
+func main() {
+  println(first_even(7));
+  var i = 0 in var sum = 0 in {
+    while (1) {
+      i = i + 1;
+      if (i > 10) { break; };
+      if (i % 2 == 0) { continue; };
+      sum = sum + i;
+    };
+    println(sum);
+  };
+}
+func first_even(n: i32) {
+  var i = n in while (1) {
+    if (i % 2 == 0) { return i; };
+    i = i + 1;
+  };
+  0 - 1;
+}
+"#;
+    assert_eq!(output(src), "8\n25\n");
+}
+
+#[test]
+fn break_and_continue_outside_loops() {
+    assert!(compile_error("func main() {\n  break;\n}\n").starts_with("main.wjit:2:3: "));
+    assert!(compile_error("func main() {\n  continue;\n}\n").starts_with("main.wjit:2:3: "));
+}
+
+#[test]
+fn break_inside_a_lambda_does_not_see_the_outer_loop() {
+    let src = "func main() {\n  while (1) { fn(): i32 { break; }; };\n}\n";
+    assert!(compile_src(src).is_err());
+}
+
+#[test]
+fn and_or_short_circuit_and_produce_zero_or_one() {
+    let src = r#"
+func main() {
+  println(0 && side(1));
+  println(2 && side(3));
+  println(5 || side(4));
+  println(0 || side(0));
+  println(0 || 7);
+}
+func side(x: i32) {
+  println(x + 100);
+  x;
+}
+"#;
+    assert_eq!(output(src), "0\n103\n1\n1\n100\n0\n1\n");
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn compile_errors_have_path_and_position() {
+    let src = "func main() {\n  x + 1;\n}\n";
+    assert_eq!(compile_error(src), "main.wjit:2:3: unknown identifier `x`");
+}
+
+#[test]
+fn unknown_function_is_an_error_not_a_panic() {
+    let src = "func main() {\n  f(1);\n}\n";
+    assert!(compile_error(src).starts_with("main.wjit:2:3: "));
+}
+
+#[test]
+fn missing_file_is_an_error() {
+    let mut loader = MemLoader(Default::default());
+    let errors = wjit::compile("nope.wjit", &mut loader, &[]).unwrap_err();
+    assert_eq!(errors.errors.len(), 1);
+}
+
+#[test]
+fn all_syntax_errors_are_reported() {
+    let src = "func f() { 1 + ; }\nfunc g() { 2; }\nfunc h() { ) ; }\n";
+    let errors = compile_error(src);
+    let lines = errors.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2, "{}", errors);
+    assert!(lines[0].starts_with("main.wjit:1:"));
+    assert!(lines[1].starts_with("main.wjit:3:"));
+    assert!(lines[1].contains("found `)`"), "{}", lines[1]);
+}
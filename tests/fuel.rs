@@ -0,0 +1,89 @@
+mod common;
+
+use common::*;
+use wjit::ir;
+
+const PROGRAM: &str = r#"
+func main() {
+  var i = 0 in var add = adder(1) in while (i < 3) {
+    println(fib(i + 2));
+    i = add(i);
+  };
+  for (var j = 0; j < 2; j += 1) { println(j); };
+}
+func fib(n: i32) { if (n < 2) { n; } else { fib(n - 1) + fib(n - 2); }; }
+func adder(n: i32): func(i32): i32 { fn(x: i32): i32 { x + n; }; }
+"#;
+
+#[test]
+fn both_tiers_charge_the_same_fuel() {
+    let full = run_with_fuel(PROGRAM, Some(100_000));
+    assert_eq!(full.trap, None);
+    assert_eq!(full.output, "1\n2\n3\n0\n1\n");
+    let used = 100_000 - full.fuel;
+
+    // ちょうど足りる燃料では最後まで実行でき、1少ないとtrapする
+    let exact = run_with_fuel(PROGRAM, Some(used));
+    assert_eq!((exact.trap, exact.fuel), (None, 0));
+    let short = run_with_fuel(PROGRAM, Some(used - 1));
+    assert_eq!(short.trap.as_deref(), Some("out of fuel"));
+
+    // どこでtrapしても出力と残りの燃料が両方の実行で一致する
+    // 燃料は領域ごとにまとめて消費するので、一番小さい領域の幅で間引いてもすべての領域でtrapする
+    let step = compile_src(PROGRAM)
+        .unwrap()
+        .funcs
+        .iter()
+        .flat_map(|func| {
+            let costs = func.fuel_costs();
+            std::iter::once(costs.entry).chain(costs.loops)
+        })
+        .min()
+        .unwrap();
+    let mut prev_output = String::new();
+    for fuel in (0..used).step_by(step as usize) {
+        let run = run_with_fuel(PROGRAM, Some(fuel));
+        assert_eq!(run.trap.as_deref(), Some("out of fuel"));
+        assert!(run.output.starts_with(&prev_output));
+        prev_output = run.output;
+    }
+}
+
+fn fuel_costs(src: &str, name: &str) -> ir::FuelCosts {
+    let module = compile_src(src).unwrap();
+    module
+        .funcs
+        .iter()
+        .find(|func| func.name == name)
+        .unwrap()
+        .fuel_costs()
+}
+
+#[test]
+fn regions_consume_their_instruction_count() {
+    let used = |src: &str| 1000 - run_with_fuel(src, Some(1000)).fuel;
+
+    let short = "func main() { 0; }\n";
+    let long = "func main() { var x = 1 in x = x * 2 + x; 0; }\n";
+    assert_eq!(used(short), fuel_costs(short, "main").entry);
+    assert_eq!(used(long), fuel_costs(long, "main").entry);
+    assert!(used(long) > used(short));
+
+    // 呼び出された関数は入口で自分の分を消費する
+    let calls = "func main() { f(); f(); }\nfunc f() { 0; }\n";
+    assert_eq!(
+        used(calls),
+        fuel_costs(calls, "main").entry + 2 * fuel_costs(calls, "f").entry
+    );
+
+    // ループは1周ごとに中の命令の数だけ消費する
+    let loops = |n: i32| {
+        format!(
+            "func main() {{ var i = 0 in while (i < {}) {{ i = i + 1; }}; }}\n",
+            n
+        )
+    };
+    let costs = fuel_costs(&loops(0), "main");
+    assert_eq!(used(&loops(0)), costs.entry + costs.loops[0]);
+    assert_eq!(used(&loops(5)) - used(&loops(4)), costs.loops[0]);
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::*;
+use wjit::interpreter::{Interpreter, Value};
+
+#[test]
+fn globals_of_each_type_are_shared_between_functions() {
+    let src = r#"
+global count = 0;
+global total: i64 = 10;
+global scale = 0.5;
+func main() {
+  bump(); bump();
+  println(count);
+  println_i64(total);
+  println_f64(scale);
+}
+func bump() {
+  count += 1;
+  total *= 2i64;
+  scale = scale * 3.0;
+}
+"#;
+    assert_eq!(output(src), "2\n40\n4.5\n");
+}
+
+#[test]
+fn interpreter_exposes_globals_with_their_types() {
+    let src = "global a = -1;\nglobal b: i64 = 2;\nglobal c = 1.5;\nfunc main() { a = 7; }\n";
+    let module = compile_src(src).unwrap();
+    let mut interpreter = Interpreter::new(&module, capturing_host("").0).unwrap();
+    interpreter.call(0, &[]).unwrap();
+    assert_eq!(
+        interpreter.globals,
+        vec![Value::I32(7), Value::I64(2), Value::F64(1.5)]
+    );
+    assert_eq!(interpreter.globals[1].as_i64(), 2);
+    assert_eq!(interpreter.globals[2].as_f64(), 1.5);
+}
+
+#[test]
+fn global_errors() {
+    assert!(
+        compile_error("global a = 1;\nglobal a = 2;\nfunc main() { 0; }\n")
+            .starts_with("main.wjit:2:")
+    );
+    assert!(
+        compile_error("global a = f();\nfunc main() { 0; }\nfunc f() { 1; }\n")
+            .starts_with("main.wjit:1:")
+    );
+}
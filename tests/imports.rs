@@ -0,0 +1,84 @@
+mod common;
+
+use common::*;
+
+fn output_files(files: &[(&str, &str)]) -> String {
+    let module = compile_files(files).unwrap_or_else(|errors| panic!("{}", errors));
+    let (result, output) = interpret_module(&module, None);
+    result.unwrap();
+    output
+}
+
+#[test]
+fn qualified_names_refer_to_the_imported_file() {
+    let files = [
+        (
+            "main.wjit",
+            r#"
+import "lib/math.wjit" as math;
+func main() {
+  var p = math.Point { x: 3, y: 4 } in println(math.norm2(p) + math.count);
+  println(f(1));
+}
+func f(x: i32) { x + 100; }
+"#,
+        ),
+        (
+            "lib/math.wjit",
+            r#"
+import "util.wjit" as util;
+global count = 1;
+struct Point { x: i32, y: i32 }
+func norm2(p: Point) { f(p.x) + f(p.y); }
+func f(x: i32) { util.square(x); }
+"#,
+        ),
+        ("lib/util.wjit", "func square(x: i32) { x * x; }\n"),
+    ];
+    assert_eq!(output_files(&files), "26\n101\n");
+}
+
+#[test]
+fn shared_imports_are_loaded_once() {
+    let files = [
+        (
+            "main.wjit",
+            "import \"a.wjit\" as a;\nimport \"b.wjit\" as b;\nimport \"c.wjit\" as c;\nfunc main() { a.f(); b.f(); println(c.n); }\n",
+        ),
+        ("a.wjit", "import \"c.wjit\" as c;\nfunc f() { c.n += 1; }\n"),
+        ("b.wjit", "import \"c.wjit\" as c;\nfunc f() { c.n += 10; }\n"),
+        ("c.wjit", "global n = 0;\n"),
+    ];
+    assert_eq!(output_files(&files), "11\n");
+}
+
+#[test]
+fn import_errors() {
+    let missing = compile_files(&[("main.wjit", "import \"nope.wjit\" as m;\nfunc main() {}\n")])
+        .unwrap_err()
+        .to_string();
+    assert_eq!(missing, "main.wjit:1:1: cannot find file `nope.wjit`");
+
+    let cycle = compile_files(&[
+        ("main.wjit", "import \"a.wjit\" as a;\nfunc main() {}\n"),
+        ("a.wjit", "import \"main.wjit\" as m;\n"),
+    ])
+    .unwrap_err()
+    .to_string();
+    assert!(
+        cycle.ends_with("`main.wjit` is imported cyclically"),
+        "{}",
+        cycle
+    );
+
+    let unknown = compile_files(&[
+        (
+            "main.wjit",
+            "import \"a.wjit\" as a;\nfunc main() { a.g(); }\n",
+        ),
+        ("a.wjit", "func f() {}\n"),
+    ])
+    .unwrap_err()
+    .to_string();
+    assert!(unknown.starts_with("main.wjit:2:"), "{}", unknown);
+}
@@ -0,0 +1,122 @@
+"use strict";
+// tests/common/mod.rsから呼ばれる。引数のディレクトリにあるskeleton.wasmと関数モジュールを実行する
+// trapしたら`trap: _trapの値 メッセージ`を標準エラー出力に書いて終了コード1で終わる
+// 続けて関数モジュールのフレームを内側から`frame: 関数のindex バイトオフセット`の形で書く
+// 最後に残った燃料を`fuel: 残り`の形で書く
+const fs = require("fs");
+const path = require("path");
+
+const dir = process.argv[2];
+const fuel = process.argv[3];
+// V8はwasmのスタックが溢れるとRangeErrorを投げるので、インタプリタと同じtrapの番号にする
+const stackOverflowCode = Number(process.env.STACK_OVERFLOW_TRAP);
+
+let inputTokens;
+let inputPos = 0;
+function readInt() {
+  if (inputTokens === undefined) {
+    inputTokens = fs
+      .readFileSync(0, { encoding: "utf8" })
+      .split(/\s+/)
+      .filter((token) => token !== "");
+  }
+  if (inputPos >= inputTokens.length) {
+    return 0;
+  }
+  const token = inputTokens[inputPos++];
+  const x = Number(token);
+  return /^[+-]?\d+$/.test(token) && x >= -(2 ** 31) && x < 2 ** 31 ? x : 0;
+}
+
+// 関数モジュールの文字列は先頭8バイトに長さを持つ
+function skeletonString(ptr) {
+  const buf = skeletonInstance.exports._memory.buffer;
+  const len = new DataView(buf).getInt32(ptr, true);
+  return Buffer.from(buf, ptr + 8, len).toString();
+}
+
+Error.stackTraceLimit = 100;
+
+let output = "";
+const skeletonModule = new WebAssembly.Module(
+  fs.readFileSync(path.join(dir, "skeleton.wasm"))
+);
+const skeletonInstance = new WebAssembly.Instance(skeletonModule, {
+  env: {
+    compile_func: (idx) => {
+      const funcModule = new WebAssembly.Module(
+        fs.readFileSync(path.join(dir, `${idx}.wasm`))
+      );
+      const globals = Object.fromEntries(
+        Object.entries(skeletonInstance.exports).filter(([name]) =>
+          name.startsWith("_global_")
+        )
+      );
+      new WebAssembly.Instance(funcModule, {
+        env: {
+          ...globals,
+          _table: skeletonInstance.exports._table,
+          _memory: skeletonInstance.exports._memory,
+          _alloc: skeletonInstance.exports._alloc,
+          _fuel: skeletonInstance.exports._fuel,
+          _trap: skeletonInstance.exports._trap,
+          println: (x) => {
+            output += `${x}\n`;
+            return 0;
+          },
+          println_i64: (x) => {
+            output += `${x}\n`;
+            return 0;
+          },
+          println_f64: (x) => {
+            output += `${x}\n`;
+            return 0;
+          },
+          print_str: (ptr) => {
+            output += skeletonString(ptr);
+            return 0;
+          },
+          println_str: (ptr) => {
+            output += `${skeletonString(ptr)}\n`;
+            return 0;
+          },
+          exit: (code) => {
+            process.stdout.write(output);
+            process.exit(code);
+          },
+          read_int: readInt,
+        },
+      });
+      return 0;
+    },
+  },
+});
+if (fuel !== undefined) {
+  skeletonInstance.exports._fuel.value = BigInt(fuel);
+}
+
+try {
+  skeletonInstance.exports.main();
+} catch (e) {
+  if (e instanceof RangeError) {
+    skeletonInstance.exports._trap.value = stackOverflowCode;
+  } else if (!(e instanceof WebAssembly.RuntimeError)) {
+    throw e;
+  }
+  process.stdout.write(output);
+  process.stderr.write(
+    `trap: ${skeletonInstance.exports._trap.value} ${e.message}\n`
+  );
+  for (const line of e.stack.split("\n")) {
+    const match = line.match(
+      /wasm:\/\/wasm\/wjit_func_(\d+)-[0-9a-f]+:wasm-function\[\d+\]:0x([0-9a-f]+)/
+    );
+    if (match) {
+      process.stderr.write(`frame: ${match[1]} ${parseInt(match[2], 16)}\n`);
+    }
+  }
+  process.stderr.write(`fuel: ${skeletonInstance.exports._fuel.value}\n`);
+  process.exit(1);
+}
+process.stdout.write(output);
+process.stderr.write(`fuel: ${skeletonInstance.exports._fuel.value}\n`);
@@ -0,0 +1,26 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn int_literals() {
+    let src = r#"
+func main() {
+  println(0xff + 0b11 + 0o10 + 1_000);
+  println(-2147483648);
+  println_i64(-9223372036854775808i64);
+  println_i64(0x7fff_ffff_ffff_ffffi64);
+}
+"#;
+    assert_eq!(
+        output(src),
+        "1266\n-2147483648\n-9223372036854775808\n9223372036854775807\n"
+    );
+}
+
+#[test]
+fn int_literal_out_of_range_for_its_type() {
+    let error = compile_error("func main() {\n  2147483648;\n}\n");
+    assert!(error.starts_with("main.wjit:2:3: "), "{}", error);
+    assert!(compile_src("func main() { 9223372036854775808i64; }").is_err());
+}
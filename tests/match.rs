@@ -0,0 +1,54 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn dense_match_uses_a_jump_table() {
+    let src = r#"
+func main() {
+  var i = 0 - 2 in while (i < 5) {
+    println(match (i) { -1 => 10, 0 => 20, 1 => 30, 3 => 40, _ => 0 - 1 });
+    i = i + 1;
+  };
+}
+"#;
+    assert_eq!(output(src), "-1\n10\n20\n30\n-1\n40\n-1\n");
+    assert_eq!(compile_src(src).unwrap().funcs[0].switch_infos.len(), 1);
+}
+
+#[test]
+fn sparse_match_falls_back_to_comparisons() {
+    let src = r#"
+func main() {
+  println(f(1000000));
+  println(f(7));
+  println(f(0 - 1000000));
+  println(f(3));
+}
+func f(x: i32) { match (x) { 1000000 => 1, 7 => 2, -1000000 => 3, _ => 4 }; }
+"#;
+    assert_eq!(output(src), "1\n2\n3\n4\n");
+    let module = compile_src(src).unwrap();
+    assert!(module.funcs.iter().all(|func| func.switch_infos.is_empty()));
+}
+
+#[test]
+fn match_on_i64_with_f64_arms() {
+    let src = r#"
+func main() {
+  println_f64(f(5000000001i64));
+  println_f64(f(5000000002i64));
+  println_f64(f(0i64));
+}
+func f(x: i64): f64 {
+  match (x) { 5000000000 => 0.5, 5000000001 => 1.5, 5000000002 => 2.5, _ => 0.0 };
+}
+"#;
+    assert_eq!(output(src), "1.5\n2.5\n0\n");
+}
+
+#[test]
+fn duplicate_patterns_are_rejected() {
+    let src = "func main() {\n  match (1) { 1 => 1, 2 => 2, 1 => 3, _ => 0 };\n}\n";
+    assert!(compile_error(src).ends_with("pattern `1` is matched more than once"));
+}
@@ -0,0 +1,20 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn i64_and_f64_arithmetic() {
+    let src = r#"
+func main() {
+  println_i64(mul(3000000000i64, 3i64));
+  println_f64(half(7.0));
+  println((2.9 as i32));
+  println_i64((-2.9 as i64));
+  println_f64((5 as f64) / 2.0);
+  println(7i64 > 3i64);
+}
+func mul(a: i64, b: i64): i64 { a * b; }
+func half(x: f64): f64 { x / 2.0; }
+"#;
+    assert_eq!(output(src), "9000000000\n3.5\n2\n-2\n2.5\n1\n");
+}
@@ -0,0 +1,12 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn sample_runs_the_same_on_both_tiers() {
+    let src = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.wjit")).unwrap();
+    assert_eq!(
+        output(&src),
+        "55\n10\n9\n8\n7\n6\n5\n4\n3\n2\n1\n0\n1\n0\n1\n0\n"
+    );
+}
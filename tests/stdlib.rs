@@ -0,0 +1,50 @@
+mod common;
+
+use common::*;
+use wjit::interpreter::{Interpreter, Value};
+
+#[test]
+fn abs_min_max_for_each_type() {
+    let src = r#"
+func main() {
+  println(abs(0 - 3) + min(4, 0 - 2) * 10 + max(4, 0 - 2) * 100);
+  println_i64(abs(0i64 - 5000000000i64) + min(1i64, 2i64) + max(1i64, 2i64));
+  println_f64(abs(0.0 - 1.5) + min(0.25, 0.5) + max(0.25, 0.5));
+  println(abs(0 - 2147483647 - 1));
+}
+"#;
+    assert_eq!(output(src), "383\n5000000003\n2.25\n-2147483648\n");
+}
+
+#[test]
+fn assert_passes_on_nonzero() {
+    assert_eq!(
+        output("func main() { assert(1); assert(0 - 1); println(2); }\n"),
+        "2\n"
+    );
+}
+
+#[test]
+fn exit_and_read_int_use_the_host() {
+    let src = r#"
+func main() {
+  var a = read_int() in var b = read_int() in var c = read_int() in var d = read_int() in {
+    println(a + b * 10 + c * 100 + d);
+    exit(a);
+  };
+}
+"#;
+    let module = compile_src(src).unwrap();
+    // 整数でないトークンと尽きた入力は0になる
+    let (host, output) = capturing_host("3 x\n 7");
+    let mut interpreter = Interpreter::new(&module, host).unwrap();
+    assert_eq!(interpreter.call(0, &[]).unwrap(), Value::I32(0));
+    assert_eq!(*output.borrow(), "703\nexit 3\n");
+}
+
+#[test]
+fn builtin_arguments_are_checked() {
+    assert!(compile_error("func main() { min(1, 2i64); }\n").starts_with("main.wjit:1:"));
+    assert!(compile_error("func main() { assert(1.0); }\n").starts_with("main.wjit:1:"));
+    assert!(compile_error("func main() { abs(1, 2); }\n").starts_with("main.wjit:1:"));
+}
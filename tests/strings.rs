@@ -0,0 +1,47 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn zero_initialized_str_is_empty_in_both_tiers() {
+    let src = r#"
+func main() {
+  var a: [str] = array(2) in {
+    a[1] = "hi";
+    print_str(a[0]);
+    println_str(a[1]);
+    println_str(a[0]);
+  };
+}
+"#;
+    assert_eq!(output(src), "hi\n\n");
+}
+
+#[test]
+fn zero_initialized_str_without_literals() {
+    let src = r#"
+func main() {
+  var a: [str] = array(1) in println_str(a[0]);
+}
+"#;
+    assert_eq!(output(src), "\n");
+}
+
+#[test]
+fn string_literals_and_escapes() {
+    let src = r#"
+func main() {
+  print_str("a\tb");
+  println_str(" \"q\"\\");
+  println_str("");
+  println_str("a\tb");
+}
+"#;
+    assert_eq!(output(src), "a\tb \"q\"\\\n\na\tb\n");
+}
+
+#[test]
+fn identical_literals_share_a_pool_entry() {
+    let module = compile_src(r#"func main() { println_str("x"); println_str("x"); }"#).unwrap();
+    assert_eq!(module.strings, vec!["".to_string(), "x".to_string()]);
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn struct_fields_of_each_type() {
+    let src = r#"
+struct Point { x: i32, y: i32 }
+struct Mixed { a: i64, b: f64, p: Point }
+func main() {
+  var m = Mixed { p: Point { x: 1, y: 2 }, b: 0.5, a: 7i64 } in {
+    m.p.y = m.p.y + 40;
+    m.b = m.b * 3.0;
+    println(m.p.x + m.p.y);
+    println_i64(m.a);
+    println_f64(m.b);
+  };
+}
+"#;
+    assert_eq!(output(src), "43\n7\n1.5\n");
+}
+
+#[test]
+fn structs_are_references() {
+    let src = r#"
+struct Counter { n: i32 }
+func main() {
+  var c = Counter { n: 0 } in {
+    bump(c);
+    bump(c);
+    println(c.n);
+  };
+}
+func bump(c: Counter) { c.n = c.n + 1; }
+"#;
+    assert_eq!(output(src), "2\n");
+}
+
+#[test]
+fn struct_errors() {
+    assert_eq!(
+        compile_error("struct P { x: i32 }\nfunc main() { P { x: 1, y: 2 }.x; }\n"),
+        "main.wjit:2:28: struct `P` has no field `y`"
+    );
+    assert!(
+        compile_error("struct P { x: i32, y: i32 }\nfunc main() { P { x: 1 }.x; }\n")
+            .ends_with("missing field `y` in `P`")
+    );
+    assert!(compile_error("func main() { Q { x: 1 }.x; }\n").ends_with("unknown struct `Q`"));
+    assert!(
+        compile_error("struct P { x: i32 }\nstruct P { y: i32 }\nfunc main() {}\n")
+            .ends_with("struct `P` is defined more than once")
+    );
+}
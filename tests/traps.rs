@@ -0,0 +1,87 @@
+mod common;
+
+use common::*;
+
+fn trap(src: &str) -> String {
+    run(src).trap.expect("should trap")
+}
+
+#[test]
+fn assertion_failure_has_the_same_message_in_both_tiers() {
+    let src = r#"
+func main() {
+  println(1);
+  assert(1 == 2);
+}
+"#;
+    let run = run(src);
+    assert_eq!(run.output, "1\n");
+    assert_eq!(run.trap.as_deref(), Some("assertion failed"));
+}
+
+#[test]
+fn array_index_out_of_bounds() {
+    let src = r#"
+func main() {
+  var a: [i32] = array(2) in a[2];
+}
+"#;
+    assert_eq!(trap(src), "array index out of bounds");
+}
+
+#[test]
+fn invalid_array_length() {
+    let src = r#"
+func main() {
+  var a: [i32] = array(0 - 1) in 0;
+}
+"#;
+    assert_eq!(trap(src), "invalid array length");
+}
+
+#[test]
+fn null_pointer_access() {
+    let src = r#"
+struct P { x: i32 }
+func main() {
+  var a: [P] = array(1) in a[0].x;
+}
+"#;
+    assert_eq!(trap(src), "null pointer access");
+}
+
+#[test]
+fn out_of_fuel() {
+    let src = r#"
+func main() {
+  while (1) {};
+}
+"#;
+    let run = run_with_fuel(src, Some(10));
+    assert_eq!(run.trap.as_deref(), Some("out of fuel"));
+}
+
+#[test]
+fn unbounded_recursion_is_a_stack_overflow() {
+    let src = r#"
+func main() {
+  println(1);
+  f();
+}
+func f() { f(); }
+"#;
+    let run = run(src);
+    assert_eq!(run.output, "1\n");
+    assert_eq!(run.trap.as_deref(), Some("stack overflow"));
+}
+
+#[test]
+fn deep_recursion_below_the_limit_runs() {
+    let src = r#"
+func main() {
+  println(depth(4990));
+}
+func depth(n: i32) { if (n == 0) { 0; } else { depth(n - 1) + 1; }; }
+"#;
+    assert_eq!(output(src), "4990\n");
+}